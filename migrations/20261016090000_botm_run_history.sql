@@ -0,0 +1,21 @@
+ALTER TABLE botm_runs
+  ADD COLUMN started_at timestamptz NOT NULL DEFAULT now(),
+  ADD COLUMN finished_at timestamptz,
+  ADD COLUMN total_users INT NOT NULL DEFAULT 0,
+  ADD COLUMN succeeded INT NOT NULL DEFAULT 0,
+  ADD COLUMN failed INT NOT NULL DEFAULT 0;
+
+-- History has to go with the user when they disconnect.
+ALTER TABLE user_botm_runs
+  DROP CONSTRAINT user_botm_runs_spotify_id_fkey,
+  ADD CONSTRAINT user_botm_runs_spotify_id_fkey
+    FOREIGN KEY (spotify_id) REFERENCES users(spotify_id) ON DELETE CASCADE,
+  ADD COLUMN status TEXT NOT NULL,
+  ADD COLUMN error TEXT,
+  ADD COLUMN playlist_id TEXT,
+  ADD COLUMN track_count INT,
+  ADD COLUMN duration_ms BIGINT NOT NULL,
+  ADD COLUMN finished_at timestamptz NOT NULL DEFAULT now();
+
+CREATE INDEX user_botm_runs_spotify_id_idx ON user_botm_runs (spotify_id);
+CREATE INDEX user_botm_runs_botm_run_id_idx ON user_botm_runs (botm_run_id);
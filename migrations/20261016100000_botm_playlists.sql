@@ -0,0 +1,13 @@
+-- One BOTM playlist per user and month, so repeated runs don't create duplicates.
+CREATE TABLE botm_playlists (
+  spotify_id TEXT NOT NULL REFERENCES users(spotify_id) ON DELETE CASCADE,
+  month DATE NOT NULL,
+  PRIMARY KEY(spotify_id, month),
+  playlist_id TEXT NOT NULL,
+  name TEXT NOT NULL,
+  track_count INT NOT NULL,
+  created_at timestamptz NOT NULL DEFAULT now(),
+  updated_at timestamptz NOT NULL DEFAULT now()
+);
+
+ALTER TABLE user_botm_runs ADD COLUMN month DATE;
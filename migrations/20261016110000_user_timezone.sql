@@ -0,0 +1,2 @@
+-- IANA time zone name, e.g. 'Europe/Vienna'. NULL falls back to UTC.
+ALTER TABLE users ADD COLUMN timezone TEXT;
@@ -0,0 +1,8 @@
+-- Generation preferences, users without a row get the defaults.
+CREATE TABLE user_settings (
+  spotify_id TEXT NOT NULL REFERENCES users(spotify_id) ON DELETE CASCADE,
+  PRIMARY KEY(spotify_id),
+  name_template TEXT NOT NULL,
+  description_template TEXT NOT NULL,
+  locale TEXT NOT NULL
+);
@@ -0,0 +1,14 @@
+ALTER TABLE user_settings
+  ADD COLUMN time_range TEXT NOT NULL DEFAULT 'short_term',
+  ADD COLUMN track_count INT NOT NULL DEFAULT 50,
+  ADD COLUMN quarterly_playlist BOOLEAN NOT NULL DEFAULT false,
+  ADD COLUMN yearly_playlist BOOLEAN NOT NULL DEFAULT false;
+
+-- Besides the monthly BOTM, users can get quarterly and yearly playlists.
+-- `month` is the first day of the period.
+ALTER TABLE botm_playlists
+  ADD COLUMN period TEXT NOT NULL DEFAULT 'month',
+  DROP CONSTRAINT botm_playlists_pkey,
+  ADD PRIMARY KEY (spotify_id, period, month);
+
+ALTER TABLE user_botm_runs ADD COLUMN period TEXT;
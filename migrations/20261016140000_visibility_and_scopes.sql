@@ -0,0 +1,10 @@
+ALTER TABLE user_settings
+  ADD COLUMN public BOOLEAN NOT NULL DEFAULT true,
+  ADD COLUMN collaborative BOOLEAN NOT NULL DEFAULT false;
+
+-- Scopes the user granted, space separated like in the Spotify token response.
+-- Everybody connected so far granted all the scopes the app used to request.
+ALTER TABLE users
+  ADD COLUMN scopes TEXT NOT NULL
+    DEFAULT 'playlist-modify-private playlist-modify-public user-top-read user-read-private';
+ALTER TABLE users ALTER COLUMN scopes DROP DEFAULT;
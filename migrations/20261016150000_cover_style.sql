@@ -0,0 +1 @@
+ALTER TABLE user_settings ADD COLUMN cover_style TEXT NOT NULL DEFAULT 'gradient';
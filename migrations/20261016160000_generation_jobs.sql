@@ -0,0 +1,17 @@
+-- Runs requested through `POST /generate`, processed by a background worker.
+CREATE TABLE generation_jobs (
+  id SERIAL NOT NULL,
+  PRIMARY KEY(id),
+  -- queued | running | finished | failed
+  state TEXT NOT NULL DEFAULT 'queued',
+  spotify_id TEXT,
+  force BOOLEAN NOT NULL DEFAULT false,
+  due_only BOOLEAN NOT NULL DEFAULT false,
+  botm_run_id INT REFERENCES botm_runs(id),
+  error TEXT,
+  created_at timestamptz NOT NULL DEFAULT now(),
+  started_at timestamptz,
+  finished_at timestamptz
+);
+
+CREATE INDEX generation_jobs_queued_idx ON generation_jobs (id) WHERE state = 'queued';
@@ -0,0 +1,15 @@
+-- Users whose BOTM for `month` failed, retried by later due runs until they succeed.
+CREATE TABLE generation_retries (
+  spotify_id TEXT NOT NULL REFERENCES users(spotify_id) ON DELETE CASCADE,
+  month DATE NOT NULL,
+  PRIMARY KEY(spotify_id, month),
+  attempts INT NOT NULL,
+  last_error TEXT NOT NULL,
+  next_attempt_at timestamptz NOT NULL,
+  -- Set once `attempts` reached the maximum, the user isn't retried anymore.
+  needs_attention BOOLEAN NOT NULL DEFAULT false,
+  created_at timestamptz NOT NULL DEFAULT now(),
+  updated_at timestamptz NOT NULL DEFAULT now()
+);
+
+CREATE INDEX generation_retries_needs_attention_idx ON generation_retries (spotify_id) WHERE needs_attention;
@@ -0,0 +1,4 @@
+-- Why and when a user was set inactive, e.g. because they removed the app in their Spotify account.
+ALTER TABLE users
+  ADD COLUMN deactivated_reason TEXT,
+  ADD COLUMN deactivated_at timestamptz;
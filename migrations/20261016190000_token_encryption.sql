@@ -0,0 +1,3 @@
+-- Id of the key `refresh_token` and `access_token` are encrypted with.
+-- NULL for tokens stored before encryption, the app encrypts those on startup.
+ALTER TABLE users ADD COLUMN token_key_id TEXT;
@@ -0,0 +1,16 @@
+-- Server-side state of browser sessions, the cookie only carries the session key.
+CREATE TABLE sessions (
+  -- SHA-256 of the session key, so a database dump doesn't contain usable keys
+  id TEXT NOT NULL,
+  PRIMARY KEY(id),
+  state JSONB NOT NULL,
+  -- The logged in user, sessions are dropped with the user
+  spotify_id TEXT REFERENCES users(spotify_id) ON DELETE CASCADE,
+  user_agent TEXT,
+  created_at timestamptz NOT NULL DEFAULT now(),
+  last_seen_at timestamptz NOT NULL DEFAULT now(),
+  expires_at timestamptz NOT NULL
+);
+
+CREATE INDEX sessions_spotify_id_idx ON sessions (spotify_id);
+CREATE INDEX sessions_expires_at_idx ON sessions (expires_at);
@@ -0,0 +1,2 @@
+-- What the start page tells users about their last BOTM, one of `NOTIFICATIONS` in src/settings.rs.
+ALTER TABLE user_settings ADD COLUMN notifications TEXT NOT NULL DEFAULT 'all';
@@ -0,0 +1,2 @@
+-- Day a user who paused generation is resumed on automatically, in their time zone.
+ALTER TABLE users ADD COLUMN resume_on DATE;
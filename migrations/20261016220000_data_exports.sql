@@ -0,0 +1,9 @@
+-- Every download of a user's data, as an audit trail.
+CREATE TABLE data_exports (
+  id SERIAL PRIMARY KEY,
+  spotify_id TEXT NOT NULL REFERENCES users(spotify_id) ON DELETE CASCADE,
+  user_agent TEXT,
+  created_at timestamptz NOT NULL DEFAULT now()
+);
+
+CREATE INDEX data_exports_spotify_id_idx ON data_exports (spotify_id);
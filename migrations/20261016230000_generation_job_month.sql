@@ -0,0 +1,2 @@
+-- Month a job generates for instead of the last one that has ended for each user.
+ALTER TABLE generation_jobs ADD COLUMN month DATE;
@@ -0,0 +1,10 @@
+-- Sign of life of the worker running a job, jobs without one for too long are requeued.
+ALTER TABLE generation_jobs ADD COLUMN heartbeat_at timestamptz;
+
+-- Users a run is generating for right now,
+-- so the scheduler and the job workers of all instances never generate for a user at the same time.
+CREATE TABLE generation_locks (
+  spotify_id TEXT NOT NULL REFERENCES users(spotify_id) ON DELETE CASCADE,
+  PRIMARY KEY(spotify_id),
+  locked_at timestamptz NOT NULL DEFAULT now()
+);
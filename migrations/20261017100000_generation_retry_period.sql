@@ -0,0 +1,3 @@
+-- The playlist of the run for `month` that failed: month | quarter | year.
+-- The BOTM of `month` itself may well exist if only the quarterly or yearly playlist failed.
+ALTER TABLE generation_retries ADD COLUMN period TEXT NOT NULL DEFAULT 'month';
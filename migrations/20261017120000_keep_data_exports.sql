@@ -0,0 +1,2 @@
+-- The audit trail of downloads outlives the user, disconnecting no longer deletes it.
+ALTER TABLE data_exports DROP CONSTRAINT data_exports_spotify_id_fkey;
@@ -0,0 +1,2 @@
+-- Playlists created in Spotify that weren't filled yet, a retry fills them instead of creating another one.
+ALTER TABLE botm_playlists ADD COLUMN pending BOOLEAN NOT NULL DEFAULT false;
@@ -0,0 +1,12 @@
+-- Times users paused their BOTM, months that end while a user is paused are never generated for them.
+CREATE TABLE generation_pauses (
+  spotify_id TEXT NOT NULL REFERENCES users(spotify_id) ON DELETE CASCADE,
+  paused_at timestamptz NOT NULL,
+  -- NULL while the user is still paused
+  resumed_at timestamptz,
+  PRIMARY KEY(spotify_id, paused_at)
+);
+
+-- Users that are paused right now
+INSERT INTO generation_pauses (spotify_id, paused_at)
+  SELECT spotify_id, deactivated_at FROM users WHERE deactivated_reason = 'paused' AND deactivated_at IS NOT NULL;
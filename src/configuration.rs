@@ -1,5 +1,6 @@
-use std::env;
+use std::{collections::HashMap, env, str::FromStr, time::Duration};
 
+use anyhow::Context;
 use config::Config;
 use secrecy::{ExposeSecret, SecretString};
 use serde_aux::prelude::deserialize_number_from_string;
@@ -8,14 +9,24 @@ use sqlx::{
     ConnectOptions,
 };
 use tracing::error;
+use url::Url;
+
+use crate::RetryConfig;
 
 #[derive(serde::Deserialize, Debug, Clone)]
 pub struct Configuration {
     pub application: AppConfig,
     // pub database: DatabaseConfig,
+    /// Overrides the `DATABASE_URL` environment variable, e.g. for a throwaway test database.
+    #[serde(default)]
+    pub database_url: Option<SecretString>,
     pub spotify: SpotifyConfig,
     pub cron_ips: Vec<String>,
     pub cookie_key: SecretString,
+    pub session: SessionConfig,
+    pub token_encryption: TokenEncryptionConfig,
+    pub scheduler: SchedulerConfig,
+    pub generator: GeneratorConfig,
 }
 
 #[derive(serde::Deserialize, Debug, Clone)]
@@ -42,6 +53,105 @@ pub struct SpotifyConfig {
     pub client_id: SecretString,
     pub client_secret: SecretString,
     pub redirect_uri: String,
+    /// Base of the Web API, with a trailing slash, e.g. `https://api.spotify.com/v1/`.
+    pub api_base_url: String,
+    /// Base of the accounts service, with a trailing slash, e.g. `https://accounts.spotify.com/`.
+    pub accounts_base_url: String,
+    pub retry: RetryConfig,
+    /// Requests per second to Spotify over all users, `0` for no limit.
+    pub max_requests_per_second: u32,
+}
+
+impl SpotifyConfig {
+    pub fn api_base_url(&self) -> anyhow::Result<Url> {
+        Url::parse(&self.api_base_url)
+            .with_context(|| format!("Failed to parse Spotify api url \"{}\"", self.api_base_url))
+    }
+
+    /// The url of `path` on the accounts service.
+    pub fn accounts_url(&self, path: &str) -> anyhow::Result<Url> {
+        Url::parse(&self.accounts_base_url)
+            .and_then(|base| base.join(path))
+            .with_context(|| {
+                format!(
+                    "Failed to parse Spotify accounts url \"{}\" with {path}",
+                    self.accounts_base_url
+                )
+            })
+    }
+}
+
+#[derive(serde::Deserialize, Debug, Clone)]
+pub struct SchedulerConfig {
+    pub enabled: bool,
+    /// Cron expression including seconds, e.g. `0 5 * * * *` for five past every hour.
+    pub cron: String,
+    /// Time zone the cron expression is evaluated in.
+    pub timezone: chrono_tz::Tz,
+}
+
+impl SchedulerConfig {
+    pub fn schedule(&self) -> anyhow::Result<cron::Schedule> {
+        cron::Schedule::from_str(&self.cron)
+            .with_context(|| format!("Failed to parse scheduler cron \"{}\"", self.cron))
+    }
+}
+
+#[derive(serde::Deserialize, Debug, Clone)]
+pub struct GeneratorConfig {
+    /// Number of users generated for at the same time.
+    pub concurrency: usize,
+    /// How often the job worker looks for jobs queued by other instances.
+    pub poll_interval_ms: u64,
+    /// Running jobs and locked users without a sign of life for this long
+    /// are taken over, the instance running them is assumed to have crashed.
+    pub stale_after_secs: u64,
+    pub retry: GenerationRetryConfig,
+}
+
+impl GeneratorConfig {
+    pub fn stale_after(&self) -> Duration {
+        Duration::from_secs(self.stale_after_secs)
+    }
+}
+
+/// How failed users are retried by later due runs.
+#[derive(serde::Deserialize, Debug, Clone)]
+pub struct GenerationRetryConfig {
+    /// Attempts per user and month, including the first one,
+    /// after which the user is flagged for attention instead.
+    pub max_attempts: i32,
+    /// Backoff before the first retry, doubled for every further retry.
+    pub initial_backoff_mins: i64,
+    pub max_backoff_mins: i64,
+}
+
+impl GenerationRetryConfig {
+    /// The backoff after the failed attempt number `attempt`, starting at 1.
+    pub fn backoff(&self, attempt: i32) -> chrono::Duration {
+        let doublings = (attempt - 1).clamp(0, 30) as u32;
+        let backoff = self
+            .initial_backoff_mins
+            .saturating_mul(2i64.saturating_pow(doublings))
+            .min(self.max_backoff_mins);
+        chrono::Duration::minutes(backoff)
+    }
+}
+
+#[derive(serde::Deserialize, Debug, Clone)]
+pub struct SessionConfig {
+    /// Days without a request after which a session expires.
+    pub ttl_days: i64,
+}
+
+/// Keys the Spotify tokens of users are encrypted with.
+#[derive(serde::Deserialize, Debug, Clone)]
+pub struct TokenEncryptionConfig {
+    /// Id of the key new tokens are encrypted with, stored next to the tokens.
+    pub current_key_id: String,
+    /// Base64 encoded 256 bit keys by id.
+    /// Previous keys stay here until `botm_web reencrypt-tokens` moved every token to the current one.
+    pub keys: HashMap<String, SecretString>,
 }
 
 impl Configuration {
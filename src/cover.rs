@@ -0,0 +1,290 @@
+use anyhow::Context;
+use chrono::{Datelike, NaiveDate};
+use jpeg_encoder::{ColorType, Encoder};
+
+/// Width and height of the generated covers, Spotify shows them at up to 640px.
+const SIZE: usize = 640;
+const JPEG_QUALITY: u8 = 85;
+
+/// Look of the cover uploaded for generated playlists, stored as text in `user_settings.cover_style`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CoverStyle {
+    /// Keep the mosaic cover of Spotify.
+    None,
+    Solid,
+    Gradient,
+}
+
+/// Cover styles with a label for the settings page.
+pub const COVER_STYLES: &[(CoverStyle, &str)] = &[
+    (CoverStyle::Gradient, "Gradient"),
+    (CoverStyle::Solid, "Solid colour"),
+    (CoverStyle::None, "Spotify default"),
+];
+
+impl CoverStyle {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CoverStyle::None => "none",
+            CoverStyle::Solid => "solid",
+            CoverStyle::Gradient => "gradient",
+        }
+    }
+
+    pub fn parse(style: &str) -> Option<Self> {
+        COVER_STYLES
+            .iter()
+            .map(|(style, _)| *style)
+            .find(|s| s.as_str() == style)
+    }
+}
+
+/// Hue of the cover for the month, going once around the colour wheel per year.
+pub fn month_hue(month: NaiveDate) -> f32 {
+    month.month0() as f32 * 30.0
+}
+
+/// Renders a square JPEG cover with `title` and `subtitle` on a colour of `hue`.
+///
+/// Returns `None` for [`CoverStyle::None`].
+pub fn render_cover(
+    style: CoverStyle,
+    title: &str,
+    subtitle: &str,
+    hue: f32,
+) -> anyhow::Result<Option<Vec<u8>>> {
+    let (top, bottom) = match style {
+        CoverStyle::None => return Ok(None),
+        CoverStyle::Solid => (hsl_to_rgb(hue, 0.55, 0.45), hsl_to_rgb(hue, 0.55, 0.45)),
+        CoverStyle::Gradient => (
+            hsl_to_rgb(hue, 0.65, 0.55),
+            hsl_to_rgb(hue + 40.0, 0.6, 0.25),
+        ),
+    };
+
+    let mut pixels = vec![0u8; SIZE * SIZE * 3];
+    for (y, row) in pixels.chunks_exact_mut(SIZE * 3).enumerate() {
+        let t = y as f32 / (SIZE - 1) as f32;
+        let color = [
+            lerp(top[0], bottom[0], t),
+            lerp(top[1], bottom[1], t),
+            lerp(top[2], bottom[2], t),
+        ];
+        for pixel in row.chunks_exact_mut(3) {
+            pixel.copy_from_slice(&color);
+        }
+    }
+
+    let white = [255, 250, 240];
+    let title = title.to_uppercase();
+    let subtitle = subtitle.to_uppercase();
+    let title_scale = fitting_scale(&title, 12);
+    draw_text(
+        &mut pixels,
+        &title,
+        SIZE / 2 - GLYPH_HEIGHT * title_scale,
+        title_scale,
+        white,
+    );
+    draw_text(&mut pixels, &subtitle, SIZE / 2 + 20, 8, white);
+    draw_text(&mut pixels, "BOTM", SIZE - 80, 5, white);
+
+    let mut jpeg = Vec::new();
+    Encoder::new(&mut jpeg, JPEG_QUALITY)
+        .encode(&pixels, SIZE as u16, SIZE as u16, ColorType::Rgb)
+        .context("Failed to encode cover")?;
+    Ok(Some(jpeg))
+}
+
+fn lerp(a: u8, b: u8, t: f32) -> u8 {
+    (a as f32 + (b as f32 - a as f32) * t).round() as u8
+}
+
+/// Converts a colour from HSL, with `hue` in degrees, to RGB.
+fn hsl_to_rgb(hue: f32, saturation: f32, lightness: f32) -> [u8; 3] {
+    let hue = hue.rem_euclid(360.0) / 60.0;
+    let chroma = (1.0 - (2.0 * lightness - 1.0).abs()) * saturation;
+    let x = chroma * (1.0 - (hue % 2.0 - 1.0).abs());
+    let (r, g, b) = match hue as u32 {
+        0 => (chroma, x, 0.0),
+        1 => (x, chroma, 0.0),
+        2 => (0.0, chroma, x),
+        3 => (0.0, x, chroma),
+        4 => (x, 0.0, chroma),
+        _ => (chroma, 0.0, x),
+    };
+    let m = lightness - chroma / 2.0;
+    [r, g, b].map(|c| ((c + m) * 255.0).round() as u8)
+}
+
+const GLYPH_WIDTH: usize = 5;
+const GLYPH_HEIGHT: usize = 7;
+/// Margin of the text to the sides of the cover.
+const MARGIN: usize = 40;
+
+/// The largest scale up to `max` at which `text` fits the cover.
+fn fitting_scale(text: &str, max: usize) -> usize {
+    let columns = text_columns(text).max(1);
+    ((SIZE - 2 * MARGIN) / columns).clamp(1, max)
+}
+
+/// Width of `text` in font pixels, glyphs are separated by one empty column.
+fn text_columns(text: &str) -> usize {
+    (text.chars().count() * (GLYPH_WIDTH + 1)).saturating_sub(1)
+}
+
+/// Draws `text` horizontally centred with its top at `top`.
+fn draw_text(pixels: &mut [u8], text: &str, top: usize, scale: usize, color: [u8; 3]) {
+    let left = SIZE.saturating_sub(text_columns(text) * scale) / 2;
+    for (i, c) in text.chars().enumerate() {
+        let glyph_left = left + i * (GLYPH_WIDTH + 1) * scale;
+        for (row, bits) in glyph(c).iter().enumerate() {
+            for column in 0..GLYPH_WIDTH {
+                if bits & (1 << (GLYPH_WIDTH - 1 - column)) == 0 {
+                    continue;
+                }
+                for y in top + row * scale..top + (row + 1) * scale {
+                    for x in glyph_left + column * scale..glyph_left + (column + 1) * scale {
+                        if x < SIZE && y < SIZE {
+                            let i = (y * SIZE + x) * 3;
+                            pixels[i..i + 3].copy_from_slice(&color);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// 5x7 bitmap of `c`, one byte per row with the leftmost pixel in the 5th bit.
+///
+/// Characters without a glyph are drawn as space.
+#[rustfmt::skip]
+fn glyph(c: char) -> [u8; GLYPH_HEIGHT] {
+    match c {
+        'A' => [0b01110, 0b10001, 0b10001, 0b11111, 0b10001, 0b10001, 0b10001],
+        'B' => [0b11110, 0b10001, 0b10001, 0b11110, 0b10001, 0b10001, 0b11110],
+        'C' => [0b01110, 0b10001, 0b10000, 0b10000, 0b10000, 0b10001, 0b01110],
+        'D' => [0b11110, 0b10001, 0b10001, 0b10001, 0b10001, 0b10001, 0b11110],
+        'E' => [0b11111, 0b10000, 0b10000, 0b11110, 0b10000, 0b10000, 0b11111],
+        'F' => [0b11111, 0b10000, 0b10000, 0b11110, 0b10000, 0b10000, 0b10000],
+        'G' => [0b01110, 0b10001, 0b10000, 0b10111, 0b10001, 0b10001, 0b01111],
+        'H' => [0b10001, 0b10001, 0b10001, 0b11111, 0b10001, 0b10001, 0b10001],
+        'I' => [0b01110, 0b00100, 0b00100, 0b00100, 0b00100, 0b00100, 0b01110],
+        'J' => [0b00111, 0b00010, 0b00010, 0b00010, 0b00010, 0b10010, 0b01100],
+        'K' => [0b10001, 0b10010, 0b10100, 0b11000, 0b10100, 0b10010, 0b10001],
+        'L' => [0b10000, 0b10000, 0b10000, 0b10000, 0b10000, 0b10000, 0b11111],
+        'M' => [0b10001, 0b11011, 0b10101, 0b10101, 0b10001, 0b10001, 0b10001],
+        'N' => [0b10001, 0b10001, 0b11001, 0b10101, 0b10011, 0b10001, 0b10001],
+        'O' => [0b01110, 0b10001, 0b10001, 0b10001, 0b10001, 0b10001, 0b01110],
+        'P' => [0b11110, 0b10001, 0b10001, 0b11110, 0b10000, 0b10000, 0b10000],
+        'Q' => [0b01110, 0b10001, 0b10001, 0b10001, 0b10101, 0b10010, 0b01101],
+        'R' => [0b11110, 0b10001, 0b10001, 0b11110, 0b10100, 0b10010, 0b10001],
+        'S' => [0b01111, 0b10000, 0b10000, 0b01110, 0b00001, 0b00001, 0b11110],
+        'T' => [0b11111, 0b00100, 0b00100, 0b00100, 0b00100, 0b00100, 0b00100],
+        'U' => [0b10001, 0b10001, 0b10001, 0b10001, 0b10001, 0b10001, 0b01110],
+        'V' => [0b10001, 0b10001, 0b10001, 0b10001, 0b10001, 0b01010, 0b00100],
+        'W' => [0b10001, 0b10001, 0b10001, 0b10101, 0b10101, 0b10101, 0b01010],
+        'X' => [0b10001, 0b10001, 0b01010, 0b00100, 0b01010, 0b10001, 0b10001],
+        'Y' => [0b10001, 0b10001, 0b10001, 0b01010, 0b00100, 0b00100, 0b00100],
+        'Z' => [0b11111, 0b00001, 0b00010, 0b00100, 0b01000, 0b10000, 0b11111],
+        '0' => [0b01110, 0b10001, 0b10011, 0b10101, 0b11001, 0b10001, 0b01110],
+        '1' => [0b00100, 0b01100, 0b00100, 0b00100, 0b00100, 0b00100, 0b01110],
+        '2' => [0b01110, 0b10001, 0b00001, 0b00010, 0b00100, 0b01000, 0b11111],
+        '3' => [0b11111, 0b00010, 0b00100, 0b00010, 0b00001, 0b10001, 0b01110],
+        '4' => [0b00010, 0b00110, 0b01010, 0b10010, 0b11111, 0b00010, 0b00010],
+        '5' => [0b11111, 0b10000, 0b11110, 0b00001, 0b00001, 0b10001, 0b01110],
+        '6' => [0b00110, 0b01000, 0b10000, 0b11110, 0b10001, 0b10001, 0b01110],
+        '7' => [0b11111, 0b00001, 0b00010, 0b00100, 0b01000, 0b01000, 0b01000],
+        '8' => [0b01110, 0b10001, 0b10001, 0b01110, 0b10001, 0b10001, 0b01110],
+        '9' => [0b01110, 0b10001, 0b10001, 0b01111, 0b00001, 0b00010, 0b01100],
+        '-' => [0b00000, 0b00000, 0b00000, 0b11111, 0b00000, 0b00000, 0b00000],
+        _ => [0; GLYPH_HEIGHT],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pixel(pixels: &[u8], x: usize, y: usize) -> [u8; 3] {
+        let i = (y * SIZE + x) * 3;
+        [pixels[i], pixels[i + 1], pixels[i + 2]]
+    }
+
+    #[test]
+    fn glyphs_fit_their_width() {
+        for c in ('A'..='Z').chain('0'..='9').chain(['-']) {
+            let glyph = glyph(c);
+
+            assert!(glyph.iter().all(|row| *row < 1 << GLYPH_WIDTH), "{c}");
+            assert!(glyph.iter().any(|row| *row != 0), "{c} is empty");
+        }
+    }
+
+    #[test]
+    fn unknown_characters_are_drawn_as_space() {
+        assert_eq!(glyph('a'), [0; GLYPH_HEIGHT]);
+        assert_eq!(glyph('ä'), [0; GLYPH_HEIGHT]);
+        assert_eq!(glyph(' '), [0; GLYPH_HEIGHT]);
+    }
+
+    #[test]
+    fn text_is_drawn_centred() {
+        let mut pixels = vec![0u8; SIZE * SIZE * 3];
+        let white = [255, 255, 255];
+
+        draw_text(&mut pixels, "I", 100, 2, white);
+
+        // The top row of I is 0b01110, two pixels wide per font pixel at scale 2
+        let left = (SIZE - GLYPH_WIDTH * 2) / 2;
+        assert_eq!(pixel(&pixels, left, 100), [0, 0, 0]);
+        assert_eq!(pixel(&pixels, left + 2, 100), white);
+        assert_eq!(pixel(&pixels, left + 7, 101), white);
+        assert_eq!(pixel(&pixels, left + 8, 100), [0, 0, 0]);
+        // The stem is in the middle column
+        assert_eq!(pixel(&pixels, left + 4, 105), white);
+        assert_eq!(pixel(&pixels, left + 2, 105), [0, 0, 0]);
+        assert_eq!(pixel(&pixels, left + 4, 100 + GLYPH_HEIGHT * 2), [0, 0, 0]);
+    }
+
+    #[test]
+    fn long_text_is_scaled_down_to_fit() {
+        assert_eq!(text_columns(""), 0);
+        assert_eq!(text_columns("AB"), 2 * GLYPH_WIDTH + 1);
+        assert_eq!(fitting_scale("MAY", 12), 12);
+
+        let title = "SEPTEMBER";
+        let scale = fitting_scale(title, 12);
+        assert!(scale < 12);
+        assert!(text_columns(title) * scale <= SIZE - 2 * MARGIN);
+        assert_eq!(fitting_scale(&"W".repeat(200), 12), 1);
+    }
+
+    #[test]
+    fn covers_are_jpegs() {
+        assert!(render_cover(CoverStyle::None, "SEPTEMBER", "2026", 0.0)
+            .unwrap()
+            .is_none());
+
+        for style in [CoverStyle::Solid, CoverStyle::Gradient] {
+            let jpeg = render_cover(style, "SEPTEMBER", "2026", month_hue(NaiveDate::MIN))
+                .unwrap()
+                .unwrap();
+
+            assert_eq!(&jpeg[..2], [0xFF, 0xD8], "{style:?}");
+        }
+    }
+
+    #[test]
+    fn hues_go_around_the_colour_wheel_once_a_year() {
+        let january = NaiveDate::from_ymd_opt(2026, 1, 1).unwrap();
+        let december = NaiveDate::from_ymd_opt(2026, 12, 1).unwrap();
+
+        assert_eq!(month_hue(january), 0.0);
+        assert_eq!(month_hue(december), 330.0);
+        assert_eq!(hsl_to_rgb(0.0, 1.0, 0.5), [255, 0, 0]);
+        assert_eq!(hsl_to_rgb(360.0, 1.0, 0.5), [255, 0, 0]);
+        assert_eq!(hsl_to_rgb(120.0, 1.0, 0.5), [0, 255, 0]);
+    }
+}
@@ -0,0 +1,161 @@
+use std::{
+    future::{ready, Ready},
+    pin::Pin,
+    rc::Rc,
+};
+
+use actix_session::{Session, SessionExt};
+use actix_web::{
+    body::EitherBody,
+    dev::{forward_ready, Payload, Service, ServiceRequest, ServiceResponse, Transform},
+    error::PayloadError,
+    http::Method,
+    web::Bytes,
+    HttpResponse,
+};
+use futures_util::{future::LocalBoxFuture, stream, Stream};
+use rand::{distributions::Alphanumeric, rngs::OsRng, Rng};
+
+/// Session key of the CSRF token, also the name of the form field it is sent back in.
+pub const CSRF_TOKEN_KEY: &str = "csrf_token";
+
+/// The CSRF token of the session, creating one if the session has none yet.
+///
+/// Forms posting to the app have to send it in a hidden `csrf_token` field.
+pub fn csrf_token(session: &Session) -> anyhow::Result<String> {
+    if let Some(token) = session.get::<String>(CSRF_TOKEN_KEY)? {
+        return Ok(token);
+    }
+    let token: String = (0..32)
+        .map(|_| OsRng.sample(Alphanumeric) as char)
+        .collect();
+    session.insert(CSRF_TOKEN_KEY, &token)?;
+    Ok(token)
+}
+
+/// Rejects requests other than `GET`, `HEAD` and `OPTIONS` with `403 Forbidden`
+/// unless their form carries the CSRF token of the session.
+///
+/// Has to be wrapped by the [`SessionMiddleware`](actix_session::SessionMiddleware).
+#[derive(Default)]
+pub struct VerifyCsrf {
+    exempt: Vec<&'static str>,
+}
+
+impl VerifyCsrf {
+    /// Skips the check for `path` and everything below it,
+    /// for endpoints authenticating without the session, like `/generate`.
+    pub fn exempt(mut self, path: &'static str) -> Self {
+        self.exempt.push(path);
+        self
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for VerifyCsrf
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Transform = VerifyCsrfMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(VerifyCsrfMiddleware {
+            service: Rc::new(service),
+            exempt: Rc::from(self.exempt.as_slice()),
+        }))
+    }
+}
+
+pub struct VerifyCsrfMiddleware<S> {
+    service: Rc<S>,
+    exempt: Rc<[&'static str]>,
+}
+
+impl<S> VerifyCsrfMiddleware<S> {
+    fn needs_check(&self, req: &ServiceRequest) -> bool {
+        let safe = [Method::GET, Method::HEAD, Method::OPTIONS].contains(req.method());
+        let exempt = self.exempt.iter().any(|path| {
+            req.path()
+                .strip_prefix(path)
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+        });
+        !safe && !exempt
+    }
+}
+
+impl<S, B> Service<ServiceRequest> for VerifyCsrfMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, mut req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        if !self.needs_check(&req) {
+            return Box::pin(async move {
+                service
+                    .call(req)
+                    .await
+                    .map(ServiceResponse::map_into_left_body)
+            });
+        }
+
+        Box::pin(async move {
+            let expected = req
+                .get_session()
+                .get::<String>(CSRF_TOKEN_KEY)
+                .ok()
+                .flatten();
+            let body = req.extract::<Bytes>().await?;
+            let submitted = url::form_urlencoded::parse(&body)
+                .find(|(key, _)| key == CSRF_TOKEN_KEY)
+                .map(|(_, token)| token.into_owned());
+
+            let valid = match (expected, submitted) {
+                (Some(expected), Some(submitted)) => constant_time_eq(&expected, &submitted),
+                _ => false,
+            };
+            if !valid {
+                tracing::warn!(
+                    "Rejected {} {} without a valid CSRF token",
+                    req.method(),
+                    req.path()
+                );
+                let response = HttpResponse::Forbidden()
+                    .body("Invalid CSRF token, please reload the page and try again.");
+                return Ok(req.into_response(response).map_into_right_body());
+            }
+
+            // The handler reads the form again
+            req.set_payload(bytes_payload(body));
+            service
+                .call(req)
+                .await
+                .map(ServiceResponse::map_into_left_body)
+        })
+    }
+}
+
+fn bytes_payload(body: Bytes) -> Payload {
+    let stream: Pin<Box<dyn Stream<Item = Result<Bytes, PayloadError>>>> =
+        Box::pin(stream::once(async move { Ok(body) }));
+    Payload::from(stream)
+}
+
+/// Compares without returning early, so the time taken doesn't tell how much of a guess matched.
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
@@ -0,0 +1,313 @@
+use std::{io, mem};
+
+use actix_web::web::Bytes;
+use anyhow::{anyhow, Context};
+use chrono::{DateTime, NaiveDate, Utc};
+use futures_util::{stream::BoxStream, TryStreamExt};
+use serde::Serialize;
+use sqlx::PgPool;
+use tokio::sync::mpsc;
+
+use crate::{UserSession, UserSettings};
+
+/// Size of the chunks the export is streamed in.
+const CHUNK_SIZE: usize = 8 * 1024;
+
+/// Everything stored about a user, for them to download.
+///
+/// Tokens are left out, they are only of use to BOTM.
+/// So are the tracks of the playlists: BOTM never stores them, it adds the top tracks straight to
+/// the playlist, which keeps them in Spotify under `playlist_id`.
+///
+/// Only the single rows are loaded up front, the lists are read from the database while
+/// [`DataExport::write`] writes them.
+pub struct DataExport {
+    pub exported_at: DateTime<Utc>,
+    spotify_id: String,
+    user: ExportedUser,
+    /// The settings generation uses, the defaults if the user never saved any.
+    settings: UserSettings,
+}
+
+#[derive(serde::Serialize, Debug)]
+pub struct ExportedUser {
+    pub spotify_id: String,
+    pub active: bool,
+    pub timezone: Option<String>,
+    /// Space separated scopes the user granted.
+    pub scopes: String,
+    pub deactivated_reason: Option<String>,
+    pub deactivated_at: Option<DateTime<Utc>>,
+    pub resume_on: Option<NaiveDate>,
+}
+
+#[derive(serde::Serialize, Debug)]
+pub struct ExportedPlaylist {
+    pub period: String,
+    pub month: NaiveDate,
+    pub playlist_id: String,
+    pub name: String,
+    pub track_count: i32,
+    /// Created in Spotify, but generating failed before its tracks were added.
+    pub pending: bool,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(serde::Serialize, Debug)]
+pub struct ExportedRun {
+    pub run_id: i32,
+    pub month: Option<NaiveDate>,
+    pub period: Option<String>,
+    pub status: String,
+    pub error: Option<String>,
+    pub playlist_id: Option<String>,
+    pub track_count: Option<i32>,
+    pub duration_ms: Option<i64>,
+    pub finished_at: DateTime<Utc>,
+}
+
+#[derive(serde::Serialize, Debug)]
+pub struct ExportedRetry {
+    pub month: NaiveDate,
+    /// The playlist of the run for `month` that failed.
+    pub period: String,
+    pub attempts: i32,
+    pub last_error: String,
+    pub next_attempt_at: DateTime<Utc>,
+    pub needs_attention: bool,
+}
+
+#[derive(serde::Serialize, Debug)]
+pub struct ExportedJob {
+    pub id: i32,
+    pub state: String,
+    pub force: bool,
+    pub due_only: bool,
+    pub month: Option<NaiveDate>,
+    pub error: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub finished_at: Option<DateTime<Utc>>,
+}
+
+#[derive(serde::Serialize, Debug)]
+pub struct ExportedPause {
+    pub paused_at: DateTime<Utc>,
+    pub resumed_at: Option<DateTime<Utc>>,
+}
+
+#[derive(serde::Serialize, Debug)]
+pub struct ExportedDataExport {
+    pub user_agent: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl DataExport {
+    pub async fn load(pg_pool: &PgPool, spotify_id: &str) -> anyhow::Result<Self> {
+        let user = sqlx::query_as!(
+            ExportedUser,
+            r#"SELECT spotify_id, active, timezone, scopes, deactivated_reason, deactivated_at, resume_on
+                FROM users WHERE spotify_id = $1"#,
+            spotify_id
+        )
+        .fetch_one(pg_pool)
+        .await
+        .with_context(|| format!("Failed to get user: {spotify_id}"))?;
+
+        Ok(Self {
+            exported_at: Utc::now(),
+            spotify_id: spotify_id.to_owned(),
+            user,
+            settings: UserSettings::load(pg_pool, spotify_id).await?,
+        })
+    }
+
+    /// Writes the export as a JSON object, listing the downloads before `export_id`.
+    pub async fn write(
+        &self,
+        pg_pool: &PgPool,
+        export_id: i32,
+        out: &mut JsonWriter,
+    ) -> anyhow::Result<()> {
+        let spotify_id = self.spotify_id.as_str();
+        out.field("exported_at", &self.exported_at).await?;
+        out.field("user", &self.user).await?;
+        out.field("settings", &self.settings).await?;
+
+        let playlists = sqlx::query_as!(
+            ExportedPlaylist,
+            r#"SELECT period, month, playlist_id, name, track_count, pending, created_at, updated_at
+                FROM botm_playlists WHERE spotify_id = $1 ORDER BY month, period"#,
+            spotify_id
+        )
+        .fetch(pg_pool);
+        out.list("playlists", playlists)
+            .await
+            .with_context(|| format!("Failed to export playlists of user: {spotify_id}"))?;
+
+        // Outcome of every run that generated for the user
+        let generation_runs = sqlx::query_as!(
+            ExportedRun,
+            r#"SELECT botm_run_id AS run_id, month, period, status, error, playlist_id, track_count, duration_ms, finished_at
+                FROM user_botm_runs WHERE spotify_id = $1 ORDER BY finished_at"#,
+            spotify_id
+        )
+        .fetch(pg_pool);
+        out.list("generation_runs", generation_runs)
+            .await
+            .with_context(|| format!("Failed to export runs of user: {spotify_id}"))?;
+
+        let failed_months = sqlx::query_as!(
+            ExportedRetry,
+            r#"SELECT month, period, attempts, last_error, next_attempt_at, needs_attention
+                FROM generation_retries WHERE spotify_id = $1 ORDER BY month"#,
+            spotify_id
+        )
+        .fetch(pg_pool);
+        out.list("failed_months", failed_months)
+            .await
+            .with_context(|| format!("Failed to export failed months of user: {spotify_id}"))?;
+
+        let generation_jobs = sqlx::query_as!(
+            ExportedJob,
+            r#"SELECT id, state, force, due_only, month, error, created_at, finished_at
+                FROM generation_jobs WHERE spotify_id = $1 ORDER BY id"#,
+            spotify_id
+        )
+        .fetch(pg_pool);
+        out.list("generation_jobs", generation_jobs)
+            .await
+            .with_context(|| format!("Failed to export generation jobs of user: {spotify_id}"))?;
+
+        let pauses = sqlx::query_as!(
+            ExportedPause,
+            r#"SELECT paused_at, resumed_at
+                FROM generation_pauses WHERE spotify_id = $1 ORDER BY paused_at"#,
+            spotify_id
+        )
+        .fetch(pg_pool);
+        out.list("pauses", pauses)
+            .await
+            .with_context(|| format!("Failed to export pauses of user: {spotify_id}"))?;
+
+        let sessions = sqlx::query_as!(
+            UserSession,
+            r#"SELECT user_agent, created_at, last_seen_at, expires_at FROM sessions
+                WHERE spotify_id = $1 AND expires_at > now()
+                ORDER BY last_seen_at DESC"#,
+            spotify_id
+        )
+        .fetch(pg_pool);
+        out.list("sessions", sessions)
+            .await
+            .with_context(|| format!("Failed to export sessions of user: {spotify_id}"))?;
+
+        let data_exports = sqlx::query_as!(
+            ExportedDataExport,
+            "SELECT user_agent, created_at FROM data_exports WHERE spotify_id = $1 AND id < $2 ORDER BY id",
+            spotify_id,
+            export_id
+        )
+        .fetch(pg_pool);
+        out.list("data_exports", data_exports)
+            .await
+            .with_context(|| format!("Failed to export data exports of user: {spotify_id}"))?;
+
+        out.finish().await
+    }
+}
+
+/// Writes a JSON object field by field and sends it in chunks of about [`CHUNK_SIZE`],
+/// so only a chunk of it is in memory at a time.
+pub struct JsonWriter {
+    sender: mpsc::Sender<io::Result<Bytes>>,
+    buffer: Vec<u8>,
+    fields: usize,
+}
+
+impl JsonWriter {
+    pub fn new(sender: mpsc::Sender<io::Result<Bytes>>) -> Self {
+        Self {
+            sender,
+            buffer: Vec::with_capacity(CHUNK_SIZE),
+            fields: 0,
+        }
+    }
+
+    pub async fn field<T: Serialize>(&mut self, name: &str, value: &T) -> anyhow::Result<()> {
+        self.key(name)?;
+        serde_json::to_writer(&mut self.buffer, value)?;
+        self.send_full_chunk().await
+    }
+
+    /// Writes the rows as a list while they are read, one row per line.
+    pub async fn list<T: Serialize>(
+        &mut self,
+        name: &str,
+        mut rows: BoxStream<'_, sqlx::Result<T>>,
+    ) -> anyhow::Result<()> {
+        self.key(name)?;
+        self.buffer.push(b'[');
+        let mut empty = true;
+        while let Some(row) = rows.try_next().await? {
+            self.buffer
+                .extend_from_slice(if empty { b"\n    " } else { b",\n    " });
+            serde_json::to_writer(&mut self.buffer, &row)?;
+            empty = false;
+            self.send_full_chunk().await?;
+        }
+        self.buffer
+            .extend_from_slice(if empty { b"]" } else { b"\n  ]" });
+        Ok(())
+    }
+
+    /// Closes the object and sends what is left of it.
+    pub async fn finish(&mut self) -> anyhow::Result<()> {
+        self.buffer
+            .extend_from_slice(if self.fields == 0 { b"{}\n" } else { b"\n}\n" });
+        self.send_chunk().await
+    }
+
+    fn key(&mut self, name: &str) -> anyhow::Result<()> {
+        self.buffer
+            .extend_from_slice(if self.fields == 0 { b"{\n  " } else { b",\n  " });
+        serde_json::to_writer(&mut self.buffer, name)?;
+        self.buffer.extend_from_slice(b": ");
+        self.fields += 1;
+        Ok(())
+    }
+
+    async fn send_full_chunk(&mut self) -> anyhow::Result<()> {
+        if self.buffer.len() < CHUNK_SIZE {
+            return Ok(());
+        }
+        self.send_chunk().await
+    }
+
+    async fn send_chunk(&mut self) -> anyhow::Result<()> {
+        let chunk = Bytes::from(mem::replace(
+            &mut self.buffer,
+            Vec::with_capacity(CHUNK_SIZE),
+        ));
+        self.sender
+            .send(Ok(chunk))
+            .await
+            .map_err(|_| anyhow!("Download was cancelled"))
+    }
+}
+
+/// Records in `data_exports` that the user downloaded their data, returns the id of the download.
+pub async fn record_data_export(
+    pg_pool: &PgPool,
+    spotify_id: &str,
+    user_agent: Option<&str>,
+) -> anyhow::Result<i32> {
+    sqlx::query_scalar!(
+        "INSERT INTO data_exports (spotify_id, user_agent) VALUES ($1, $2) RETURNING id",
+        spotify_id,
+        user_agent
+    )
+    .fetch_one(pg_pool)
+    .await
+    .with_context(|| format!("Failed to record data export of user: {spotify_id}"))
+}
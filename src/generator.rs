@@ -0,0 +1,1132 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use chrono::{DateTime, Datelike, Months, NaiveDate, TimeZone, Utc};
+use chrono_tz::Tz;
+use futures_util::StreamExt;
+use secrecy::ExposeSecret;
+use sqlx::PgPool;
+use tracing::{debug, log::trace};
+
+use crate::{
+    fetch_top_tracks, month_hue, parse_timezone, render_cover, render_template, resume_due_users,
+    start_of_day, AuthorizationRevoked, CoverStyle, GenerationRetryConfig, GeneratorConfig,
+    PlaylistDetails, SpotifyApi, TemplateValues, TokenManager, UserSettings,
+    MAX_TRACKS_PER_REQUEST,
+};
+
+/// How often a run waiting for another one to finish with a user checks again.
+const USER_LOCK_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Debug)]
+struct UserData {
+    spotify_id: String,
+    timezone: Option<String>,
+    /// Space separated scopes the user granted.
+    scopes: String,
+}
+
+impl UserData {
+    /// The time zone of the user, UTC if none or an unknown one is stored.
+    fn timezone(&self) -> Tz {
+        parse_timezone(self.timezone.as_deref())
+    }
+
+    fn now(&self) -> chrono::DateTime<Tz> {
+        chrono::Utc::now().with_timezone(&self.timezone())
+    }
+}
+
+/// Selects which users a BOTM run generates for.
+#[derive(Debug, Default)]
+pub struct GenerateOptions<'a> {
+    /// Only generate for this user.
+    pub spotify_id: Option<&'a str>,
+    /// Regenerate playlists that already exist for the target month.
+    pub force: bool,
+    /// Only generate for users whose month has ended in their own time zone
+    /// and who don't have a playlist for it yet.
+    pub due_only: bool,
+    /// Generate for this month instead of the last one that has ended for each user,
+    /// e.g. to fill in a missed month. It has to have ended for every user.
+    pub month: Option<NaiveDate>,
+}
+
+/// Totals of a finished BOTM run.
+///
+/// `created`, `updated` and `skipped` count playlists, the others count users.
+#[derive(Debug)]
+pub struct RunSummary {
+    pub run_id: i32,
+    pub total: usize,
+    pub created: usize,
+    pub updated: usize,
+    pub skipped: usize,
+    pub failed: usize,
+}
+
+/// Generates the BOTMs for the active users selected by `options`
+/// and records the run in the database.
+///
+/// Failing users don't abort the run, they are counted in `RunSummary::failed`.
+/// Returns `None` without recording a run if there was nobody to generate for.
+pub async fn generate_botms(
+    pg_pool: &PgPool,
+    tokens: &TokenManager,
+    spotify_api: &dyn SpotifyApi,
+    config: &GeneratorConfig,
+    options: &GenerateOptions<'_>,
+) -> anyhow::Result<Option<RunSummary>> {
+    let Some(run) = prepare_run(pg_pool, options).await? else {
+        return Ok(None);
+    };
+    Ok(Some(
+        run.execute(pg_pool, tokens, spotify_api, config).await,
+    ))
+}
+
+/// A BOTM run that is recorded in `botm_runs` but wasn't executed yet.
+pub struct PreparedRun {
+    run_id: i32,
+    users: Vec<UserData>,
+    force: bool,
+    month: Option<NaiveDate>,
+}
+
+/// Selects the users for a BOTM run and opens the run in the database.
+///
+/// Returns `None` without recording a run if there is nobody to generate for.
+pub async fn prepare_run(
+    pg_pool: &PgPool,
+    options: &GenerateOptions<'_>,
+) -> anyhow::Result<Option<PreparedRun>> {
+    let resumed = resume_due_users(pg_pool).await?;
+    if resumed != 0 {
+        tracing::info!("Resumed {resumed} paused users");
+    }
+
+    let mut users = match options.spotify_id {
+        Some(spotify_id) => sqlx::query_as!(
+            UserData,
+            r#"SELECT spotify_id, timezone, scopes FROM users WHERE spotify_id = $1 AND active = true"#,
+            spotify_id
+        )
+        .fetch_all(pg_pool)
+        .await,
+        None => sqlx::query_as!(
+            UserData,
+            r#"SELECT spotify_id, timezone, scopes FROM users WHERE active = true"#
+        )
+        .fetch_all(pg_pool)
+        .await,
+    }
+    .context("Failed to get users from database")?;
+
+    tracing::info!("Found {} users", users.len());
+
+    if options.due_only {
+        let mut due_users = Vec::with_capacity(users.len());
+        for user in users {
+            if is_due(pg_pool, &user, options.month).await? {
+                due_users.push(user);
+            }
+        }
+        users = due_users;
+        tracing::info!("{} users are due", users.len());
+    }
+
+    if users.is_empty() {
+        return Ok(None);
+    }
+
+    let run_id = start_run(pg_pool, users.len()).await?;
+    Ok(Some(PreparedRun {
+        run_id,
+        users,
+        force: options.force,
+        month: options.month,
+    }))
+}
+
+impl PreparedRun {
+    pub fn id(&self) -> i32 {
+        self.run_id
+    }
+
+    /// Generates for up to `config.concurrency` users at a time,
+    /// recording each user as soon as they are done so the progress can be followed.
+    pub async fn execute(
+        self,
+        pg_pool: &PgPool,
+        tokens: &TokenManager,
+        spotify_api: &dyn SpotifyApi,
+        config: &GeneratorConfig,
+    ) -> RunSummary {
+        let run_id = self.run_id;
+        let total = self.users.len();
+        let force = self.force;
+        let month = self.month;
+        let botm_generator = BotmGenerator::new(tokens, spotify_api, pg_pool);
+        // Owned users keep the closure free of higher ranked lifetimes, so the run can be spawned
+        let results: Vec<_> = futures_util::stream::iter(self.users)
+            .map(|user| {
+                generate_and_record(&botm_generator, pg_pool, config, run_id, user, force, month)
+            })
+            .buffer_unordered(config.concurrency.max(1))
+            .collect()
+            .await;
+
+        let mut counts: HashMap<RunStatus, usize> = HashMap::new();
+        for result in &results {
+            match result {
+                Ok(playlists) => {
+                    for playlist in playlists {
+                        *counts.entry(playlist.status).or_default() += 1;
+                    }
+                }
+                Err(_) => *counts.entry(RunStatus::Failed).or_default() += 1,
+            }
+        }
+
+        let count = |status| counts.get(&status).copied().unwrap_or_default();
+        let summary = RunSummary {
+            run_id,
+            total,
+            created: count(RunStatus::Created),
+            updated: count(RunStatus::Updated),
+            skipped: count(RunStatus::Skipped),
+            failed: count(RunStatus::Failed),
+        };
+        if let Err(err) = finish_run(
+            pg_pool,
+            run_id,
+            summary.total - summary.failed,
+            summary.failed,
+        )
+        .await
+        {
+            tracing::error!("{:#}", err);
+        }
+
+        if summary.failed != 0 {
+            tracing::error!(
+                "Failed to generate BOTM for {} of {} users in run {}",
+                summary.failed,
+                summary.total,
+                run_id
+            );
+        }
+
+        summary
+    }
+}
+
+/// Generates for a single user of the run and records the outcome,
+/// scheduling a retry if the user failed.
+async fn generate_and_record(
+    botm_generator: &BotmGenerator<'_>,
+    pg_pool: &PgPool,
+    config: &GeneratorConfig,
+    run_id: i32,
+    user: UserData,
+    force: bool,
+    month: Option<NaiveDate>,
+) -> anyhow::Result<Vec<GeneratedPlaylist>> {
+    let started = Instant::now();
+    let month = month.unwrap_or_else(|| target_month(&user.now()));
+    let result = match lock_user(pg_pool, &user.spotify_id, config.stale_after()).await {
+        Ok(locked_at) => {
+            let result = botm_generator.generate_for(&user, month, force).await;
+            if let Err(err) = unlock_user(pg_pool, &user.spotify_id, locked_at).await {
+                tracing::error!("{:#}", err);
+            }
+            result
+        }
+        Err(err) => Err(err),
+    };
+    if let Err(err) = &result {
+        tracing::error!("Failed to generate BOTM for {}", &user.spotify_id);
+        tracing::error!("{:#}", err);
+    }
+    if let Err(err) = record_user_run(
+        pg_pool,
+        run_id,
+        &user.spotify_id,
+        &result,
+        started.elapsed(),
+    )
+    .await
+    {
+        tracing::error!("{:#}", err);
+    }
+    if let Err(err) = record_retry(pg_pool, &config.retry, &user, month, &result).await {
+        tracing::error!("{:#}", err);
+    }
+    result
+}
+
+/// Waits until no other run generates for the user and locks them in `generation_locks`,
+/// so the scheduler and the job workers of all instances never create the same playlist twice.
+///
+/// Locks older than `stale_after` are taken over, their instance is assumed to have crashed.
+/// Returns when the lock was taken, which [`unlock_user`] needs to release it.
+async fn lock_user(
+    pg_pool: &PgPool,
+    spotify_id: &str,
+    stale_after: Duration,
+) -> anyhow::Result<DateTime<Utc>> {
+    loop {
+        let locked_at = sqlx::query_scalar!(
+            r#"INSERT INTO generation_locks (spotify_id) VALUES ($1)
+                ON CONFLICT (spotify_id) DO UPDATE SET locked_at = now()
+                WHERE generation_locks.locked_at < now() - make_interval(secs => $2)
+                RETURNING locked_at"#,
+            spotify_id,
+            stale_after.as_secs_f64()
+        )
+        .fetch_optional(pg_pool)
+        .await
+        .with_context(|| format!("Failed to lock user: {spotify_id}"))?;
+        if let Some(locked_at) = locked_at {
+            return Ok(locked_at);
+        }
+        debug!("Another run is generating for {spotify_id}, waiting");
+        tokio::time::sleep(USER_LOCK_POLL_INTERVAL).await;
+    }
+}
+
+/// Releases the lock taken at `locked_at`, unless another run took it over in the meantime
+/// because this one took longer than `stale_after`.
+async fn unlock_user(
+    pg_pool: &PgPool,
+    spotify_id: &str,
+    locked_at: DateTime<Utc>,
+) -> anyhow::Result<()> {
+    sqlx::query!(
+        "DELETE FROM generation_locks WHERE spotify_id = $1 AND locked_at = $2",
+        spotify_id,
+        locked_at
+    )
+    .execute(pg_pool)
+    .await
+    .with_context(|| format!("Failed to unlock user: {spotify_id}"))?;
+    Ok(())
+}
+
+/// Progress of a BOTM run, read from what was recorded so far.
+///
+/// `created`, `updated` and `skipped` count playlists, the others count users.
+#[derive(serde::Serialize, Debug)]
+pub struct RunProgress {
+    pub run_id: i32,
+    pub total: i64,
+    pub done: i64,
+    pub created: i64,
+    pub updated: i64,
+    pub skipped: i64,
+    pub failed: i64,
+    pub finished: bool,
+}
+
+/// Reads the progress of the run, `None` if there is no such run.
+pub async fn run_progress(pg_pool: &PgPool, run_id: i32) -> anyhow::Result<Option<RunProgress>> {
+    sqlx::query_as!(
+        RunProgress,
+        r#"SELECT r.id AS run_id, r.total_users::BIGINT AS "total!", COUNT(DISTINCT u.spotify_id) AS "done!",
+                COUNT(u.status) FILTER (WHERE u.status = 'created') AS "created!",
+                COUNT(u.status) FILTER (WHERE u.status = 'updated') AS "updated!",
+                COUNT(u.status) FILTER (WHERE u.status = 'skipped') AS "skipped!",
+                COUNT(u.status) FILTER (WHERE u.status = 'failed') AS "failed!",
+                r.finished_at IS NOT NULL AS "finished!"
+            FROM botm_runs r LEFT JOIN user_botm_runs u ON u.botm_run_id = r.id
+            WHERE r.id = $1
+            GROUP BY r.id"#,
+        run_id
+    )
+    .fetch_optional(pg_pool)
+    .await
+    .with_context(|| format!("Failed to get progress of botm run {run_id}"))
+}
+
+/// Checks if the month of the user, the last one that has ended if `month` is `None`,
+/// has ended in their time zone and no playlist was generated for it yet.
+///
+/// Users that failed for the month are only due again once their retry backoff has passed,
+/// and not at all once they need attention.
+/// Months that ended while the user was paused are never due.
+async fn is_due(
+    pg_pool: &PgPool,
+    user: &UserData,
+    month: Option<NaiveDate>,
+) -> anyhow::Result<bool> {
+    let now = user.now();
+    let month = month.unwrap_or_else(|| target_month(&now));
+    let month_end = start_of_day(month + Months::new(1), user.timezone());
+    let due = sqlx::query!(
+        r#"SELECT
+                EXISTS(SELECT 1 FROM botm_playlists WHERE spotify_id = $1 AND period = 'month' AND month = $2 AND NOT pending) AS "exists!",
+                (SELECT next_attempt_at <= now() AND NOT needs_attention FROM generation_retries WHERE spotify_id = $1 AND month = $2) AS retry_due,
+                EXISTS(SELECT 1 FROM generation_pauses WHERE spotify_id = $1 AND paused_at <= $3 AND (resumed_at IS NULL OR resumed_at > $3)) AS "paused!""#,
+        user.spotify_id,
+        month,
+        month_end
+    )
+    .fetch_one(pg_pool)
+    .await
+    .context("Failed to check for existing playlist")?;
+    if due.paused {
+        return Ok(false);
+    }
+    Ok(match due.retry_due {
+        Some(retry_due) => retry_due,
+        None => month_ended(month, &now) && !due.exists,
+    })
+}
+
+/// Keeps track of the failed attempts of the user for `month` in `generation_retries`,
+/// along with the period whose playlist failed,
+/// clearing them once the user succeeded.
+async fn record_retry(
+    pg_pool: &PgPool,
+    config: &GenerationRetryConfig,
+    user: &UserData,
+    month: NaiveDate,
+    result: &anyhow::Result<Vec<GeneratedPlaylist>>,
+) -> anyhow::Result<()> {
+    let err = match result {
+        // Deactivated users aren't generated for until they reconnect
+        Err(err) if err.is::<AuthorizationRevoked>() => return Ok(()),
+        Err(err) => err,
+        Ok(_) => {
+            sqlx::query!(
+                "DELETE FROM generation_retries WHERE spotify_id = $1 AND month = $2",
+                user.spotify_id,
+                month
+            )
+            .execute(pg_pool)
+            .await
+            .with_context(|| format!("Failed to clear retries of user: {}", user.spotify_id))?;
+            return Ok(());
+        }
+    };
+
+    let previous_attempts = sqlx::query_scalar!(
+        "SELECT attempts FROM generation_retries WHERE spotify_id = $1 AND month = $2",
+        user.spotify_id,
+        month
+    )
+    .fetch_optional(pg_pool)
+    .await
+    .with_context(|| format!("Failed to get retries of user: {}", user.spotify_id))?
+    .unwrap_or_default();
+    let attempts = previous_attempts + 1;
+    let needs_attention = attempts >= config.max_attempts;
+    let period = err
+        .downcast_ref::<PeriodFailed>()
+        .map_or(Period::Month, |failed| failed.0);
+    let next_attempt_at = chrono::Utc::now() + config.backoff(attempts);
+
+    sqlx::query!(
+        r#"INSERT INTO generation_retries (spotify_id, month, period, attempts, last_error, next_attempt_at, needs_attention)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)
+            ON CONFLICT (spotify_id, month) DO UPDATE
+            SET period = EXCLUDED.period, attempts = EXCLUDED.attempts, last_error = EXCLUDED.last_error,
+                next_attempt_at = EXCLUDED.next_attempt_at, needs_attention = EXCLUDED.needs_attention,
+                updated_at = now()"#,
+        user.spotify_id,
+        month,
+        period.as_str(),
+        attempts,
+        format!("{:#}", err),
+        next_attempt_at,
+        needs_attention
+    )
+    .execute(pg_pool)
+    .await
+    .with_context(|| format!("Failed to record retry of user: {}", user.spotify_id))?;
+
+    if needs_attention {
+        tracing::error!(
+            "Giving up on the BOTM of {} for {} after {} attempts, it needs attention",
+            user.spotify_id,
+            month,
+            attempts
+        );
+    } else {
+        tracing::warn!(
+            "Retrying the BOTM of {} for {} at {}",
+            user.spotify_id,
+            month,
+            next_attempt_at
+        );
+    }
+    Ok(())
+}
+
+/// Opens a new row in `botm_runs` and returns its id.
+async fn start_run(pg_pool: &PgPool, total_users: usize) -> anyhow::Result<i32> {
+    let run_id = sqlx::query_scalar!(
+        "INSERT INTO botm_runs (date, total_users) VALUES (CURRENT_DATE, $1) RETURNING id",
+        total_users as i32
+    )
+    .fetch_one(pg_pool)
+    .await
+    .context("Failed to insert botm run")?;
+    tracing::info!("Started BOTM run {run_id} for {total_users} users");
+    Ok(run_id)
+}
+
+/// Stores the outcome of generating the BOTMs for a single user in `user_botm_runs`,
+/// one row per playlist or a single row if the user failed.
+async fn record_user_run(
+    pg_pool: &PgPool,
+    run_id: i32,
+    spotify_id: &str,
+    result: &anyhow::Result<Vec<GeneratedPlaylist>>,
+    duration: std::time::Duration,
+) -> anyhow::Result<()> {
+    let playlists = match result {
+        Ok(playlists) => playlists,
+        Err(err) => {
+            sqlx::query!(
+                r#"INSERT INTO user_botm_runs (spotify_id, botm_run_id, status, error, duration_ms)
+                    VALUES ($1, $2, $3, $4, $5)"#,
+                spotify_id,
+                run_id,
+                RunStatus::Failed.as_str(),
+                format!("{:#}", err),
+                duration.as_millis() as i64,
+            )
+            .execute(pg_pool)
+            .await
+            .with_context(|| format!("Failed to record run {run_id} for user: {spotify_id}"))?;
+            return Ok(());
+        }
+    };
+    for playlist in playlists {
+        sqlx::query!(
+            r#"INSERT INTO user_botm_runs (spotify_id, botm_run_id, status, period, month, playlist_id, track_count, duration_ms)
+                VALUES ($1, $2, $3, $4, $5, $6, $7, $8)"#,
+            spotify_id,
+            run_id,
+            playlist.status.as_str(),
+            playlist.period.as_str(),
+            playlist.month,
+            playlist.playlist_id,
+            playlist.track_count,
+            duration.as_millis() as i64,
+        )
+        .execute(pg_pool)
+        .await
+        .with_context(|| format!("Failed to record run {run_id} for user: {spotify_id}"))?;
+    }
+    Ok(())
+}
+
+/// Closes the run with the final totals.
+async fn finish_run(
+    pg_pool: &PgPool,
+    run_id: i32,
+    succeeded: usize,
+    failed: usize,
+) -> anyhow::Result<()> {
+    sqlx::query!(
+        "UPDATE botm_runs SET finished_at = now(), succeeded = $1, failed = $2 WHERE id = $3",
+        succeeded as i32,
+        failed as i32,
+        run_id
+    )
+    .execute(pg_pool)
+    .await
+    .with_context(|| format!("Failed to finish botm run {run_id}"))?;
+    Ok(())
+}
+
+/// Status of a single user in a BOTM run, stored as text in `user_botm_runs.status`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum RunStatus {
+    Created,
+    /// An existing playlist for the month was regenerated in place.
+    Updated,
+    /// A playlist for the month already existed and was left alone.
+    Skipped,
+    Failed,
+}
+
+impl RunStatus {
+    fn as_str(&self) -> &'static str {
+        match self {
+            RunStatus::Created => "created",
+            RunStatus::Updated => "updated",
+            RunStatus::Skipped => "skipped",
+            RunStatus::Failed => "failed",
+        }
+    }
+}
+
+struct BotmGenerator<'a> {
+    tokens: &'a TokenManager,
+    spotify_api: &'a dyn SpotifyApi,
+    pg_pool: &'a PgPool,
+}
+
+impl<'a> BotmGenerator<'a> {
+    fn new(tokens: &'a TokenManager, spotify_api: &'a dyn SpotifyApi, pg_pool: &'a PgPool) -> Self {
+        Self {
+            tokens,
+            spotify_api,
+            pg_pool,
+        }
+    }
+
+    /// Generates the BOTM of `month` for a user,
+    /// and the quarterly and yearly playlists if the user wants them and the month ends one.
+    ///
+    /// Playlists that were already generated are skipped,
+    /// unless `force` is set, in which case the existing playlists are updated in place.
+    /// Pending playlists of an earlier attempt are always filled in place.
+    async fn generate_for(
+        &self,
+        user: &UserData,
+        month: NaiveDate,
+        force: bool,
+    ) -> anyhow::Result<Vec<GeneratedPlaylist>> {
+        anyhow::ensure!(
+            month_ended(month, &user.now()),
+            "The month {month} hasn't ended yet for user: {}",
+            user.spotify_id
+        );
+        let settings = UserSettings::load(self.pg_pool, &user.spotify_id).await?;
+
+        let mut periods = Vec::new();
+        for period in settings.periods() {
+            let Some(start) = period.ending_with(month) else {
+                continue;
+            };
+            let existing = sqlx::query_as!(
+                ExistingPlaylist,
+                "SELECT playlist_id, track_count, pending FROM botm_playlists WHERE spotify_id = $1 AND period = $2 AND month = $3",
+                user.spotify_id,
+                period.as_str(),
+                start
+            )
+            .fetch_optional(self.pg_pool)
+            .await
+            .context("Failed to look up existing playlist")?;
+            periods.push((period, start, existing));
+        }
+
+        let done = |existing: &Option<ExistingPlaylist>| {
+            existing.as_ref().is_some_and(|existing| !existing.pending)
+        };
+        if !force && periods.iter().all(|(_, _, existing)| done(existing)) {
+            debug!(
+                "BOTM for {} already exists for {}, skipping",
+                user.spotify_id, month
+            );
+            return Ok(periods
+                .into_iter()
+                .filter_map(|(period, start, existing)| {
+                    existing.map(|existing| existing.skipped(period, start))
+                })
+                .collect());
+        }
+
+        tracing::trace!("Getting access token for user: {}", user.spotify_id);
+        let access_token = self.tokens.access_token(&user.spotify_id).await?;
+        let access_token = access_token.expose_secret();
+
+        let mut playlists = Vec::with_capacity(periods.len());
+        for (period, start, existing) in periods {
+            let playlist = match existing {
+                Some(existing) if !force && !existing.pending => existing.skipped(period, start),
+                existing => self
+                    .generate_period(user, access_token, &settings, period, start, existing)
+                    .await
+                    .context(PeriodFailed(period))?,
+            };
+            playlists.push(playlist);
+        }
+        Ok(playlists)
+    }
+
+    /// Creates the playlist for `period` starting at `start`, or updates `existing` in place.
+    ///
+    /// A created playlist is stored as pending before it is filled,
+    /// so a failure after creating it doesn't leave a playlist behind that the retry doesn't know.
+    async fn generate_period(
+        &self,
+        user: &UserData,
+        access_token: &str,
+        settings: &UserSettings,
+        period: Period,
+        start: NaiveDate,
+        existing: Option<ExistingPlaylist>,
+    ) -> anyhow::Result<GeneratedPlaylist> {
+        // Get top tracks
+        let time_range = match period {
+            Period::Month => settings.time_range.as_str(),
+            Period::Quarter => "medium_term",
+            Period::Year => "long_term",
+        };
+        trace!(
+            "Getting {} {time_range} top tracks for user: {}",
+            settings.track_count,
+            user.spotify_id
+        );
+        let top_tracks = fetch_top_tracks(
+            self.spotify_api,
+            access_token,
+            time_range,
+            settings.track_count as usize,
+        )
+        .await?;
+
+        debug!(
+            "Got {} top tracks for {}",
+            top_tracks.len(),
+            user.spotify_id
+        );
+
+        let values = TemplateValues {
+            month: start,
+            track_count: top_tracks.len(),
+            generated: user.now().date_naive(),
+            locale: settings.locale(),
+        };
+        let (playlist_name, description) = match period {
+            Period::Month => (
+                render_template(&settings.name_template, &values)
+                    .context("Failed to render playlist name")?,
+                render_template(&settings.description_template, &values)
+                    .context("Failed to render playlist description")?,
+            ),
+            Period::Quarter => {
+                let quarter = start.month0() / 3 + 1;
+                (
+                    start.format(&format!("%Y Q{quarter} BOTM")).to_string(),
+                    start
+                        .format(&format!(
+                            "Bangers of the quarter Q{quarter} %Y, (generated on {})",
+                            values.generated.format("%F")
+                        ))
+                        .to_string(),
+                )
+            }
+            Period::Year => (
+                start.format("%Y BOTM").to_string(),
+                start
+                    .format(&format!(
+                        "Bangers of the year %Y, (generated on {})",
+                        values.generated.format("%F")
+                    ))
+                    .to_string(),
+            ),
+        };
+
+        let details = PlaylistDetails {
+            name: &playlist_name,
+            description: &description,
+            public: settings.public,
+            collaborative: settings.collaborative,
+        };
+        let uris: Vec<&str> = top_tracks.iter().map(|i| i.uri.as_str()).collect();
+
+        let (status, playlist_id) = match existing {
+            Some(existing) => {
+                debug!(
+                    "Updating playlist {} to \"{playlist_name}\" with description \"{description}\"",
+                    existing.playlist_id
+                );
+                self.spotify_api
+                    .update_playlist(access_token, &existing.playlist_id, &details)
+                    .await?;
+
+                self.replace_tracks(access_token, &existing.playlist_id, &uris)
+                    .await?;
+                // Finishing a pending playlist still creates it as far as the user is concerned
+                let status = if existing.pending {
+                    RunStatus::Created
+                } else {
+                    RunStatus::Updated
+                };
+                (status, existing.playlist_id)
+            }
+            None => {
+                debug!(
+                    "Generating playlist \"{playlist_name}\" with description \"{description}\""
+                );
+                let playlist_id = self
+                    .spotify_api
+                    .create_playlist(access_token, &user.spotify_id, &details)
+                    .await?;
+                sqlx::query!(
+                    r#"INSERT INTO botm_playlists (spotify_id, period, month, playlist_id, name, track_count, pending)
+                        VALUES ($1, $2, $3, $4, $5, 0, true)"#,
+                    user.spotify_id,
+                    period.as_str(),
+                    start,
+                    playlist_id,
+                    playlist_name,
+                )
+                .execute(self.pg_pool)
+                .await
+                .context("Failed to store created playlist")?;
+                self.add_tracks(access_token, &playlist_id, &uris, 0)
+                    .await?;
+                (RunStatus::Created, playlist_id)
+            }
+        };
+
+        // The cover is only decoration, so failing to upload it doesn't fail the playlist
+        if let Err(err) = self
+            .upload_cover(user, access_token, settings, period, start, &playlist_id)
+            .await
+        {
+            tracing::warn!(
+                "Failed to upload cover of {} for {}: {:#}",
+                playlist_id,
+                user.spotify_id,
+                err
+            );
+        }
+
+        let track_count = top_tracks.len() as i32;
+        sqlx::query!(
+            r#"INSERT INTO botm_playlists (spotify_id, period, month, playlist_id, name, track_count) VALUES ($1, $2, $3, $4, $5, $6)
+                ON CONFLICT (spotify_id, period, month) DO UPDATE SET playlist_id = $4, name = $5, track_count = $6, pending = false,
+                    updated_at = CASE WHEN botm_playlists.pending THEN botm_playlists.updated_at ELSE now() END"#,
+            user.spotify_id,
+            period.as_str(),
+            start,
+            playlist_id,
+            playlist_name,
+            track_count,
+        )
+        .execute(self.pg_pool)
+        .await
+        .context("Failed to store generated playlist")?;
+
+        Ok(GeneratedPlaylist {
+            status,
+            period,
+            month: start,
+            playlist_id,
+            track_count,
+        })
+    }
+
+    /// Renders the cover for the period and uploads it to the playlist.
+    ///
+    /// Does nothing if the user turned covers off or hasn't granted `ugc-image-upload` yet.
+    async fn upload_cover(
+        &self,
+        user: &UserData,
+        access_token: &str,
+        settings: &UserSettings,
+        period: Period,
+        start: NaiveDate,
+        playlist_id: &str,
+    ) -> anyhow::Result<()> {
+        let style = settings.cover_style();
+        if style == CoverStyle::None {
+            return Ok(());
+        }
+        if !user
+            .scopes
+            .split_whitespace()
+            .any(|s| s == "ugc-image-upload")
+        {
+            debug!(
+                "{} hasn't granted ugc-image-upload, keeping the default cover",
+                user.spotify_id
+            );
+            return Ok(());
+        }
+
+        let (title, subtitle) = match period {
+            Period::Month => (
+                start.format("%B").to_string(),
+                start.format("%Y").to_string(),
+            ),
+            Period::Quarter => (
+                format!("Q{}", start.month0() / 3 + 1),
+                start.format("%Y").to_string(),
+            ),
+            Period::Year => (start.format("%Y").to_string(), "Year".to_owned()),
+        };
+        let Some(jpeg) = render_cover(style, &title, &subtitle, month_hue(start))? else {
+            return Ok(());
+        };
+
+        self.spotify_api
+            .upload_cover(access_token, playlist_id, &jpeg)
+            .await
+    }
+
+    /// Adds `uris` to the playlist at `position`, split into as many requests as needed.
+    async fn add_tracks(
+        &self,
+        access_token: &str,
+        playlist_id: &str,
+        uris: &[&str],
+        position: usize,
+    ) -> anyhow::Result<()> {
+        for (i, chunk) in uris.chunks(MAX_TRACKS_PER_REQUEST).enumerate() {
+            self.spotify_api
+                .add_tracks(
+                    access_token,
+                    playlist_id,
+                    chunk,
+                    position + i * MAX_TRACKS_PER_REQUEST,
+                )
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Replaces all the tracks of the playlist with `uris`.
+    async fn replace_tracks(
+        &self,
+        access_token: &str,
+        playlist_id: &str,
+        uris: &[&str],
+    ) -> anyhow::Result<()> {
+        let (first, rest) = uris.split_at(uris.len().min(MAX_TRACKS_PER_REQUEST));
+        self.spotify_api
+            .replace_tracks(access_token, playlist_id, first)
+            .await?;
+        self.add_tracks(access_token, playlist_id, rest, first.len())
+            .await
+    }
+}
+
+/// Returns the first day of the month a BOTM generated at `now` is for,
+/// the last month that has ended in the time zone of `now`.
+///
+/// The current month is never generated, its top tracks are only final once it has ended.
+pub fn target_month<T: TimeZone>(now: &chrono::DateTime<T>) -> NaiveDate {
+    current_month(now) - Months::new(1)
+}
+
+/// Returns the first day of the month `now` is in, in the time zone of `now`.
+pub fn current_month<T: TimeZone>(now: &chrono::DateTime<T>) -> NaiveDate {
+    now.date_naive()
+        .with_day(1)
+        .expect("Every month has a first day")
+}
+
+/// Whether the month starting at `month` has ended at `now`, in the time zone of `now`.
+pub fn month_ended<T: TimeZone>(month: NaiveDate, now: &chrono::DateTime<T>) -> bool {
+    now.date_naive() >= month + Months::new(1)
+}
+
+/// Context of errors generating the playlist of a single period,
+/// errors without it happened before any playlist and concern the monthly BOTM as well.
+#[derive(Debug)]
+struct PeriodFailed(Period);
+
+impl fmt::Display for PeriodFailed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Failed to generate the {} playlist", self.0.as_str())
+    }
+}
+
+/// The time span a generated playlist covers, stored as text in `botm_playlists.period`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Period {
+    Month,
+    Quarter,
+    Year,
+}
+
+impl Period {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Period::Month => "month",
+            Period::Quarter => "quarter",
+            Period::Year => "year",
+        }
+    }
+
+    pub fn parse(period: &str) -> Option<Self> {
+        [Period::Month, Period::Quarter, Period::Year]
+            .into_iter()
+            .find(|p| p.as_str() == period)
+    }
+
+    /// Human readable name of the period starting at `start`, e.g. `September 2026` or `Q3 2026`.
+    pub fn label(&self, start: NaiveDate) -> String {
+        match self {
+            Period::Month => start.format("%B %Y").to_string(),
+            Period::Quarter => format!("Q{} {}", start.month0() / 3 + 1, start.year()),
+            Period::Year => start.year().to_string(),
+        }
+    }
+
+    /// Returns the first day of the period that ends with `month`,
+    /// or `None` if `month` isn't the last month of such a period.
+    pub fn ending_with(&self, month: NaiveDate) -> Option<NaiveDate> {
+        match self {
+            Period::Month => Some(month),
+            Period::Quarter if month.month().is_multiple_of(3) => {
+                month.with_month(month.month() - 2)
+            }
+            Period::Year if month.month() == 12 => month.with_month(1),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct ExistingPlaylist {
+    playlist_id: String,
+    track_count: i32,
+    /// Created by an earlier attempt that failed before filling it.
+    pending: bool,
+}
+
+impl ExistingPlaylist {
+    fn skipped(self, period: Period, month: NaiveDate) -> GeneratedPlaylist {
+        GeneratedPlaylist {
+            status: RunStatus::Skipped,
+            period,
+            month,
+            playlist_id: self.playlist_id,
+            track_count: self.track_count,
+        }
+    }
+}
+
+/// A playlist of a user after `BotmGenerator::generate_for`.
+#[derive(Debug)]
+struct GeneratedPlaylist {
+    status: RunStatus,
+    period: Period,
+    /// First day of the period.
+    month: NaiveDate,
+    playlist_id: String,
+    track_count: i32,
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+
+    use super::*;
+
+    fn date(year: i32, month: u32, day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(year, month, day).unwrap()
+    }
+
+    #[test]
+    fn target_month_is_the_last_month_that_has_ended() {
+        let now = Utc.with_ymd_and_hms(2026, 10, 17, 12, 0, 0).unwrap();
+        assert_eq!(target_month(&now), date(2026, 9, 1));
+
+        let now = Utc.with_ymd_and_hms(2026, 10, 1, 0, 0, 0).unwrap();
+        assert_eq!(target_month(&now), date(2026, 9, 1));
+
+        let now = Utc.with_ymd_and_hms(2026, 9, 30, 23, 59, 59).unwrap();
+        assert_eq!(target_month(&now), date(2026, 8, 1));
+    }
+
+    #[test]
+    fn target_month_is_in_the_time_zone_of_the_user() {
+        // Still October 31st in UTC, already the 1st in Kiritimati (UTC+14)
+        let now = Utc.with_ymd_and_hms(2026, 10, 31, 10, 0, 0).unwrap();
+        assert_eq!(target_month(&now), date(2026, 9, 1));
+        assert_eq!(
+            target_month(&now.with_timezone(&Tz::Pacific__Kiritimati)),
+            date(2026, 10, 1)
+        );
+
+        // Already November 1st in UTC, still October 31st in Honolulu (UTC-10)
+        let now = Utc.with_ymd_and_hms(2026, 11, 1, 5, 0, 0).unwrap();
+        assert_eq!(target_month(&now), date(2026, 10, 1));
+        assert_eq!(
+            target_month(&now.with_timezone(&Tz::Pacific__Honolulu)),
+            date(2026, 9, 1)
+        );
+    }
+
+    #[test]
+    fn current_month_is_in_the_time_zone_of_the_user() {
+        let now = Utc.with_ymd_and_hms(2026, 12, 31, 12, 0, 0).unwrap();
+
+        assert_eq!(current_month(&now), date(2026, 12, 1));
+        assert_eq!(
+            current_month(&now.with_timezone(&Tz::Pacific__Kiritimati)),
+            date(2027, 1, 1)
+        );
+    }
+
+    #[test]
+    fn target_month_rolls_over_the_year() {
+        let now = Utc.with_ymd_and_hms(2026, 12, 31, 12, 0, 0).unwrap();
+        assert_eq!(target_month(&now), date(2026, 11, 1));
+        assert_eq!(
+            target_month(&now.with_timezone(&Tz::Pacific__Kiritimati)),
+            date(2026, 12, 1)
+        );
+
+        let now = Utc.with_ymd_and_hms(2027, 1, 1, 0, 0, 0).unwrap();
+        assert_eq!(target_month(&now), date(2026, 12, 1));
+    }
+
+    #[test]
+    fn month_ends_at_midnight_of_the_user() {
+        let month = date(2026, 10, 1);
+        let now = Utc.with_ymd_and_hms(2026, 10, 31, 10, 0, 0).unwrap();
+
+        assert!(!month_ended(month, &now));
+        assert!(month_ended(
+            month,
+            &now.with_timezone(&Tz::Pacific__Kiritimati)
+        ));
+        assert!(!month_ended(
+            month,
+            &now.with_timezone(&Tz::Pacific__Honolulu)
+        ));
+        assert!(month_ended(month, &(now + chrono::Duration::days(1))));
+    }
+
+    #[test]
+    fn periods_end_with_their_last_month() {
+        let cases = [
+            (Period::Month, date(2026, 1, 1), Some(date(2026, 1, 1))),
+            (Period::Quarter, date(2026, 3, 1), Some(date(2026, 1, 1))),
+            (Period::Quarter, date(2026, 9, 1), Some(date(2026, 7, 1))),
+            (Period::Quarter, date(2026, 10, 1), None),
+            (Period::Year, date(2026, 11, 1), None),
+        ];
+        for (period, month, start) in cases {
+            assert_eq!(period.ending_with(month), start, "{period:?} {month}");
+        }
+    }
+
+    #[test]
+    fn december_ends_the_last_quarter_and_the_year() {
+        let december = date(2026, 12, 1);
+
+        assert_eq!(
+            Period::Quarter.ending_with(december),
+            Some(date(2026, 10, 1))
+        );
+        assert_eq!(Period::Year.ending_with(december), Some(date(2026, 1, 1)));
+        // January starts a new year, it doesn't end one
+        assert_eq!(Period::Quarter.ending_with(date(2027, 1, 1)), None);
+        assert_eq!(Period::Year.ending_with(date(2027, 1, 1)), None);
+    }
+
+    #[test]
+    fn period_labels() {
+        assert_eq!(Period::Month.label(date(2026, 9, 1)), "September 2026");
+        assert_eq!(Period::Quarter.label(date(2026, 10, 1)), "Q4 2026");
+        assert_eq!(Period::Year.label(date(2026, 1, 1)), "2026");
+    }
+}
@@ -0,0 +1,129 @@
+use anyhow::Context;
+use chrono::{DateTime, Months, NaiveDate, Utc};
+use chrono_tz::Tz;
+use sqlx::PgPool;
+
+use crate::{target_month, user_timezone, Period};
+
+/// The playlists generated for a user and the months that failed, for the dashboard.
+pub struct History {
+    /// Time zone of the user, UTC if none or an unknown one is stored.
+    pub timezone: Tz,
+    /// Newest first.
+    pub entries: Vec<HistoryEntry>,
+}
+
+pub struct HistoryEntry {
+    pub period: Period,
+    /// First day of the period.
+    pub month: NaiveDate,
+    pub status: HistoryStatus,
+}
+
+impl HistoryEntry {
+    /// First day of the last month of the period.
+    fn last_month(&self) -> NaiveDate {
+        let months = match self.period {
+            Period::Month => 0,
+            Period::Quarter => 2,
+            Period::Year => 11,
+        };
+        self.month + Months::new(months)
+    }
+}
+
+pub enum HistoryStatus {
+    /// The playlist was generated, `updated` if it was regenerated later.
+    Generated {
+        playlist_id: String,
+        name: String,
+        track_count: i32,
+        created_at: DateTime<Utc>,
+        updated: bool,
+    },
+    /// Generating failed and is retried by a later run.
+    Retrying {
+        attempts: i32,
+        next_attempt_at: DateTime<Utc>,
+    },
+    /// Generating failed too often and isn't retried anymore.
+    NeedsAttention { attempts: i32 },
+}
+
+impl History {
+    /// Loads the playlists from `botm_playlists` and failed playlists from `generation_retries`.
+    pub async fn load(pg_pool: &PgPool, spotify_id: &str) -> anyhow::Result<Self> {
+        let timezone = user_timezone(pg_pool, spotify_id).await?;
+
+        let playlists = sqlx::query!(
+            r#"SELECT period, month, playlist_id, name, track_count, created_at, updated_at
+                FROM botm_playlists WHERE spotify_id = $1 AND NOT pending"#,
+            spotify_id
+        )
+        .fetch_all(pg_pool)
+        .await
+        .with_context(|| format!("Failed to get playlists of user: {spotify_id}"))?;
+        let mut entries: Vec<_> = playlists
+            .into_iter()
+            .filter_map(|playlist| {
+                Some(HistoryEntry {
+                    period: Period::parse(&playlist.period)?,
+                    month: playlist.month,
+                    status: HistoryStatus::Generated {
+                        playlist_id: playlist.playlist_id,
+                        name: playlist.name,
+                        track_count: playlist.track_count,
+                        created_at: playlist.created_at,
+                        updated: playlist.updated_at > playlist.created_at,
+                    },
+                })
+            })
+            .collect();
+
+        // Pending playlists failed before they were filled, their retry shows up instead.
+        // Retries are dropped once the run for their month succeeds,
+        // the monthly BOTM may exist already if only the quarterly or yearly playlist failed
+        let retries = sqlx::query!(
+            r#"SELECT month, period, attempts, next_attempt_at, needs_attention FROM generation_retries
+                WHERE spotify_id = $1"#,
+            spotify_id
+        )
+        .fetch_all(pg_pool)
+        .await
+        .with_context(|| format!("Failed to get failed months of user: {spotify_id}"))?;
+        entries.extend(retries.into_iter().filter_map(|retry| {
+            let period = Period::parse(&retry.period)?;
+            Some(HistoryEntry {
+                period,
+                month: period.ending_with(retry.month)?,
+                status: if retry.needs_attention {
+                    HistoryStatus::NeedsAttention {
+                        attempts: retry.attempts,
+                    }
+                } else {
+                    HistoryStatus::Retrying {
+                        attempts: retry.attempts,
+                        next_attempt_at: retry.next_attempt_at,
+                    }
+                },
+            })
+        }));
+
+        // Newest first, quarters and years next to the monthly BOTM they are generated with
+        entries.sort_by_key(|entry| std::cmp::Reverse((entry.last_month(), entry.period as u8)));
+        Ok(Self { timezone, entries })
+    }
+
+    /// The last month that has ended in the time zone of the user, see [`target_month`].
+    pub fn last_month(&self) -> NaiveDate {
+        target_month(&Utc::now().with_timezone(&self.timezone))
+    }
+
+    /// What happened to the BOTM of [`History::last_month`], `None` if nothing was tried yet.
+    pub fn last_month_entry(&self) -> Option<&HistoryEntry> {
+        let last_month = self.last_month();
+        self.entries
+            .iter()
+            .find(|entry| entry.period == Period::Month && entry.month == last_month)
+    }
+}
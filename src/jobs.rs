@@ -0,0 +1,347 @@
+use std::{convert::Infallible, sync::Arc, time::Duration};
+
+use anyhow::Context;
+use chrono::{DateTime, NaiveDate, Utc};
+use sqlx::PgPool;
+use tokio::{sync::Notify, task::JoinHandle};
+
+use crate::{
+    prepare_run, run_progress, GenerateOptions, GeneratorConfig, RunProgress, SpotifyApi,
+    TokenManager,
+};
+
+/// State of a generation job, stored as text in `generation_jobs.state`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum JobState {
+    Queued,
+    Running,
+    /// The run went through, single users may still have failed.
+    Finished,
+    /// The run couldn't be started or recorded at all.
+    Failed,
+}
+
+impl JobState {
+    fn as_str(&self) -> &'static str {
+        match self {
+            JobState::Queued => "queued",
+            JobState::Running => "running",
+            JobState::Finished => "finished",
+            JobState::Failed => "failed",
+        }
+    }
+}
+
+/// Generation jobs waiting in `generation_jobs` for a [`JobWorker`].
+#[derive(Clone)]
+pub struct JobQueue {
+    pg_pool: PgPool,
+    /// Wakes the worker of this instance up, workers of other instances poll.
+    wake: Arc<Notify>,
+}
+
+impl JobQueue {
+    pub fn new(pg_pool: PgPool) -> Self {
+        Self {
+            pg_pool,
+            wake: Arc::new(Notify::new()),
+        }
+    }
+
+    /// Queues a BOTM run for the users selected by `options` and returns the id of the job.
+    pub async fn enqueue(&self, options: &GenerateOptions<'_>) -> anyhow::Result<i32> {
+        let job_id = sqlx::query_scalar!(
+            "INSERT INTO generation_jobs (spotify_id, force, due_only, month) VALUES ($1, $2, $3, $4) RETURNING id",
+            options.spotify_id,
+            options.force,
+            options.due_only,
+            options.month
+        )
+        .fetch_one(&self.pg_pool)
+        .await
+        .context("Failed to insert generation job")?;
+        tracing::info!("Queued generation job {job_id}");
+        self.wake.notify_one();
+        Ok(job_id)
+    }
+
+    /// Reads the state of the job and the progress of its run, `None` if there is no such job.
+    pub async fn status(&self, job_id: i32) -> anyhow::Result<Option<JobStatus>> {
+        let Some(job) = sqlx::query!(
+            r#"SELECT id, state, spotify_id, force, due_only, month, botm_run_id, error, created_at, started_at, finished_at
+                FROM generation_jobs WHERE id = $1"#,
+            job_id
+        )
+        .fetch_optional(&self.pg_pool)
+        .await
+        .with_context(|| format!("Failed to get generation job {job_id}"))?
+        else {
+            return Ok(None);
+        };
+
+        let (progress, users) = match job.botm_run_id {
+            Some(run_id) => (
+                run_progress(&self.pg_pool, run_id).await?,
+                user_progress(&self.pg_pool, run_id).await?,
+            ),
+            None => (None, Vec::new()),
+        };
+        let duration_ms = job.started_at.map(|started_at| {
+            (job.finished_at.unwrap_or_else(Utc::now) - started_at).num_milliseconds()
+        });
+
+        Ok(Some(JobStatus {
+            job_id: job.id,
+            state: job.state,
+            spotify_id: job.spotify_id,
+            force: job.force,
+            due_only: job.due_only,
+            month: job.month,
+            error: job.error,
+            created_at: job.created_at,
+            started_at: job.started_at,
+            finished_at: job.finished_at,
+            duration_ms,
+            progress,
+            users,
+        }))
+    }
+
+    /// Claims the oldest queued job, jobs claimed by other workers are skipped.
+    ///
+    /// Running jobs without a heartbeat for `stale_after` are queued again first,
+    /// the worker running them is assumed to have crashed.
+    async fn claim(&self, stale_after: Duration) -> anyhow::Result<Option<ClaimedJob>> {
+        let requeued = sqlx::query_scalar!(
+            r#"UPDATE generation_jobs SET state = $1, heartbeat_at = NULL
+                WHERE state = $2 AND heartbeat_at < now() - make_interval(secs => $3)
+                RETURNING id"#,
+            JobState::Queued.as_str(),
+            JobState::Running.as_str(),
+            stale_after.as_secs_f64()
+        )
+        .fetch_all(&self.pg_pool)
+        .await
+        .context("Failed to requeue stale generation jobs")?;
+        for job_id in requeued {
+            tracing::warn!("Requeued generation job {job_id}, its worker stopped responding");
+        }
+
+        sqlx::query_as!(
+            ClaimedJob,
+            r#"UPDATE generation_jobs SET state = $1, started_at = now(), heartbeat_at = now()
+                WHERE id = (
+                    SELECT id FROM generation_jobs WHERE state = $2
+                    ORDER BY id LIMIT 1 FOR UPDATE SKIP LOCKED
+                )
+                RETURNING id, spotify_id, force, due_only, month"#,
+            JobState::Running.as_str(),
+            JobState::Queued.as_str()
+        )
+        .fetch_optional(&self.pg_pool)
+        .await
+        .context("Failed to claim generation job")
+    }
+
+    /// Keeps the heartbeat of the running job up to date, until the future is dropped.
+    async fn keep_alive(&self, job_id: i32, interval: Duration) -> Infallible {
+        loop {
+            tokio::time::sleep(interval).await;
+            if let Err(err) = sqlx::query!(
+                "UPDATE generation_jobs SET heartbeat_at = now() WHERE id = $1",
+                job_id
+            )
+            .execute(&self.pg_pool)
+            .await
+            {
+                tracing::error!("Failed to update heartbeat of generation job {job_id}: {err}");
+            }
+        }
+    }
+
+    async fn set_run(&self, job_id: i32, run_id: i32) -> anyhow::Result<()> {
+        sqlx::query!(
+            "UPDATE generation_jobs SET botm_run_id = $1 WHERE id = $2",
+            run_id,
+            job_id
+        )
+        .execute(&self.pg_pool)
+        .await
+        .with_context(|| format!("Failed to set run of generation job {job_id}"))?;
+        Ok(())
+    }
+
+    async fn finish(
+        &self,
+        job_id: i32,
+        state: JobState,
+        error: Option<String>,
+    ) -> anyhow::Result<()> {
+        sqlx::query!(
+            "UPDATE generation_jobs SET state = $1, error = $2, finished_at = now() WHERE id = $3",
+            state.as_str(),
+            error,
+            job_id
+        )
+        .execute(&self.pg_pool)
+        .await
+        .with_context(|| format!("Failed to finish generation job {job_id}"))?;
+        Ok(())
+    }
+}
+
+/// State of a generation job as returned by `GET /generate/{job_id}`.
+#[derive(serde::Serialize, Debug)]
+pub struct JobStatus {
+    pub job_id: i32,
+    /// `queued`, `running`, `finished` or `failed`.
+    pub state: String,
+    pub spotify_id: Option<String>,
+    pub force: bool,
+    pub due_only: bool,
+    /// The month generated for, `None` for the last one that has ended for each user.
+    pub month: Option<NaiveDate>,
+    /// Why the whole job failed, failures of single users are in `users`.
+    pub error: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub started_at: Option<DateTime<Utc>>,
+    pub finished_at: Option<DateTime<Utc>>,
+    /// Time spent running so far, or in total once finished.
+    pub duration_ms: Option<i64>,
+    /// `None` until the job started a run, jobs without any users never do.
+    pub progress: Option<RunProgress>,
+    /// Outcome of every user done so far, one entry per playlist.
+    pub users: Vec<UserProgress>,
+}
+
+/// Outcome for a single user of a run, read from `user_botm_runs`.
+#[derive(serde::Serialize, Debug)]
+pub struct UserProgress {
+    pub spotify_id: String,
+    pub status: String,
+    pub period: Option<String>,
+    pub month: Option<NaiveDate>,
+    pub playlist_id: Option<String>,
+    pub track_count: Option<i32>,
+    pub error: Option<String>,
+    pub duration_ms: i64,
+    pub finished_at: DateTime<Utc>,
+}
+
+async fn user_progress(pg_pool: &PgPool, run_id: i32) -> anyhow::Result<Vec<UserProgress>> {
+    sqlx::query_as!(
+        UserProgress,
+        r#"SELECT spotify_id, status, period, month, playlist_id, track_count, error, duration_ms, finished_at
+            FROM user_botm_runs WHERE botm_run_id = $1 ORDER BY finished_at, spotify_id"#,
+        run_id
+    )
+    .fetch_all(pg_pool)
+    .await
+    .with_context(|| format!("Failed to get users of botm run {run_id}"))
+}
+
+struct ClaimedJob {
+    id: i32,
+    spotify_id: Option<String>,
+    force: bool,
+    due_only: bool,
+    month: Option<NaiveDate>,
+}
+
+/// Works through the [`JobQueue`] in the background, one job at a time.
+pub struct JobWorker {
+    queue: JobQueue,
+    tokens: TokenManager,
+    spotify_api: Arc<dyn SpotifyApi>,
+    generator_config: GeneratorConfig,
+}
+
+impl JobWorker {
+    pub fn new(
+        queue: JobQueue,
+        tokens: TokenManager,
+        spotify_api: Arc<dyn SpotifyApi>,
+        generator_config: GeneratorConfig,
+    ) -> Self {
+        Self {
+            queue,
+            tokens,
+            spotify_api,
+            generator_config,
+        }
+    }
+
+    /// Runs the worker in the background until the runtime shuts down.
+    pub fn spawn(self) -> JoinHandle<()> {
+        tokio::spawn(async move { self.run().await })
+    }
+
+    async fn run(self) {
+        let poll_interval = Duration::from_millis(self.generator_config.poll_interval_ms);
+        loop {
+            match self.process_next().await {
+                Ok(true) => continue,
+                Ok(false) => {}
+                Err(err) => tracing::error!("Generation job failed: {:#}", err),
+            }
+            tokio::select! {
+                _ = self.queue.wake.notified() => {}
+                _ = tokio::time::sleep(poll_interval) => {}
+            }
+        }
+    }
+
+    /// Processes the next queued job, returns if there was one.
+    async fn process_next(&self) -> anyhow::Result<bool> {
+        let stale_after = self.generator_config.stale_after();
+        let Some(job) = self.queue.claim(stale_after).await? else {
+            return Ok(false);
+        };
+        tracing::info!("Processing generation job {}", job.id);
+
+        let result = tokio::select! {
+            result = self.execute(&job) => result,
+            never = self.queue.keep_alive(job.id, stale_after / 3) => match never {},
+        };
+        let (state, error) = match &result {
+            Ok(()) => (JobState::Finished, None),
+            Err(err) => (JobState::Failed, Some(format!("{:#}", err))),
+        };
+        self.queue.finish(job.id, state, error).await?;
+        result.map(|()| true)
+    }
+
+    async fn execute(&self, job: &ClaimedJob) -> anyhow::Result<()> {
+        let pg_pool = &self.queue.pg_pool;
+        let options = GenerateOptions {
+            spotify_id: job.spotify_id.as_deref(),
+            force: job.force,
+            due_only: job.due_only,
+            month: job.month,
+        };
+        let Some(run) = prepare_run(pg_pool, &options).await? else {
+            tracing::info!("Generation job {} has no users to generate for", job.id);
+            return Ok(());
+        };
+        self.queue.set_run(job.id, run.id()).await?;
+
+        let summary = run
+            .execute(
+                pg_pool,
+                &self.tokens,
+                self.spotify_api.as_ref(),
+                &self.generator_config,
+            )
+            .await;
+        tracing::info!(
+            "Generation job {} done with BOTM run {}: {} created, {} updated, {} skipped, {} failed",
+            job.id,
+            summary.run_id,
+            summary.created,
+            summary.updated,
+            summary.skipped,
+            summary.failed
+        );
+        Ok(())
+    }
+}
@@ -1,10 +1,33 @@
 use actix_session::Session;
 
-use actix_web::{http::header, HttpResponse};
+use actix_web::{http::header, web, HttpResponse};
+use actix_web_flash_messages::FlashMessage;
+use sqlx::PgPool;
 
 pub mod configuration;
 pub use configuration::*;
 
+pub mod cover;
+pub use cover::*;
+
+pub mod csrf;
+pub use csrf::*;
+
+pub mod export;
+pub use export::*;
+
+pub mod generator;
+pub use generator::*;
+
+pub mod history;
+pub use history::*;
+
+pub mod jobs;
+pub use jobs::*;
+
+pub mod pause;
+pub use pause::*;
+
 pub mod routes;
 pub use routes::*;
 
@@ -14,6 +37,18 @@ pub use spotify::*;
 pub mod telementery;
 pub use telementery::*;
 
+pub mod timezone;
+pub use timezone::*;
+
+pub mod scheduler;
+pub use scheduler::*;
+
+pub mod session_store;
+pub use session_store::*;
+
+pub mod settings;
+pub use settings::*;
+
 pub mod startup;
 pub use startup::*;
 
@@ -23,3 +58,20 @@ async fn logout(session: Session) -> HttpResponse {
         .append_header((header::LOCATION, "/"))
         .finish()
 }
+
+/// Logs the user out of every browser, not just this one.
+async fn logout_all(session: Session, pg_pool: web::Data<PgPool>) -> HttpResponse {
+    if let Ok(Some(spotify_id)) = session.get::<String>("login") {
+        if let Err(err) = delete_user_sessions(pg_pool.as_ref(), &spotify_id).await {
+            tracing::error!("{:#}", err);
+            FlashMessage::error("Failed to log out of all devices.").send();
+            return HttpResponse::Found()
+                .append_header((header::LOCATION, "/settings"))
+                .finish();
+        }
+    }
+    session.purge();
+    HttpResponse::Found()
+        .append_header((header::LOCATION, "/"))
+        .finish()
+}
@@ -1,4 +1,8 @@
-use botm_web::{Botm, Configuration};
+use std::env;
+
+use anyhow::bail;
+use botm_web::{connect_database, reencrypt_tokens, Botm, Configuration, TokenCipher};
+use tracing::log::info;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
@@ -8,6 +12,16 @@ async fn main() -> anyhow::Result<()> {
 
     let configuration = Configuration::new().expect("Failed to load configuration");
 
+    match env::args().nth(1).as_deref() {
+        None => serve(configuration).await,
+        Some("reencrypt-tokens") => reencrypt(configuration).await,
+        Some(command) => bail!(
+            "Unknown command {command}, run without a command to start the server or with `reencrypt-tokens`"
+        ),
+    }
+}
+
+async fn serve(configuration: Configuration) -> anyhow::Result<()> {
     let botm = Botm::build(configuration)
         .await
         .expect("Failed to build Botm app");
@@ -16,3 +30,16 @@ async fn main() -> anyhow::Result<()> {
 
     Ok(())
 }
+
+/// Encrypts the tokens of all users with `token_encryption.current_key_id`,
+/// after which the previous keys can be removed from the configuration.
+async fn reencrypt(configuration: Configuration) -> anyhow::Result<()> {
+    let pg_pool = connect_database(configuration.database_url).await?;
+    let cipher = TokenCipher::from_config(&configuration.token_encryption)?;
+    let reencrypted = reencrypt_tokens(&pg_pool, &cipher).await?;
+    info!(
+        "Re-encrypted the tokens of {reencrypted} users with key {}",
+        cipher.key_id()
+    );
+    Ok(())
+}
@@ -0,0 +1,113 @@
+use anyhow::Context;
+use chrono::{DateTime, NaiveDate, Utc};
+use sqlx::PgPool;
+
+use crate::{parse_timezone, start_of_day};
+
+/// `users.deactivated_reason` of users who paused generation themselves.
+pub const PAUSED_REASON: &str = "paused";
+
+/// Generation of a user is paused, the user stays connected to Spotify.
+pub struct Paused {
+    /// Day generation is resumed on, `None` until the user resumes it.
+    pub resume_on: Option<NaiveDate>,
+}
+
+impl Paused {
+    /// `None` if the user didn't pause generation.
+    pub async fn load(pg_pool: &PgPool, spotify_id: &str) -> anyhow::Result<Option<Self>> {
+        let paused = sqlx::query!(
+            "SELECT resume_on FROM users WHERE spotify_id = $1 AND deactivated_reason = $2",
+            spotify_id,
+            PAUSED_REASON
+        )
+        .fetch_optional(pg_pool)
+        .await
+        .with_context(|| format!("Failed to get pause of user: {spotify_id}"))?;
+        Ok(paused.map(|paused| Self {
+            resume_on: paused.resume_on,
+        }))
+    }
+}
+
+/// Stops generating for the user until they resume or `resume_on`,
+/// also changing `resume_on` if the user already paused.
+///
+/// The pause is recorded in `generation_pauses`, so the months that end while the user is paused
+/// aren't generated once they resume.
+/// Users deactivated for another reason are left alone.
+pub async fn pause_user(
+    pg_pool: &PgPool,
+    spotify_id: &str,
+    resume_on: Option<NaiveDate>,
+) -> anyhow::Result<()> {
+    sqlx::query!(
+        r#"WITH paused AS (
+                UPDATE users SET active = false, deactivated_reason = $1, deactivated_at = COALESCE(deactivated_at, now()), resume_on = $2
+                    WHERE spotify_id = $3 AND (active OR deactivated_reason = $1)
+                    RETURNING spotify_id, deactivated_at
+            )
+            INSERT INTO generation_pauses (spotify_id, paused_at) SELECT spotify_id, deactivated_at FROM paused
+                ON CONFLICT DO NOTHING"#,
+        PAUSED_REASON,
+        resume_on,
+        spotify_id
+    )
+    .execute(pg_pool)
+    .await
+    .with_context(|| format!("Failed to pause user: {spotify_id}"))?;
+    Ok(())
+}
+
+/// Generates for the user again if they paused, ending their pause at `resumed_at`.
+pub async fn resume_user(
+    pg_pool: &PgPool,
+    spotify_id: &str,
+    resumed_at: DateTime<Utc>,
+) -> anyhow::Result<()> {
+    sqlx::query!(
+        r#"WITH resumed AS (
+                UPDATE users SET active = true, deactivated_reason = NULL, deactivated_at = NULL, resume_on = NULL
+                    WHERE spotify_id = $1 AND deactivated_reason = $2
+                    RETURNING spotify_id
+            )
+            UPDATE generation_pauses SET resumed_at = $3
+                WHERE spotify_id IN (SELECT spotify_id FROM resumed) AND resumed_at IS NULL"#,
+        spotify_id,
+        PAUSED_REASON,
+        resumed_at
+    )
+    .execute(pg_pool)
+    .await
+    .with_context(|| format!("Failed to resume user: {spotify_id}"))?;
+    Ok(())
+}
+
+/// Resumes the paused users whose `resume_on` has come in their time zone,
+/// returns how many there were.
+///
+/// Their pause ends when `resume_on` started, however late the run is,
+/// so the month that ended that midnight is generated.
+pub async fn resume_due_users(pg_pool: &PgPool) -> anyhow::Result<usize> {
+    let paused = sqlx::query!(
+        r#"SELECT spotify_id, timezone, resume_on AS "resume_on!" FROM users
+            WHERE deactivated_reason = $1 AND resume_on IS NOT NULL"#,
+        PAUSED_REASON
+    )
+    .fetch_all(pg_pool)
+    .await
+    .context("Failed to get paused users")?;
+
+    let mut resumed = 0;
+    for user in paused {
+        let timezone = parse_timezone(user.timezone.as_deref());
+        let today = chrono::Utc::now().with_timezone(&timezone).date_naive();
+        if user.resume_on <= today {
+            tracing::info!("Resuming {} as of {}", user.spotify_id, user.resume_on);
+            let resumed_at = start_of_day(user.resume_on, timezone);
+            resume_user(pg_pool, &user.spotify_id, resumed_at).await?;
+            resumed += 1;
+        }
+    }
+    Ok(resumed)
+}
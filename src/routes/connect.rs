@@ -1,21 +1,65 @@
 use actix_session::Session;
 use actix_web::{http::header, web, HttpResponse, Responder};
-use oauth2::{basic::BasicClient, CsrfToken, Scope};
+use chrono_tz::Tz;
+use oauth2::{basic::BasicClient, CsrfToken, PkceCodeChallenge, Scope};
+use sqlx::PgPool;
+
+use crate::UserSettings;
 
 pub const STATE_COOKIE: &str = "spotify_auth_state";
+/// The PKCE code verifier, sent with the authorization code to prove it was requested by this session.
+pub const PKCE_VERIFIER_COOKIE: &str = "spotify_pkce_verifier";
+pub const TIMEZONE_COOKIE: &str = "timezone";
+/// The space separated scopes asked for, granted as asked if the token response doesn't list any.
+pub const SCOPES_COOKIE: &str = "spotify_scopes";
+
+#[derive(serde::Deserialize, Debug)]
+pub struct ConnectParams {
+    /// Time zone of the browser, stored for the user once connected.
+    timezone: Option<String>,
+}
+
+pub async fn get_connect(
+    session: Session,
+    oauth: web::Data<BasicClient>,
+    pg_pool: web::Data<PgPool>,
+    params: web::Query<ConnectParams>,
+) -> impl Responder {
+    // Only ask for what the settings of the user need, new users get the defaults
+    let settings = match session.get::<String>("login") {
+        Ok(Some(spotify_id)) => UserSettings::load(pg_pool.as_ref(), &spotify_id)
+            .await
+            .unwrap_or_default(),
+        _ => UserSettings::default(),
+    };
 
-pub async fn get_connect(session: Session, oauth: web::Data<BasicClient>) -> impl Responder {
+    let scopes = settings.required_scopes();
+    let (pkce_challenge, pkce_verifier) = PkceCodeChallenge::new_random_sha256();
     let (auth_url, csrf_token) = oauth
         .authorize_url(CsrfToken::new_random)
-        .add_scope(Scope::new("playlist-modify-private".to_string()))
-        .add_scope(Scope::new("playlist-modify-public".to_string()))
-        .add_scope(Scope::new("user-top-read".to_string()))
-        .add_scope(Scope::new("user-read-private".to_string()))
+        .set_pkce_challenge(pkce_challenge)
+        .add_scopes(scopes.iter().map(|scope| Scope::new((*scope).to_owned())))
         .url();
 
     session
         .insert(STATE_COOKIE, csrf_token)
         .expect("Save state cookie");
+    session
+        .insert(PKCE_VERIFIER_COOKIE, pkce_verifier)
+        .expect("Save PKCE verifier cookie");
+    session
+        .insert(SCOPES_COOKIE, scopes.join(" "))
+        .expect("Save scopes cookie");
+
+    if let Some(timezone) = params
+        .timezone
+        .as_deref()
+        .filter(|tz| tz.parse::<Tz>().is_ok())
+    {
+        session
+            .insert(TIMEZONE_COOKIE, timezone)
+            .expect("Save timezone cookie");
+    }
 
     tracing::debug!("Sending user to {auth_url}");
 
@@ -1,23 +1,64 @@
 use actix_session::Session;
 use actix_web::{http::header, web, HttpResponse};
+use anyhow::Context;
+use askama_actix::{Template, TemplateToResponse};
 use sqlx::PgPool;
 
-pub async fn disconnect(session: Session, pg_pool: web::Data<PgPool>) -> HttpResponse {
-    let Ok(user) = session.get::<String>("login") else {
+use crate::csrf_token;
+
+#[derive(Template)]
+#[template(path = "disconnect.html")]
+struct DisconnectTemplate {
+    csrf_token: String,
+}
+
+/// Asks the user to confirm, explaining what disconnecting deletes.
+pub async fn get_disconnect(session: Session) -> HttpResponse {
+    let Ok(Some(_)) = session.get::<String>("login") else {
         return HttpResponse::Found()
             .append_header((header::LOCATION, "/"))
             .finish();
     };
+    let Ok(csrf_token) = csrf_token(&session) else {
+        return HttpResponse::InternalServerError().finish();
+    };
+
+    DisconnectTemplate { csrf_token }.to_response()
+}
 
-    let res = sqlx::query!("DELETE FROM users WHERE spotify_id = $1", user)
-        .execute(pg_pool.as_ref())
-        .await;
+pub async fn post_disconnect(session: Session, pg_pool: web::Data<PgPool>) -> HttpResponse {
+    let Ok(Some(user)) = session.get::<String>("login") else {
+        return HttpResponse::Found()
+            .append_header((header::LOCATION, "/"))
+            .finish();
+    };
 
-    if res.is_ok() {
-        session.purge();
+    match delete_user(pg_pool.as_ref(), &user).await {
+        Ok(()) => session.purge(),
+        Err(err) => tracing::error!("{:#}", err),
     }
 
     HttpResponse::Found()
         .append_header((header::LOCATION, "/"))
         .finish()
 }
+
+/// Deletes the user and their generation jobs, keeping only the record of their data downloads.
+async fn delete_user(pg_pool: &PgPool, spotify_id: &str) -> anyhow::Result<()> {
+    let mut transaction = pg_pool.begin().await?;
+    // Jobs only have the Spotify id of the user, without a foreign key
+    sqlx::query!(
+        "DELETE FROM generation_jobs WHERE spotify_id = $1",
+        spotify_id
+    )
+    .execute(&mut *transaction)
+    .await
+    .with_context(|| format!("Failed to delete generation jobs of user: {spotify_id}"))?;
+    // Settings, playlist history and sessions of the user are deleted with it
+    sqlx::query!("DELETE FROM users WHERE spotify_id = $1", spotify_id)
+        .execute(&mut *transaction)
+        .await
+        .with_context(|| format!("Failed to delete user: {spotify_id}"))?;
+    transaction.commit().await?;
+    Ok(())
+}
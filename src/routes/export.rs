@@ -0,0 +1,72 @@
+use std::io;
+
+use actix_session::Session;
+use actix_web::{
+    http::header::{self, ContentDisposition, DispositionParam, DispositionType},
+    web, HttpRequest, HttpResponse,
+};
+use futures_util::stream;
+use sqlx::PgPool;
+use tokio::sync::mpsc;
+
+use crate::{record_data_export, DataExport, JsonWriter};
+
+/// Downloads everything stored about the user as JSON, recording the download in `data_exports`.
+///
+/// The JSON is streamed while the rows are read from the database instead of being built in memory
+/// first.
+pub async fn export_data(
+    session: Session,
+    request: HttpRequest,
+    pg_pool: web::Data<PgPool>,
+) -> HttpResponse {
+    let Ok(Some(spotify_id)) = session.get::<String>("login") else {
+        return HttpResponse::Found()
+            .append_header((header::LOCATION, "/"))
+            .finish();
+    };
+
+    let export = match DataExport::load(pg_pool.as_ref(), &spotify_id).await {
+        Ok(export) => export,
+        Err(err) => {
+            tracing::error!("{:#}", err);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+    // Only hand out the data once the download is on record
+    let user_agent = request
+        .headers()
+        .get(header::USER_AGENT)
+        .and_then(|user_agent| user_agent.to_str().ok());
+    let export_id = match record_data_export(pg_pool.as_ref(), &spotify_id, user_agent).await {
+        Ok(export_id) => export_id,
+        Err(err) => {
+            tracing::error!("{:#}", err);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+    tracing::info!("{} downloaded their data", spotify_id);
+
+    let filename = format!("botm-data-{}.json", export.exported_at.format("%Y-%m-%d"));
+    let (sender, receiver) = mpsc::channel(4);
+    let pg_pool = pg_pool.into_inner();
+    tokio::spawn(async move {
+        let mut writer = JsonWriter::new(sender.clone());
+        if let Err(err) = export.write(&pg_pool, export_id, &mut writer).await {
+            tracing::error!("Failed to stream data export of {}: {:#}", spotify_id, err);
+            // Aborts the download, so a cut off export isn't taken for a complete one
+            let _ = sender.send(Err(io::Error::other(err.to_string()))).await;
+        }
+    });
+    let body = stream::unfold(receiver, |mut receiver| async move {
+        receiver.recv().await.map(|chunk| (chunk, receiver))
+    });
+
+    HttpResponse::Ok()
+        .content_type("application/json")
+        .insert_header(ContentDisposition {
+            disposition: DispositionType::Attachment,
+            parameters: vec![DispositionParam::Filename(filename)],
+        })
+        .streaming(body)
+}
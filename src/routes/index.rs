@@ -1,11 +1,121 @@
+use std::sync::Arc;
+
 use actix_session::Session;
 use actix_web::{web, HttpResponse};
 use actix_web_flash_messages::IncomingFlashMessages;
 use askama_actix::{Template, TemplateToResponse};
-use oauth2::basic::BasicClient;
+use chrono_tz::Tz;
 use sqlx::PgPool;
 
-use crate::{Image, SpotifyConnector, UserInfo};
+use crate::{
+    csrf_token, format_local_time, playlist_url, AuthorizationRevoked, History, HistoryEntry,
+    HistoryStatus, Image, Paused, SpotifyApi, SpotifyConnector, TokenManager, UserInfo,
+    UserSettings, REVOKED_REASON,
+};
+
+/// A playlist of the user or a month that failed, with times in the time zone of the user.
+struct HistoryRow {
+    period: String,
+    name: String,
+    /// Link to the playlist in Spotify.
+    url: Option<String>,
+    track_count: String,
+    created: String,
+    status: String,
+    failed: bool,
+}
+
+impl HistoryRow {
+    fn new(entry: &HistoryEntry, timezone: Tz) -> Self {
+        let period = entry.period.label(entry.month);
+        match &entry.status {
+            HistoryStatus::Generated {
+                playlist_id,
+                name,
+                track_count,
+                created_at,
+                updated,
+            } => Self {
+                period,
+                name: name.clone(),
+                url: Some(playlist_url(playlist_id)),
+                track_count: track_count.to_string(),
+                created: format_local_time(*created_at, timezone),
+                status: if *updated { "Updated" } else { "Created" }.to_owned(),
+                failed: false,
+            },
+            HistoryStatus::Retrying {
+                attempts,
+                next_attempt_at,
+            } => Self {
+                period,
+                name: String::new(),
+                url: None,
+                track_count: String::new(),
+                created: String::new(),
+                status: format!(
+                    "Failed {attempts}x, retrying {}",
+                    format_local_time(*next_attempt_at, timezone)
+                ),
+                failed: true,
+            },
+            HistoryStatus::NeedsAttention { attempts } => Self {
+                period,
+                name: String::new(),
+                url: None,
+                track_count: String::new(),
+                created: String::new(),
+                status: format!("Failed {attempts}x, not retried anymore"),
+                failed: true,
+            },
+        }
+    }
+}
+
+/// How generating the BOTM of last month went, shown above the history.
+struct LastMonth {
+    message: String,
+    url: Option<String>,
+    /// `ok`, `pending` or `failed`, used as CSS class.
+    state: &'static str,
+}
+
+impl LastMonth {
+    fn new(history: &History) -> Self {
+        let label = history.last_month().format("%B %Y");
+        let Some(entry) = history.last_month_entry() else {
+            return Self {
+                message: format!("Your BOTM for {label} hasn't been created yet."),
+                url: None,
+                state: "pending",
+            };
+        };
+        match &entry.status {
+            HistoryStatus::Generated { playlist_id, .. } => Self {
+                message: format!("Your BOTM for {label} is ready."),
+                url: Some(playlist_url(playlist_id)),
+                state: "ok",
+            },
+            HistoryStatus::Retrying {
+                next_attempt_at, ..
+            } => Self {
+                message: format!(
+                    "Creating your BOTM for {label} failed, it is tried again {}.",
+                    format_local_time(*next_attempt_at, history.timezone)
+                ),
+                url: None,
+                state: "failed",
+            },
+            HistoryStatus::NeedsAttention { .. } => Self {
+                message: format!(
+                    "Creating your BOTM for {label} failed and isn't tried again automatically."
+                ),
+                url: None,
+                state: "failed",
+            },
+        }
+    }
+}
 
 #[derive(Template)]
 #[template(path = "index.html")]
@@ -15,27 +125,40 @@ struct IndexTemplate<'a> {
     show_image: bool,
     profile_image_url: &'a str,
     flash_message: Option<&'a str>,
+    /// The user removed the app in their Spotify account and has to connect again.
+    reconnect: bool,
+    csrf_token: String,
+    last_month: Option<LastMonth>,
+    history: Vec<HistoryRow>,
+    /// The user paused generation, `paused_until` is empty if until they resume.
+    paused: bool,
+    paused_until: String,
 }
 
 pub async fn index(
     session: Session,
     messages: IncomingFlashMessages,
-    oauth_client: web::Data<BasicClient>,
+    tokens: web::Data<TokenManager>,
+    spotify_api: web::Data<dyn SpotifyApi>,
     pg_pool: web::Data<PgPool>,
 ) -> HttpResponse {
     let login = session.get::<String>("login").unwrap();
 
+    let mut reconnect = false;
     let user_info = if let Some(spotify_id) = &login {
-        let spotty_con = SpotifyConnector::build(
-            oauth_client.as_ref().clone(),
-            pg_pool.as_ref().clone(),
+        match load_user_info(
+            pg_pool.as_ref(),
+            tokens.as_ref().clone(),
+            spotify_api.into_inner(),
             spotify_id,
         )
-        .await;
-        if let Ok(mut spotty_con) = spotty_con {
-            spotty_con.get_user_info().await.ok()
-        } else {
-            None
+        .await
+        {
+            Ok(user_info) => Some(user_info),
+            Err(err) => {
+                reconnect = err.is::<AuthorizationRevoked>();
+                None
+            }
         }
     } else {
         None
@@ -46,11 +169,56 @@ pub async fn index(
         images: vec![Image { url: "".to_owned() }],
     });
 
+    // Only logged in users get forms, so anonymous visitors don't get a session
+    let csrf_token = if login.is_some() {
+        match csrf_token(&session) {
+            Ok(csrf_token) => csrf_token,
+            Err(err) => {
+                tracing::error!("{:#}", err);
+                return HttpResponse::InternalServerError().finish();
+            }
+        }
+    } else {
+        String::new()
+    };
+
+    let history = match &login {
+        Some(spotify_id) if !reconnect => match History::load(pg_pool.as_ref(), spotify_id).await {
+            Ok(history) => Some(history),
+            Err(err) => {
+                tracing::error!("{:#}", err);
+                return HttpResponse::InternalServerError().finish();
+            }
+        },
+        _ => None,
+    };
+
+    let paused = match &login {
+        Some(spotify_id) if !reconnect => match Paused::load(pg_pool.as_ref(), spotify_id).await {
+            Ok(paused) => paused,
+            Err(err) => {
+                tracing::error!("{:#}", err);
+                return HttpResponse::InternalServerError().finish();
+            }
+        },
+        _ => None,
+    };
+
+    let settings = match &login {
+        Some(spotify_id) if !reconnect => {
+            match UserSettings::load(pg_pool.as_ref(), spotify_id).await {
+                Ok(settings) => settings,
+                Err(err) => {
+                    tracing::error!("{:#}", err);
+                    return HttpResponse::InternalServerError().finish();
+                }
+            }
+        }
+        _ => UserSettings::default(),
+    };
+
     let message = messages.iter().next();
-    tracing::debug!(
-        "Flash messages: {:?}",
-        message.and_then(|m| Some(m.content()))
-    );
+    tracing::debug!("Flash messages: {:?}", message.map(|m| m.content()));
 
     IndexTemplate {
         logged_in: login.is_some(),
@@ -58,10 +226,57 @@ pub async fn index(
         show_image: false,
         profile_image_url: &user_info
             .images
-            .get(0)
-            .and_then(|i| Some(i.url.to_owned()))
+            .first()
+            .map(|i| i.url.to_owned())
+            .unwrap_or_default(),
+        flash_message: message.map(|m| m.content()),
+        reconnect,
+        csrf_token,
+        last_month: history
+            .as_ref()
+            .map(LastMonth::new)
+            .filter(|last_month| settings.notify(last_month.state == "failed")),
+        history: history
+            .iter()
+            .flat_map(|history| {
+                history
+                    .entries
+                    .iter()
+                    .map(|entry| HistoryRow::new(entry, history.timezone))
+            })
+            .collect(),
+        paused: paused.is_some(),
+        paused_until: paused
+            .and_then(|paused| paused.resume_on)
+            .map(|resume_on| resume_on.format("%Y-%m-%d").to_string())
             .unwrap_or_default(),
-        flash_message: message.and_then(|m| Some(m.content())),
     }
     .to_response()
 }
+
+/// Gets the Spotify profile of the user,
+/// failing with [`AuthorizationRevoked`] if the user was deactivated because of it.
+async fn load_user_info(
+    pg_pool: &PgPool,
+    tokens: TokenManager,
+    spotify_api: Arc<dyn SpotifyApi>,
+    spotify_id: &str,
+) -> anyhow::Result<UserInfo> {
+    let deactivated_reason = sqlx::query_scalar!(
+        "SELECT deactivated_reason FROM users WHERE spotify_id = $1",
+        spotify_id
+    )
+    .fetch_optional(pg_pool)
+    .await?
+    .flatten();
+    if deactivated_reason.as_deref() == Some(REVOKED_REASON) {
+        return Err(AuthorizationRevoked {
+            spotify_id: spotify_id.to_owned(),
+        }
+        .into());
+    }
+
+    SpotifyConnector::new(tokens, spotify_api, spotify_id)
+        .get_user_info()
+        .await
+}
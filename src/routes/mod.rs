@@ -1,15 +1,23 @@
 pub mod connect;
 pub mod disconnect;
+pub mod export;
 pub mod generate;
 pub mod health_check;
 pub mod index;
 pub mod not_found;
+pub mod pause;
+pub mod preview;
 pub mod redirect;
+pub mod settings;
 
 pub use connect::*;
 pub use disconnect::*;
+pub use export::*;
 pub use generate::*;
 pub use health_check::*;
 pub use index::*;
 pub use not_found::*;
+pub use pause::*;
+pub use preview::*;
 pub use redirect::*;
+pub use settings::*;
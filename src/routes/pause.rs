@@ -0,0 +1,83 @@
+use actix_session::Session;
+use actix_web::{http::header, web, HttpResponse};
+use actix_web_flash_messages::FlashMessage;
+use chrono::NaiveDate;
+use sqlx::PgPool;
+
+use crate::{pause_user, resume_user, user_timezone};
+
+#[derive(serde::Deserialize, Debug)]
+pub struct PauseForm {
+    /// `YYYY-MM-DD` of a date input, empty to pause until the user resumes.
+    #[serde(default)]
+    resume_on: String,
+}
+
+/// Stops the monthly generation for the user without disconnecting them.
+pub async fn pause(
+    session: Session,
+    pg_pool: web::Data<PgPool>,
+    form: web::Form<PauseForm>,
+) -> HttpResponse {
+    let Ok(Some(spotify_id)) = session.get::<String>("login") else {
+        return HttpResponse::Found()
+            .append_header((header::LOCATION, "/"))
+            .finish();
+    };
+
+    let timezone = match user_timezone(pg_pool.as_ref(), &spotify_id).await {
+        Ok(timezone) => timezone,
+        Err(err) => {
+            tracing::error!("{:#}", err);
+            FlashMessage::error("Failed to pause your BOTM.").send();
+            return HttpResponse::Found()
+                .append_header((header::LOCATION, "/"))
+                .finish();
+        }
+    };
+    let today = chrono::Utc::now().with_timezone(&timezone).date_naive();
+    let resume_on = match form.resume_on.trim() {
+        "" => None,
+        resume_on => match resume_on.parse::<NaiveDate>() {
+            Ok(resume_on) if resume_on > today => Some(resume_on),
+            _ => {
+                FlashMessage::error("The resume date must be a day in the future.").send();
+                return HttpResponse::Found()
+                    .append_header((header::LOCATION, "/"))
+                    .finish();
+            }
+        },
+    };
+
+    match pause_user(pg_pool.as_ref(), &spotify_id, resume_on).await {
+        Ok(()) => FlashMessage::info("Your BOTM is paused.").send(),
+        Err(err) => {
+            tracing::error!("{:#}", err);
+            FlashMessage::error("Failed to pause your BOTM.").send();
+        }
+    }
+
+    HttpResponse::Found()
+        .append_header((header::LOCATION, "/"))
+        .finish()
+}
+
+pub async fn resume(session: Session, pg_pool: web::Data<PgPool>) -> HttpResponse {
+    let Ok(Some(spotify_id)) = session.get::<String>("login") else {
+        return HttpResponse::Found()
+            .append_header((header::LOCATION, "/"))
+            .finish();
+    };
+
+    match resume_user(pg_pool.as_ref(), &spotify_id, chrono::Utc::now()).await {
+        Ok(()) => FlashMessage::info("Your BOTM is resumed.").send(),
+        Err(err) => {
+            tracing::error!("{:#}", err);
+            FlashMessage::error("Failed to resume your BOTM.").send();
+        }
+    }
+
+    HttpResponse::Found()
+        .append_header((header::LOCATION, "/"))
+        .finish()
+}
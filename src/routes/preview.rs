@@ -0,0 +1,132 @@
+use std::sync::Arc;
+
+use actix_session::Session;
+use actix_web::{http::header, web, HttpResponse};
+use actix_web_flash_messages::FlashMessage;
+use anyhow::Context;
+use askama_actix::{Template, TemplateToResponse};
+use sqlx::PgPool;
+
+use crate::{
+    current_month, render_template, user_timezone, AuthorizationRevoked, Paused, Period,
+    SpotifyApi, SpotifyConnector, TemplateValues, TokenManager, Track, UserSettings,
+};
+
+/// A top track as it would land in the playlist.
+struct TrackRow {
+    rank: usize,
+    name: String,
+    artists: String,
+    album: String,
+    /// Url of the smallest album cover, empty if the album has none.
+    image_url: String,
+}
+
+impl TrackRow {
+    fn new(rank: usize, track: Track) -> Self {
+        Self {
+            rank,
+            name: track.name,
+            artists: track
+                .artists
+                .iter()
+                .map(|artist| artist.name.as_str())
+                .collect::<Vec<_>>()
+                .join(", "),
+            image_url: track
+                .album
+                .images
+                .last()
+                .map(|image| image.url.clone())
+                .unwrap_or_default(),
+            album: track.album.name,
+        }
+    }
+}
+
+#[derive(Template)]
+#[template(path = "preview.html")]
+struct PreviewTemplate {
+    month: String,
+    playlist_name: String,
+    tracks: Vec<TrackRow>,
+    /// Paused users don't get a BOTM for the month unless they resume before it ends.
+    paused: bool,
+}
+
+/// Shows the tracks the BOTM of the current month would get so far, without creating anything.
+///
+/// There is no way to create it from here, a BOTM is only generated once its month has ended.
+pub async fn get_preview(
+    session: Session,
+    pg_pool: web::Data<PgPool>,
+    tokens: web::Data<TokenManager>,
+    spotify_api: web::Data<dyn SpotifyApi>,
+) -> HttpResponse {
+    let Ok(Some(spotify_id)) = session.get::<String>("login") else {
+        return HttpResponse::Found()
+            .append_header((header::LOCATION, "/"))
+            .finish();
+    };
+
+    let template = load_preview(
+        pg_pool.as_ref(),
+        tokens.as_ref().clone(),
+        spotify_api.into_inner(),
+        &spotify_id,
+    )
+    .await;
+    match template {
+        Ok(template) => template.to_response(),
+        // The start page asks the user to reconnect
+        Err(err) if err.is::<AuthorizationRevoked>() => HttpResponse::Found()
+            .append_header((header::LOCATION, "/"))
+            .finish(),
+        Err(err) => {
+            tracing::error!("{:#}", err);
+            FlashMessage::error("Failed to get your top tracks from Spotify.").send();
+            HttpResponse::Found()
+                .append_header((header::LOCATION, "/"))
+                .finish()
+        }
+    }
+}
+
+async fn load_preview(
+    pg_pool: &PgPool,
+    tokens: TokenManager,
+    spotify_api: Arc<dyn SpotifyApi>,
+    spotify_id: &str,
+) -> anyhow::Result<PreviewTemplate> {
+    let timezone = user_timezone(pg_pool, spotify_id).await?;
+    let now = chrono::Utc::now().with_timezone(&timezone);
+    let month = current_month(&now);
+
+    let paused = Paused::load(pg_pool, spotify_id).await?.is_some();
+    let settings = UserSettings::load(pg_pool, spotify_id).await?;
+    let tracks = SpotifyConnector::new(tokens, spotify_api, spotify_id)
+        .top_tracks(&settings.time_range, settings.track_count as usize)
+        .await?;
+
+    let playlist_name = render_template(
+        &settings.name_template,
+        &TemplateValues {
+            month,
+            track_count: tracks.len(),
+            generated: now.date_naive(),
+            locale: settings.locale(),
+        },
+    )
+    .context("Failed to render playlist name")?;
+
+    Ok(PreviewTemplate {
+        month: Period::Month.label(month),
+        playlist_name,
+        tracks: tracks
+            .into_iter()
+            .enumerate()
+            .map(|(i, track)| TrackRow::new(i + 1, track))
+            .collect(),
+        paused,
+    })
+}
@@ -1,15 +1,16 @@
-use std::time::Duration;
-
 use actix_session::Session;
-use actix_web::{web, HttpResponse, Responder};
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
 use actix_web_flash_messages::FlashMessage;
-use oauth2::{AuthorizationCode, CsrfToken, TokenResponse};
+use oauth2::{AuthorizationCode, CsrfToken, PkceCodeVerifier, TokenResponse};
 use reqwest::header;
 use secrecy::{ExposeSecret, SecretString};
 use sqlx::PgPool;
 use tracing::error;
 
-use crate::STATE_COOKIE;
+use crate::{
+    SpotifyApi, TokenCipher, TokenKind, TokenManager, PAUSED_REASON, PKCE_VERIFIER_COOKIE,
+    SCOPES_COOKIE, STATE_COOKIE, TIMEZONE_COOKIE, USER_AGENT_KEY,
+};
 
 #[derive(serde::Deserialize, Debug)]
 pub struct RedirectParams {
@@ -26,21 +27,22 @@ pub enum Outcome {
     Code(SecretString),
 }
 
-#[derive(serde::Deserialize, Debug)]
-struct MeResponse {
-    id: String,
-}
-
+#[allow(clippy::too_many_arguments)]
 pub async fn redirect(
+    request: HttpRequest,
     session: Session,
     params: web::Query<RedirectParams>,
     oauth: web::Data<oauth2::basic::BasicClient>,
+    spotify_api: web::Data<dyn SpotifyApi>,
+    tokens: web::Data<TokenManager>,
     pg_pool: web::Data<PgPool>,
 ) -> impl Responder {
     // Checking state
     let Ok(Some(cookie_state)) = session.get::<CsrfToken>(STATE_COOKIE) else {
         FlashMessage::error("Failed to connect to Spotify.\nNo state cookie.").send();
-        return HttpResponse::Found().append_header((header::LOCATION, "/")).finish();
+        return HttpResponse::Found()
+            .append_header((header::LOCATION, "/"))
+            .finish();
         // return HttpResponse::Forbidden().body("No state cookie");
     };
     if cookie_state.secret() != &params.state {
@@ -51,6 +53,13 @@ pub async fn redirect(
         // return HttpResponse::Unauthorized().body("Wrong state");
     }
     session.remove(STATE_COOKIE);
+    let Some(Ok(pkce_verifier)) = session.remove_as::<PkceCodeVerifier>(PKCE_VERIFIER_COOKIE)
+    else {
+        FlashMessage::error("Failed to connect to Spotify.\nNo PKCE verifier cookie.").send();
+        return HttpResponse::Found()
+            .append_header((header::LOCATION, "/"))
+            .finish();
+    };
 
     if let Outcome::Error(error) = &params.outcome {
         let message = match error.as_str() {
@@ -68,13 +77,16 @@ pub async fn redirect(
     };
 
     // Get access_token
-    let Ok(token_response) =
-        oauth
+    let Ok(token_response) = oauth
         .exchange_code(AuthorizationCode::new(code.expose_secret().clone()))
-        .request_async(oauth2::reqwest::async_http_client)
-        .await else {
+        .set_pkce_verifier(pkce_verifier)
+        .request_async(|request| spotify_api.oauth_http_client(request))
+        .await
+    else {
         FlashMessage::error("Failed to connect to Spotify.\nCould not get access token.").send();
-        return HttpResponse::Found().append_header((header::LOCATION, "/")).finish();
+        return HttpResponse::Found()
+            .append_header((header::LOCATION, "/"))
+            .finish();
         // return HttpResponse::Unauthorized().body("Failed to get token");
     };
 
@@ -89,31 +101,70 @@ pub async fn redirect(
     // Get spotify_id
     tracing::info!("Making /me request");
 
-    let client = reqwest::Client::new();
-    let Ok(spotify_id) = client
-        .get("https://api.spotify.com/v1/me")
-        .bearer_auth(access_token.secret())
-        .timeout(Duration::from_secs(10))
-        .send()
-        .await else {
-        return HttpResponse::InternalServerError().body("Failed to access user info");
+    let me_response = match spotify_api.me(access_token.secret()).await {
+        Ok(me_response) => me_response,
+        Err(err) => {
+            error!("Failed to get user info: {:#}", err);
+            return HttpResponse::InternalServerError().body("Failed to access user info");
+        }
     };
 
-    let me_response: MeResponse = spotify_id.json().await.unwrap();
-
     println!("Me response: {:#?}", me_response);
 
-    // Save into users table
+    let timezone = session.get::<String>(TIMEZONE_COOKIE).ok().flatten();
+    session.remove(TIMEZONE_COOKIE);
+
+    // Spotify may leave out the scopes if it granted exactly the ones asked for
+    let requested_scopes = session
+        .remove_as::<String>(SCOPES_COOKIE)
+        .and_then(Result::ok);
+    let scopes = token_response
+        .scopes()
+        .map(|scopes| {
+            scopes
+                .iter()
+                .map(|scope| scope.as_str())
+                .collect::<Vec<_>>()
+                .join(" ")
+        })
+        .or(requested_scopes)
+        .unwrap_or_default();
+
+    let cipher = tokens.cipher();
+    let refresh_token = token_response
+        .refresh_token()
+        .expect("Failed to unwrap refresh_token");
+    let encrypted_tokens = encrypt_tokens(
+        cipher,
+        &me_response.id,
+        refresh_token.secret(),
+        access_token.secret(),
+    );
+    let (refresh_token, access_token) = match encrypted_tokens {
+        Ok(encrypted_tokens) => encrypted_tokens,
+        Err(err) => {
+            error!("{:#}", err);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+
+    // Save into users table, keeping a time zone the user already has
+    // and reactivating users whose authorization was revoked, but not users who paused
     let query_res = sqlx::query!(
-        r#"INSERT INTO users (spotify_id, active, refresh_token, access_token, expiry_timestamp) VALUES ($1, true, $2, $3, $4)
-            ON CONFLICT (spotify_id) DO UPDATE SET refresh_token = $2"#,
+        r#"INSERT INTO users (spotify_id, active, refresh_token, access_token, expiry_timestamp, timezone, scopes, token_key_id) VALUES ($1, true, $2, $3, $4, $5, $6, $7)
+            ON CONFLICT (spotify_id) DO UPDATE SET refresh_token = $2, access_token = $3, expiry_timestamp = $4,
+                timezone = COALESCE(users.timezone, $5), scopes = $6, token_key_id = $7,
+                active = users.deactivated_reason IS DISTINCT FROM $8,
+                deactivated_reason = CASE WHEN users.deactivated_reason = $8 THEN users.deactivated_reason END,
+                deactivated_at = CASE WHEN users.deactivated_reason = $8 THEN users.deactivated_at END"#,
         me_response.id,
-        token_response
-            .refresh_token()
-            .expect("Failed to unwrap refresh_token")
-            .secret(),
-        token_response.access_token().secret(),
+        refresh_token,
+        access_token,
         expiry_timestamp,
+        timezone,
+        scopes,
+        cipher.key_id(),
+        PAUSED_REASON,
     )
     .execute(pg_pool.as_ref())
     .await;
@@ -125,11 +176,35 @@ pub async fn redirect(
         ));
     }
 
+    // A new session key on login, so a key planted before can't be used to act as the user
+    session.renew();
     session
         .insert("login", me_response.id)
         .expect("Set session value");
+    if let Some(user_agent) = request
+        .headers()
+        .get(header::USER_AGENT)
+        .and_then(|user_agent| user_agent.to_str().ok())
+    {
+        session
+            .insert(USER_AGENT_KEY, user_agent)
+            .expect("Set session value");
+    }
 
     HttpResponse::Found()
         .append_header((header::LOCATION, "/"))
         .finish()
 }
+
+/// Encrypts the refresh and access token for storing them.
+fn encrypt_tokens(
+    cipher: &TokenCipher,
+    spotify_id: &str,
+    refresh_token: &str,
+    access_token: &str,
+) -> anyhow::Result<(String, String)> {
+    Ok((
+        cipher.encrypt(spotify_id, TokenKind::Refresh, refresh_token)?,
+        cipher.encrypt(spotify_id, TokenKind::Access, access_token)?,
+    ))
+}
@@ -0,0 +1,266 @@
+use actix_session::Session;
+use actix_web::{http::header, web, HttpResponse};
+use actix_web_flash_messages::{FlashMessage, IncomingFlashMessages};
+use anyhow::Context;
+use askama_actix::{Template, TemplateToResponse};
+use chrono::Datelike;
+use chrono_tz::{Tz, TZ_VARIANTS};
+use sqlx::PgPool;
+
+use crate::{
+    csrf_token, format_local_time, render_template, user_sessions, user_timezone, TemplateValues,
+    UserSession, UserSettings, COVER_STYLES, MAX_TRACK_COUNT, NOTIFICATIONS, PLACEHOLDERS,
+    TIME_RANGES,
+};
+
+struct SelectOption {
+    value: &'static str,
+    label: &'static str,
+    selected: bool,
+}
+
+/// A session of the user, with times in their time zone.
+struct SessionRow {
+    user_agent: String,
+    created: String,
+    last_seen: String,
+}
+
+impl SessionRow {
+    fn new(session: UserSession, timezone: Tz) -> Self {
+        Self {
+            user_agent: session
+                .user_agent
+                .unwrap_or_else(|| "Unknown browser".to_owned()),
+            created: format_local_time(session.created_at, timezone),
+            last_seen: format_local_time(session.last_seen_at, timezone),
+        }
+    }
+}
+
+#[derive(Template)]
+#[template(path = "settings.html")]
+struct SettingsTemplate<'a> {
+    timezones: Vec<SelectOption>,
+    time_ranges: Vec<SelectOption>,
+    cover_styles: Vec<SelectOption>,
+    notifications: Vec<SelectOption>,
+    settings: UserSettings,
+    placeholders: &'a [(&'a str, &'a str)],
+    max_track_count: i32,
+    example_name: String,
+    sessions: Vec<SessionRow>,
+    csrf_token: String,
+    flash_message: Option<&'a str>,
+}
+
+#[derive(serde::Deserialize, Debug)]
+pub struct SettingsForm {
+    timezone: String,
+    name_template: String,
+    description_template: String,
+    locale: String,
+    time_range: String,
+    track_count: i32,
+    // Unchecked checkboxes aren't sent at all
+    #[serde(default)]
+    quarterly_playlist: bool,
+    #[serde(default)]
+    yearly_playlist: bool,
+    public: bool,
+    #[serde(default)]
+    collaborative: bool,
+    cover_style: String,
+    notifications: String,
+}
+
+pub async fn get_settings(
+    session: Session,
+    messages: IncomingFlashMessages,
+    pg_pool: web::Data<PgPool>,
+) -> HttpResponse {
+    let Ok(Some(spotify_id)) = session.get::<String>("login") else {
+        return HttpResponse::Found()
+            .append_header((header::LOCATION, "/"))
+            .finish();
+    };
+
+    let tz = match user_timezone(pg_pool.as_ref(), &spotify_id).await {
+        Ok(tz) => tz,
+        Err(err) => {
+            tracing::error!("{:#}", err);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+
+    let Ok(settings) = UserSettings::load(pg_pool.as_ref(), &spotify_id).await else {
+        tracing::error!("Failed to get settings of {} from database", spotify_id);
+        return HttpResponse::InternalServerError().finish();
+    };
+
+    let sessions = match user_sessions(pg_pool.as_ref(), &spotify_id).await {
+        Ok(sessions) => sessions,
+        Err(err) => {
+            tracing::error!("{:#}", err);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+
+    let csrf_token = match csrf_token(&session) {
+        Ok(csrf_token) => csrf_token,
+        Err(err) => {
+            tracing::error!("{:#}", err);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+
+    let now = chrono::Utc::now().date_naive();
+    let example_name = render_template(
+        &settings.name_template,
+        &TemplateValues {
+            month: now.with_day(1).unwrap_or(now),
+            track_count: settings.track_count as usize,
+            generated: now,
+            locale: settings.locale(),
+        },
+    )
+    .unwrap_or_default();
+
+    let message = messages.iter().next();
+
+    SettingsTemplate {
+        timezones: TZ_VARIANTS
+            .iter()
+            .map(|variant| SelectOption {
+                value: variant.name(),
+                label: variant.name(),
+                selected: *variant == tz,
+            })
+            .collect(),
+        time_ranges: TIME_RANGES
+            .iter()
+            .map(|(time_range, label)| SelectOption {
+                value: time_range,
+                label,
+                selected: *time_range == settings.time_range,
+            })
+            .collect(),
+        cover_styles: COVER_STYLES
+            .iter()
+            .map(|(style, label)| SelectOption {
+                value: style.as_str(),
+                label,
+                selected: *style == settings.cover_style(),
+            })
+            .collect(),
+        notifications: NOTIFICATIONS
+            .iter()
+            .map(|(notifications, label)| SelectOption {
+                value: notifications,
+                label,
+                selected: *notifications == settings.notifications,
+            })
+            .collect(),
+        settings,
+        placeholders: PLACEHOLDERS,
+        max_track_count: MAX_TRACK_COUNT,
+        example_name,
+        sessions: sessions
+            .into_iter()
+            .map(|session| SessionRow::new(session, tz))
+            .collect(),
+        csrf_token,
+        flash_message: message.map(|m| m.content()),
+    }
+    .to_response()
+}
+
+pub async fn post_settings(
+    session: Session,
+    pg_pool: web::Data<PgPool>,
+    form: web::Form<SettingsForm>,
+) -> HttpResponse {
+    let Ok(Some(spotify_id)) = session.get::<String>("login") else {
+        return HttpResponse::Found()
+            .append_header((header::LOCATION, "/"))
+            .finish();
+    };
+
+    let form = form.into_inner();
+    let settings = UserSettings {
+        name_template: form.name_template,
+        description_template: form.description_template,
+        locale: form.locale.trim().to_owned(),
+        time_range: form.time_range,
+        track_count: form.track_count,
+        quarterly_playlist: form.quarterly_playlist,
+        yearly_playlist: form.yearly_playlist,
+        public: form.public,
+        collaborative: form.collaborative,
+        cover_style: form.cover_style,
+        notifications: form.notifications,
+    };
+    let validation = if form.timezone.parse::<Tz>().is_err() {
+        Err(format!("Unknown time zone \"{}\".", form.timezone))
+    } else {
+        settings.validate()
+    };
+    if let Err(message) = validation {
+        FlashMessage::error(message).send();
+        return HttpResponse::Found()
+            .append_header((header::LOCATION, "/settings"))
+            .finish();
+    }
+
+    let res = save_settings(pg_pool.as_ref(), &spotify_id, &form.timezone, &settings).await;
+
+    match res {
+        Ok(granted_scopes) if !settings.missing_scopes(&granted_scopes).is_empty() => {
+            // Send the user through Spotify again to grant what the new settings need
+            tracing::info!(
+                "{} needs to grant {:?}",
+                spotify_id,
+                settings.missing_scopes(&granted_scopes)
+            );
+            return HttpResponse::Found()
+                .append_header((header::LOCATION, "/connect"))
+                .finish();
+        }
+        Ok(_) => FlashMessage::info("Settings saved.").send(),
+        Err(err) => {
+            tracing::error!("Failed to save settings of {}: {}", spotify_id, err);
+            FlashMessage::error("Failed to save settings.").send();
+        }
+    }
+
+    HttpResponse::Found()
+        .append_header((header::LOCATION, "/settings"))
+        .finish()
+}
+
+/// Saves the time zone and settings together and returns the scopes the user has granted so far.
+async fn save_settings(
+    pg_pool: &PgPool,
+    spotify_id: &str,
+    timezone: &str,
+    settings: &UserSettings,
+) -> anyhow::Result<String> {
+    let mut transaction = pg_pool
+        .begin()
+        .await
+        .context("Failed to begin transaction")?;
+    let granted_scopes = sqlx::query_scalar!(
+        "UPDATE users SET timezone = $1 WHERE spotify_id = $2 RETURNING scopes",
+        timezone,
+        spotify_id
+    )
+    .fetch_one(&mut *transaction)
+    .await
+    .with_context(|| format!("Failed to save time zone of user: {spotify_id}"))?;
+    settings.save(&mut *transaction, spotify_id).await?;
+    transaction
+        .commit()
+        .await
+        .with_context(|| format!("Failed to save settings for user: {spotify_id}"))?;
+    Ok(granted_scopes)
+}
@@ -0,0 +1,122 @@
+use std::sync::Arc;
+
+use anyhow::Context;
+use chrono_tz::Tz;
+use sqlx::PgPool;
+use tokio::task::JoinHandle;
+
+use crate::{
+    generate_botms, GenerateOptions, GeneratorConfig, SchedulerConfig, SpotifyApi, TokenManager,
+};
+
+/// Key of the Postgres advisory lock held while a scheduled run is in progress.
+///
+/// Every fly.io machine runs its own scheduler, only the one getting the lock generates.
+pub const SCHEDULER_LOCK_KEY: i64 = 0x424f_544d; // "BOTM"
+
+/// In-process replacement for the external cron job calling `/generate`.
+///
+/// Every tick generates for the users whose month has ended in their own time zone,
+/// so running it hourly creates each playlist shortly after the local month end.
+pub struct Scheduler {
+    schedule: cron::Schedule,
+    timezone: Tz,
+    pg_pool: PgPool,
+    tokens: TokenManager,
+    spotify_api: Arc<dyn SpotifyApi>,
+    generator_config: GeneratorConfig,
+}
+
+impl Scheduler {
+    pub fn new(
+        config: &SchedulerConfig,
+        pg_pool: PgPool,
+        tokens: TokenManager,
+        spotify_api: Arc<dyn SpotifyApi>,
+        generator_config: GeneratorConfig,
+    ) -> anyhow::Result<Self> {
+        Ok(Self {
+            schedule: config.schedule()?,
+            timezone: config.timezone,
+            pg_pool,
+            tokens,
+            spotify_api,
+            generator_config,
+        })
+    }
+
+    /// Runs the scheduler in the background until the runtime shuts down.
+    pub fn spawn(self) -> JoinHandle<()> {
+        tokio::spawn(async move { self.run().await })
+    }
+
+    async fn run(self) {
+        loop {
+            let now = chrono::Utc::now().with_timezone(&self.timezone);
+            let Some(next) = self.schedule.after(&now).next() else {
+                tracing::warn!("Scheduler has no upcoming runs, stopping");
+                return;
+            };
+            tracing::info!("Next scheduled BOTM run at {next}");
+
+            let wait = (next - now).to_std().unwrap_or_default();
+            tokio::time::sleep(wait).await;
+
+            if let Err(err) = self.tick().await {
+                tracing::error!("Scheduled BOTM run failed: {:#}", err);
+            }
+        }
+    }
+
+    /// Generates the BOTMs if no other instance is currently doing so.
+    pub async fn tick(&self) -> anyhow::Result<()> {
+        // Advisory locks belong to the session, so lock and unlock on the same connection.
+        let mut conn = self
+            .pg_pool
+            .acquire()
+            .await
+            .context("Failed to acquire connection for scheduler lock")?;
+
+        let locked = sqlx::query_scalar!("SELECT pg_try_advisory_lock($1)", SCHEDULER_LOCK_KEY)
+            .fetch_one(&mut *conn)
+            .await
+            .context("Failed to try scheduler lock")?
+            .unwrap_or_default();
+        if !locked {
+            tracing::info!("Scheduled BOTM run is already in progress on another instance");
+            return Ok(());
+        }
+
+        let options = GenerateOptions {
+            due_only: true,
+            ..Default::default()
+        };
+        let result = generate_botms(
+            &self.pg_pool,
+            &self.tokens,
+            self.spotify_api.as_ref(),
+            &self.generator_config,
+            &options,
+        )
+        .await;
+
+        sqlx::query_scalar!("SELECT pg_advisory_unlock($1)", SCHEDULER_LOCK_KEY)
+            .fetch_one(&mut *conn)
+            .await
+            .context("Failed to release scheduler lock")?;
+
+        let Some(summary) = result? else {
+            tracing::info!("No users are due for a scheduled BOTM run");
+            return Ok(());
+        };
+        tracing::info!(
+            "Scheduled BOTM run {} done: {} created, {} updated, {} skipped, {} failed",
+            summary.run_id,
+            summary.created,
+            summary.updated,
+            summary.skipped,
+            summary.failed
+        );
+        Ok(())
+    }
+}
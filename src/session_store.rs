@@ -0,0 +1,196 @@
+use std::collections::HashMap;
+
+use actix_session::storage::{LoadError, SaveError, SessionKey, SessionStore, UpdateError};
+use actix_web::cookie::time::Duration;
+use anyhow::Context;
+use base64::{engine::general_purpose, Engine};
+use rand::{distributions::Alphanumeric, rngs::OsRng, Rng};
+use sha2::{Digest, Sha256};
+use sqlx::{types::Json, PgPool};
+
+/// Session key of the logged in user.
+const LOGIN_KEY: &str = "login";
+/// Session key of the user agent the user logged in with, shown in the list of sessions.
+pub const USER_AGENT_KEY: &str = "user_agent";
+
+type SessionState = HashMap<String, String>;
+
+/// Stores sessions in the `sessions` table, so they can be listed and revoked.
+///
+/// The logged in user and their user agent are copied out of the state into their own columns.
+#[derive(Clone)]
+pub struct PgSessionStore {
+    pg_pool: PgPool,
+}
+
+/// A session of a user, as listed on the settings page.
+#[derive(serde::Serialize, Debug)]
+pub struct UserSession {
+    pub user_agent: Option<String>,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    pub last_seen_at: chrono::DateTime<chrono::Utc>,
+    pub expires_at: chrono::DateTime<chrono::Utc>,
+}
+
+impl PgSessionStore {
+    pub fn new(pg_pool: PgPool) -> Self {
+        Self { pg_pool }
+    }
+
+    async fn insert(
+        &self,
+        session_state: &SessionState,
+        ttl: &Duration,
+    ) -> anyhow::Result<SessionKey> {
+        // Drop expired sessions along the way, they are never loaded again
+        sqlx::query!("DELETE FROM sessions WHERE expires_at <= now()")
+            .execute(&self.pg_pool)
+            .await
+            .context("Failed to delete expired sessions")?;
+
+        let session_key = generate_session_key();
+        sqlx::query!(
+            r#"INSERT INTO sessions (id, state, spotify_id, user_agent, expires_at)
+                VALUES ($1, $2, $3, $4, $5)"#,
+            hash_session_key(&session_key),
+            Json(session_state) as _,
+            state_value(session_state, LOGIN_KEY)?,
+            state_value(session_state, USER_AGENT_KEY)?,
+            expires_at(ttl),
+        )
+        .execute(&self.pg_pool)
+        .await
+        .context("Failed to insert session")?;
+        Ok(session_key)
+    }
+}
+
+#[async_trait::async_trait(?Send)]
+impl SessionStore for PgSessionStore {
+    async fn load(&self, session_key: &SessionKey) -> Result<Option<SessionState>, LoadError> {
+        let state = sqlx::query_scalar!(
+            r#"SELECT state AS "state: Json<SessionState>" FROM sessions
+                WHERE id = $1 AND expires_at > now()"#,
+            hash_session_key(session_key)
+        )
+        .fetch_optional(&self.pg_pool)
+        .await
+        .context("Failed to load session")
+        .map_err(LoadError::Other)?;
+        Ok(state.map(|state| state.0))
+    }
+
+    async fn save(
+        &self,
+        session_state: SessionState,
+        ttl: &Duration,
+    ) -> Result<SessionKey, SaveError> {
+        self.insert(&session_state, ttl)
+            .await
+            .map_err(SaveError::Other)
+    }
+
+    async fn update(
+        &self,
+        session_key: SessionKey,
+        session_state: SessionState,
+        ttl: &Duration,
+    ) -> Result<SessionKey, UpdateError> {
+        let updated = sqlx::query!(
+            r#"UPDATE sessions SET state = $1, spotify_id = $2, user_agent = $3, expires_at = $4, last_seen_at = now()
+                WHERE id = $5 AND expires_at > now()"#,
+            Json(&session_state) as _,
+            state_value(&session_state, LOGIN_KEY).map_err(UpdateError::Serialization)?,
+            state_value(&session_state, USER_AGENT_KEY).map_err(UpdateError::Serialization)?,
+            expires_at(ttl),
+            hash_session_key(&session_key),
+        )
+        .execute(&self.pg_pool)
+        .await
+        .context("Failed to update session")
+        .map_err(UpdateError::Other)?;
+
+        if updated.rows_affected() == 0 {
+            // The session expired or was logged out while the request was handled,
+            // start over with an empty one instead of logging the user in again
+            return self
+                .insert(&SessionState::new(), ttl)
+                .await
+                .map_err(UpdateError::Other);
+        }
+        Ok(session_key)
+    }
+
+    async fn update_ttl(&self, session_key: &SessionKey, ttl: &Duration) -> anyhow::Result<()> {
+        sqlx::query!(
+            "UPDATE sessions SET expires_at = $1, last_seen_at = now() WHERE id = $2",
+            expires_at(ttl),
+            hash_session_key(session_key),
+        )
+        .execute(&self.pg_pool)
+        .await
+        .context("Failed to extend session")?;
+        Ok(())
+    }
+
+    async fn delete(&self, session_key: &SessionKey) -> anyhow::Result<()> {
+        sqlx::query!(
+            "DELETE FROM sessions WHERE id = $1",
+            hash_session_key(session_key)
+        )
+        .execute(&self.pg_pool)
+        .await
+        .context("Failed to delete session")?;
+        Ok(())
+    }
+}
+
+/// The sessions the user is logged in with that didn't expire yet, most recently used first.
+pub async fn user_sessions(pg_pool: &PgPool, spotify_id: &str) -> anyhow::Result<Vec<UserSession>> {
+    sqlx::query_as!(
+        UserSession,
+        r#"SELECT user_agent, created_at, last_seen_at, expires_at FROM sessions
+            WHERE spotify_id = $1 AND expires_at > now()
+            ORDER BY last_seen_at DESC"#,
+        spotify_id
+    )
+    .fetch_all(pg_pool)
+    .await
+    .with_context(|| format!("Failed to get sessions of user: {spotify_id}"))
+}
+
+/// Logs the user out of all their sessions, returns how many there were.
+pub async fn delete_user_sessions(pg_pool: &PgPool, spotify_id: &str) -> anyhow::Result<u64> {
+    let deleted = sqlx::query!("DELETE FROM sessions WHERE spotify_id = $1", spotify_id)
+        .execute(pg_pool)
+        .await
+        .with_context(|| format!("Failed to delete sessions of user: {spotify_id}"))?;
+    Ok(deleted.rows_affected())
+}
+
+/// A random session key of 64 alphanumeric characters, like the stores of `actix_session` use.
+fn generate_session_key() -> SessionKey {
+    let key: String = (0..64)
+        .map(|_| OsRng.sample(Alphanumeric) as char)
+        .collect();
+    key.try_into()
+        .expect("64 characters are a valid session key")
+}
+
+fn hash_session_key(session_key: &SessionKey) -> String {
+    general_purpose::STANDARD.encode(Sha256::digest(session_key.as_ref()))
+}
+
+fn expires_at(ttl: &Duration) -> chrono::DateTime<chrono::Utc> {
+    chrono::Utc::now() + chrono::Duration::seconds(ttl.whole_seconds())
+}
+
+/// A string value of the session state, which stores every value as JSON.
+fn state_value(session_state: &SessionState, key: &str) -> anyhow::Result<Option<String>> {
+    session_state
+        .get(key)
+        .map(|value| {
+            serde_json::from_str(value).with_context(|| format!("Invalid session value {key}"))
+        })
+        .transpose()
+}
@@ -0,0 +1,456 @@
+use std::fmt;
+
+use anyhow::Context;
+use chrono::{Locale, NaiveDate};
+use sqlx::{PgExecutor, PgPool};
+
+use crate::{CoverStyle, Period};
+
+pub const DEFAULT_NAME_TEMPLATE: &str = "{year}-{month} ({month_short}) BOTM";
+pub const DEFAULT_DESCRIPTION_TEMPLATE: &str =
+    "Bangers of the month for {month_name} {year}, (generated on {generated})";
+pub const DEFAULT_LOCALE: &str = "en_US";
+pub const DEFAULT_TIME_RANGE: &str = "short_term";
+pub const DEFAULT_TRACK_COUNT: i32 = 50;
+pub const DEFAULT_NOTIFICATIONS: &str = "all";
+/// Spotify doesn't rank more top tracks than this.
+pub const MAX_TRACK_COUNT: i32 = 100;
+
+/// Longest playlist name Spotify shows without cutting it off.
+const MAX_NAME_LENGTH: usize = 100;
+/// Longest playlist description Spotify accepts.
+const MAX_DESCRIPTION_LENGTH: usize = 300;
+
+/// Placeholders that can be used in the name and description templates.
+pub const PLACEHOLDERS: &[(&str, &str)] = &[
+    ("year", "Year, e.g. 2026"),
+    ("month", "Month number, e.g. 09"),
+    ("month_name", "Month name, e.g. September"),
+    ("month_short", "Short month name, e.g. Sep"),
+    ("month_local", "Month name in your language"),
+    ("track_count", "Number of tracks in the playlist"),
+    (
+        "generated",
+        "Date the playlist was generated, e.g. 2026-10-01",
+    ),
+];
+
+/// Spotify time ranges for the top tracks of the monthly playlist.
+pub const TIME_RANGES: &[(&str, &str)] = &[
+    ("short_term", "Last ~4 weeks"),
+    ("medium_term", "Last ~6 months"),
+    ("long_term", "Last ~year"),
+];
+
+/// What the start page tells the user about the BOTM of last month.
+pub const NOTIFICATIONS: &[(&str, &str)] = &[
+    ("all", "Whether it is ready or failed"),
+    ("failures", "Only if it failed"),
+    ("none", "Nothing"),
+];
+
+/// Generation preferences of a user, stored in `user_settings`.
+///
+/// Users without a row get the defaults, which match how BOTMs were always named.
+#[derive(serde::Serialize, Debug, Clone)]
+pub struct UserSettings {
+    pub name_template: String,
+    pub description_template: String,
+    /// Locale for `{month_local}`, e.g. `de_DE`.
+    pub locale: String,
+    /// Spotify time range of the monthly playlist, one of [`TIME_RANGES`].
+    pub time_range: String,
+    pub track_count: i32,
+    /// Also generate a playlist of the `medium_term` top tracks at the end of every quarter.
+    pub quarterly_playlist: bool,
+    /// Also generate a playlist of the `long_term` top tracks at the end of every year.
+    pub yearly_playlist: bool,
+    pub public: bool,
+    /// Collaborative playlists have to be private.
+    pub collaborative: bool,
+    /// One of the [`CoverStyle`]s.
+    pub cover_style: String,
+    /// One of the [`NOTIFICATIONS`].
+    pub notifications: String,
+}
+
+impl Default for UserSettings {
+    fn default() -> Self {
+        Self {
+            name_template: DEFAULT_NAME_TEMPLATE.to_owned(),
+            description_template: DEFAULT_DESCRIPTION_TEMPLATE.to_owned(),
+            locale: DEFAULT_LOCALE.to_owned(),
+            time_range: DEFAULT_TIME_RANGE.to_owned(),
+            track_count: DEFAULT_TRACK_COUNT,
+            quarterly_playlist: false,
+            yearly_playlist: false,
+            public: true,
+            collaborative: false,
+            cover_style: CoverStyle::Gradient.as_str().to_owned(),
+            notifications: DEFAULT_NOTIFICATIONS.to_owned(),
+        }
+    }
+}
+
+impl UserSettings {
+    pub async fn load(pg_pool: &PgPool, spotify_id: &str) -> anyhow::Result<Self> {
+        let settings = sqlx::query_as!(
+            UserSettings,
+            r#"SELECT name_template, description_template, locale, time_range, track_count, quarterly_playlist, yearly_playlist,
+                    public, collaborative, cover_style, notifications
+                FROM user_settings WHERE spotify_id = $1"#,
+            spotify_id
+        )
+        .fetch_optional(pg_pool)
+        .await
+        .with_context(|| format!("Failed to load settings for user: {spotify_id}"))?;
+        Ok(settings.unwrap_or_default())
+    }
+
+    /// Saves the settings with `executor`, which can be a transaction saving more of the user.
+    pub async fn save(
+        &self,
+        executor: impl PgExecutor<'_>,
+        spotify_id: &str,
+    ) -> anyhow::Result<()> {
+        sqlx::query!(
+            r#"INSERT INTO user_settings (spotify_id, name_template, description_template, locale, time_range, track_count, quarterly_playlist, yearly_playlist,
+                    public, collaborative, cover_style, notifications)
+                VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12)
+                ON CONFLICT (spotify_id) DO UPDATE SET name_template = $2, description_template = $3, locale = $4,
+                    time_range = $5, track_count = $6, quarterly_playlist = $7, yearly_playlist = $8,
+                    public = $9, collaborative = $10, cover_style = $11, notifications = $12"#,
+            spotify_id,
+            self.name_template,
+            self.description_template,
+            self.locale,
+            self.time_range,
+            self.track_count,
+            self.quarterly_playlist,
+            self.yearly_playlist,
+            self.public,
+            self.collaborative,
+            self.cover_style,
+            self.notifications,
+        )
+        .execute(executor)
+        .await
+        .with_context(|| format!("Failed to save settings for user: {spotify_id}"))?;
+        Ok(())
+    }
+
+    /// Checks the settings before saving them.
+    ///
+    /// The error is meant to be shown to the user.
+    pub fn validate(&self) -> Result<(), String> {
+        if !TIME_RANGES
+            .iter()
+            .any(|(range, _)| *range == self.time_range)
+        {
+            return Err(format!("Unknown time range \"{}\".", self.time_range));
+        }
+        if !(1..=MAX_TRACK_COUNT).contains(&self.track_count) {
+            return Err(format!(
+                "The number of tracks must be between 1 and {MAX_TRACK_COUNT}."
+            ));
+        }
+        if CoverStyle::parse(&self.cover_style).is_none() {
+            return Err(format!("Unknown cover style \"{}\".", self.cover_style));
+        }
+        if !NOTIFICATIONS
+            .iter()
+            .any(|(notifications, _)| *notifications == self.notifications)
+        {
+            return Err(format!("Unknown notifications \"{}\".", self.notifications));
+        }
+        if self.public && self.collaborative {
+            return Err("Collaborative playlists can't be public.".to_owned());
+        }
+        let locale = self
+            .locale
+            .parse::<Locale>()
+            .map_err(|_| format!("Unknown locale \"{}\".", self.locale))?;
+
+        // Render with the longest values to make sure the result fits
+        let values = TemplateValues {
+            month: NaiveDate::from_ymd_opt(2026, 9, 1).expect("Valid date"),
+            track_count: MAX_TRACK_COUNT as usize,
+            generated: NaiveDate::from_ymd_opt(2026, 9, 30).expect("Valid date"),
+            locale,
+        };
+        let name = render_template(&self.name_template, &values)
+            .map_err(|e| format!("Invalid playlist name: {e}."))?;
+        if name.trim().is_empty() {
+            return Err("The playlist name must not be empty.".to_owned());
+        }
+        if name.chars().count() > MAX_NAME_LENGTH {
+            return Err(format!(
+                "The playlist name must not be longer than {MAX_NAME_LENGTH} characters."
+            ));
+        }
+        let description = render_template(&self.description_template, &values)
+            .map_err(|e| format!("Invalid playlist description: {e}."))?;
+        if description.chars().count() > MAX_DESCRIPTION_LENGTH {
+            return Err(format!(
+                "The playlist description must not be longer than {MAX_DESCRIPTION_LENGTH} characters."
+            ));
+        }
+        Ok(())
+    }
+
+    /// The periods the user gets playlists for.
+    pub fn periods(&self) -> impl Iterator<Item = Period> {
+        [
+            Some(Period::Month),
+            self.quarterly_playlist.then_some(Period::Quarter),
+            self.yearly_playlist.then_some(Period::Year),
+        ]
+        .into_iter()
+        .flatten()
+    }
+
+    /// The OAuth scopes needed to generate playlists with these settings.
+    pub fn required_scopes(&self) -> Vec<&'static str> {
+        let playlist_scope = if self.public {
+            "playlist-modify-public"
+        } else {
+            "playlist-modify-private"
+        };
+        let mut scopes = vec!["user-top-read", playlist_scope];
+        if self.cover_style() != CoverStyle::None {
+            scopes.push("ugc-image-upload");
+        }
+        scopes
+    }
+
+    /// The style of the uploaded covers, falling back to the default one.
+    pub fn cover_style(&self) -> CoverStyle {
+        CoverStyle::parse(&self.cover_style).unwrap_or(CoverStyle::Gradient)
+    }
+
+    /// The required scopes that aren't in `granted`, a space separated list of scopes.
+    pub fn missing_scopes(&self, granted: &str) -> Vec<&'static str> {
+        let granted: Vec<&str> = granted.split_whitespace().collect();
+        self.required_scopes()
+            .into_iter()
+            .filter(|scope| !granted.contains(scope))
+            .collect()
+    }
+
+    /// Whether the start page tells the user about a BOTM that is ready or not created yet,
+    /// failures are shown unless the user chose no notifications at all.
+    pub fn notify(&self, failed: bool) -> bool {
+        match self.notifications.as_str() {
+            "none" => false,
+            "failures" => failed,
+            _ => true,
+        }
+    }
+
+    /// The locale for `{month_local}`, falling back to the default one.
+    pub fn locale(&self) -> Locale {
+        self.locale.parse().unwrap_or(Locale::en_US)
+    }
+}
+
+/// Values filled into the placeholders of a template.
+#[derive(Debug, Clone, Copy)]
+pub struct TemplateValues {
+    /// First day of the month the playlist is for.
+    pub month: NaiveDate,
+    pub track_count: usize,
+    pub generated: NaiveDate,
+    pub locale: Locale,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum TemplateError {
+    UnknownPlaceholder(String),
+    UnclosedPlaceholder,
+    UnopenedPlaceholder,
+}
+
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TemplateError::UnknownPlaceholder(name) => write!(f, "unknown placeholder {{{name}}}"),
+            TemplateError::UnclosedPlaceholder => write!(f, "a '{{' is missing its '}}'"),
+            TemplateError::UnopenedPlaceholder => write!(f, "a '}}' is missing its '{{'"),
+        }
+    }
+}
+
+impl std::error::Error for TemplateError {}
+
+/// Replaces the `{placeholder}`s in `template` with `values`.
+pub fn render_template(template: &str, values: &TemplateValues) -> Result<String, TemplateError> {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find(['{', '}']) {
+        if rest[start..].starts_with('}') {
+            return Err(TemplateError::UnopenedPlaceholder);
+        }
+        rendered.push_str(&rest[..start]);
+        let Some(len) = rest[start + 1..].find('}') else {
+            return Err(TemplateError::UnclosedPlaceholder);
+        };
+        let name = &rest[start + 1..start + 1 + len];
+        let value = match name {
+            "year" => values.month.format("%Y").to_string(),
+            "month" => values.month.format("%m").to_string(),
+            "month_name" => values.month.format("%B").to_string(),
+            "month_short" => values.month.format("%b").to_string(),
+            "month_local" => values
+                .month
+                .format_localized("%B", values.locale)
+                .to_string(),
+            "track_count" => values.track_count.to_string(),
+            "generated" => values.generated.format("%F").to_string(),
+            _ => return Err(TemplateError::UnknownPlaceholder(name.to_owned())),
+        };
+        rendered.push_str(&value);
+        rest = &rest[start + len + 2..];
+    }
+    rendered.push_str(rest);
+    Ok(rendered)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn values() -> TemplateValues {
+        TemplateValues {
+            month: NaiveDate::from_ymd_opt(2026, 9, 1).unwrap(),
+            track_count: 50,
+            generated: NaiveDate::from_ymd_opt(2026, 10, 1).unwrap(),
+            locale: Locale::de_DE,
+        }
+    }
+
+    #[test]
+    fn template_fills_in_every_placeholder() {
+        let rendered = render_template(
+            "{year}-{month} {month_name} {month_short} {month_local} {track_count} {generated}",
+            &values(),
+        );
+
+        assert_eq!(
+            rendered.unwrap(),
+            "2026-09 September Sep September 50 2026-10-01"
+        );
+    }
+
+    #[test]
+    fn template_without_placeholders_is_kept() {
+        assert_eq!(render_template("BOTM", &values()).unwrap(), "BOTM");
+        assert_eq!(render_template("", &values()).unwrap(), "");
+    }
+
+    #[test]
+    fn template_errors() {
+        let cases = [
+            ("{", TemplateError::UnclosedPlaceholder),
+            ("BOTM {year", TemplateError::UnclosedPlaceholder),
+            ("}", TemplateError::UnopenedPlaceholder),
+            ("year} BOTM", TemplateError::UnopenedPlaceholder),
+            ("{year}}", TemplateError::UnopenedPlaceholder),
+            (
+                "{nope}",
+                TemplateError::UnknownPlaceholder("nope".to_owned()),
+            ),
+            ("{}", TemplateError::UnknownPlaceholder(String::new())),
+            (
+                "{{year}}",
+                TemplateError::UnknownPlaceholder("{year".to_owned()),
+            ),
+        ];
+        for (template, err) in cases {
+            assert_eq!(render_template(template, &values()), Err(err), "{template}");
+        }
+    }
+
+    #[test]
+    fn defaults_are_valid() {
+        assert_eq!(UserSettings::default().validate(), Ok(()));
+    }
+
+    type Change = fn(&mut UserSettings);
+
+    #[test]
+    fn invalid_settings_are_rejected() {
+        let cases: [(Change, &str); 7] = [
+            (|s| s.track_count = 0, "The number of tracks"),
+            (
+                |s| s.track_count = MAX_TRACK_COUNT + 1,
+                "The number of tracks",
+            ),
+            (
+                |s| s.time_range = "forever".to_owned(),
+                "Unknown time range",
+            ),
+            (
+                |s| s.notifications = "loud".to_owned(),
+                "Unknown notifications",
+            ),
+            (|s| s.collaborative = true, "Collaborative playlists"),
+            (|s| s.name_template = " ".to_owned(), "must not be empty"),
+            (
+                |s| s.name_template = "{month_name}".repeat(12),
+                "longer than",
+            ),
+        ];
+        for (change, message) in cases {
+            let mut settings = UserSettings::default();
+            change(&mut settings);
+
+            let err = settings.validate().unwrap_err();
+
+            assert!(err.contains(message), "{err}");
+        }
+    }
+
+    #[test]
+    fn missing_scopes_follow_visibility_and_cover() {
+        let settings = UserSettings::default();
+        assert_eq!(
+            settings.missing_scopes("user-top-read playlist-modify-public"),
+            ["ugc-image-upload"]
+        );
+        assert!(settings
+            .missing_scopes("ugc-image-upload user-top-read playlist-modify-public")
+            .is_empty());
+
+        let settings = UserSettings {
+            public: false,
+            cover_style: CoverStyle::None.as_str().to_owned(),
+            ..Default::default()
+        };
+        assert_eq!(
+            settings.missing_scopes("user-top-read playlist-modify-public"),
+            ["playlist-modify-private"]
+        );
+        assert_eq!(
+            settings.missing_scopes(""),
+            ["user-top-read", "playlist-modify-private"]
+        );
+    }
+
+    #[test]
+    fn notify_follows_the_notification_choice() {
+        let cases = [
+            ("all", true, true),
+            ("failures", false, true),
+            ("none", false, false),
+        ];
+        for (notifications, ready, failed) in cases {
+            let settings = UserSettings {
+                notifications: notifications.to_owned(),
+                ..Default::default()
+            };
+
+            assert_eq!(settings.notify(false), ready, "{notifications}");
+            assert_eq!(settings.notify(true), failed, "{notifications}");
+        }
+    }
+}
@@ -0,0 +1,404 @@
+use std::time::Duration;
+
+use anyhow::Context;
+use async_trait::async_trait;
+use base64::{engine::general_purpose, Engine};
+use url::Url;
+
+use crate::{send_with_retry, Image, RateLimiter, RetryConfig, SendError, SpotifyConfig, UserInfo};
+
+/// Most top tracks Spotify returns per request.
+pub const TOP_TRACKS_PAGE_SIZE: usize = 50;
+/// Most tracks Spotify accepts per add or replace request.
+pub const MAX_TRACKS_PER_REQUEST: usize = 100;
+
+/// The parts of the Spotify Web API BOTM uses.
+///
+/// Every call takes the access token of the user it is made for.
+#[async_trait]
+pub trait SpotifyApi: Send + Sync {
+    /// Sends a request of the `oauth2` crate to the accounts service,
+    /// to be used with `request_async` for exchanging codes and tokens.
+    async fn oauth_http_client(
+        &self,
+        request: oauth2::HttpRequest,
+    ) -> Result<oauth2::HttpResponse, SendError>;
+
+    /// The user the access token belongs to.
+    async fn me(&self, access_token: &str) -> anyhow::Result<CurrentUser>;
+
+    /// The public profile of `user_id`.
+    async fn user_profile(&self, access_token: &str, user_id: &str) -> anyhow::Result<UserInfo>;
+
+    /// A single page of up to [`TOP_TRACKS_PAGE_SIZE`] top tracks.
+    async fn top_tracks(
+        &self,
+        access_token: &str,
+        time_range: &str,
+        limit: usize,
+        offset: usize,
+    ) -> anyhow::Result<TopTracksPage>;
+
+    /// Creates a playlist for `user_id` and returns its id.
+    async fn create_playlist(
+        &self,
+        access_token: &str,
+        user_id: &str,
+        details: &PlaylistDetails<'_>,
+    ) -> anyhow::Result<String>;
+
+    async fn update_playlist(
+        &self,
+        access_token: &str,
+        playlist_id: &str,
+        details: &PlaylistDetails<'_>,
+    ) -> anyhow::Result<()>;
+
+    /// Adds up to [`MAX_TRACKS_PER_REQUEST`] tracks to the playlist at `position`.
+    async fn add_tracks(
+        &self,
+        access_token: &str,
+        playlist_id: &str,
+        uris: &[&str],
+        position: usize,
+    ) -> anyhow::Result<()>;
+
+    /// Replaces all tracks of the playlist with up to [`MAX_TRACKS_PER_REQUEST`] tracks.
+    async fn replace_tracks(
+        &self,
+        access_token: &str,
+        playlist_id: &str,
+        uris: &[&str],
+    ) -> anyhow::Result<()>;
+
+    /// Sets the cover of the playlist to the `jpeg`.
+    async fn upload_cover(
+        &self,
+        access_token: &str,
+        playlist_id: &str,
+        jpeg: &[u8],
+    ) -> anyhow::Result<()>;
+}
+
+/// Gets up to `count` top tracks, paging through the top tracks with `offset`.
+pub async fn fetch_top_tracks(
+    spotify_api: &dyn SpotifyApi,
+    access_token: &str,
+    time_range: &str,
+    count: usize,
+) -> anyhow::Result<Vec<Track>> {
+    let mut items = Vec::with_capacity(count);
+    while items.len() < count {
+        let limit = (count - items.len()).min(TOP_TRACKS_PAGE_SIZE);
+        let page = spotify_api
+            .top_tracks(access_token, time_range, limit, items.len())
+            .await?;
+
+        let last_page = page.items.len() < limit || page.next.is_none();
+        items.extend(page.items);
+        if last_page {
+            break;
+        }
+    }
+    Ok(items)
+}
+
+/// [`SpotifyApi`] talking to the Web API at `api_base_url` of the [`SpotifyConfig`],
+/// retrying rate limited and failed requests.
+///
+/// All requests share one [`RateLimiter`], so share a single instance across the app.
+pub struct ReqwestSpotifyApi {
+    base_url: Url,
+    client: reqwest::Client,
+    /// Client for the accounts service, which must not follow redirects.
+    oauth_client: reqwest::Client,
+    retry: RetryConfig,
+    rate_limiter: RateLimiter,
+}
+
+impl ReqwestSpotifyApi {
+    pub fn new(
+        base_url: Url,
+        retry: RetryConfig,
+        rate_limiter: RateLimiter,
+    ) -> anyhow::Result<Self> {
+        let oauth_client = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .context("Failed to build oauth client")?;
+        Ok(Self {
+            base_url,
+            client: reqwest::Client::new(),
+            oauth_client,
+            retry,
+            rate_limiter,
+        })
+    }
+
+    pub fn from_config(spotify_config: &SpotifyConfig) -> anyhow::Result<Self> {
+        Self::new(
+            spotify_config.api_base_url()?,
+            spotify_config.retry.clone(),
+            RateLimiter::new(spotify_config.max_requests_per_second),
+        )
+    }
+
+    async fn send(&self, request: reqwest::RequestBuilder) -> Result<reqwest::Response, SendError> {
+        send_with_retry(&self.retry, &self.rate_limiter, request).await
+    }
+
+    fn url(&self, path: &str) -> anyhow::Result<Url> {
+        self.base_url
+            .join(path)
+            .with_context(|| format!("Failed to parse Spotify url for {path}"))
+    }
+}
+
+#[async_trait]
+impl SpotifyApi for ReqwestSpotifyApi {
+    async fn oauth_http_client(
+        &self,
+        request: oauth2::HttpRequest,
+    ) -> Result<oauth2::HttpResponse, SendError> {
+        let mut request_builder = self
+            .oauth_client
+            .request(request.method, request.url.as_str())
+            .body(request.body);
+        for (name, value) in &request.headers {
+            request_builder = request_builder.header(name, value);
+        }
+        let response = self.send(request_builder).await?;
+        let status_code = response.status();
+        let headers = response.headers().to_owned();
+        let body = response.bytes().await.map_err(SendError::Request)?;
+        Ok(oauth2::HttpResponse {
+            status_code,
+            headers,
+            body: body.to_vec(),
+        })
+    }
+
+    async fn me(&self, access_token: &str) -> anyhow::Result<CurrentUser> {
+        self.send(
+            self.client
+                .get(self.url("me")?)
+                .bearer_auth(access_token)
+                .timeout(Duration::from_secs(10)),
+        )
+        .await
+        .context("Failed to send me request")?
+        .error_for_status()
+        .context("Error status returned")?
+        .json()
+        .await
+        .context("Failed to parse me response")
+    }
+
+    async fn user_profile(&self, access_token: &str, user_id: &str) -> anyhow::Result<UserInfo> {
+        self.send(
+            self.client
+                .get(self.url(&format!("users/{user_id}"))?)
+                .bearer_auth(access_token),
+        )
+        .await
+        .context("Failed to send user info request")?
+        .error_for_status()
+        .context("Error status returned")?
+        .json()
+        .await
+        .context("Failed to deserialize to user info")
+    }
+
+    async fn top_tracks(
+        &self,
+        access_token: &str,
+        time_range: &str,
+        limit: usize,
+        offset: usize,
+    ) -> anyhow::Result<TopTracksPage> {
+        self.send(
+            self.client
+                .get(self.url("me/top/tracks")?)
+                .bearer_auth(access_token)
+                .query(&[
+                    ("time_range", time_range),
+                    ("limit", &limit.to_string()),
+                    ("offset", &offset.to_string()),
+                ]),
+        )
+        .await
+        .context("Failed to get top tracks")?
+        .error_for_status()
+        .context("Error status returned")?
+        .json()
+        .await
+        .context("Failed to parse top tracks response")
+    }
+
+    async fn create_playlist(
+        &self,
+        access_token: &str,
+        user_id: &str,
+        details: &PlaylistDetails<'_>,
+    ) -> anyhow::Result<String> {
+        let response = self
+            .send(
+                self.client
+                    .post(self.url(&format!("users/{user_id}/playlists"))?)
+                    .json(details)
+                    .bearer_auth(access_token),
+            )
+            .await
+            .context("Failed to send create playlist")?
+            .error_for_status()
+            .context("Error status returned")?
+            .json::<CreatePlaylistResponse>()
+            .await
+            .context("Failed to parse playlist create response")?;
+        tracing::debug!("Create playlist: {:?}", response);
+        Ok(response.id)
+    }
+
+    async fn update_playlist(
+        &self,
+        access_token: &str,
+        playlist_id: &str,
+        details: &PlaylistDetails<'_>,
+    ) -> anyhow::Result<()> {
+        self.send(
+            self.client
+                .put(self.url(&format!("playlists/{playlist_id}"))?)
+                .json(details)
+                .bearer_auth(access_token),
+        )
+        .await
+        .context("Failed to send change playlist details")?
+        .error_for_status()
+        .context("Error status returned")?;
+        Ok(())
+    }
+
+    async fn add_tracks(
+        &self,
+        access_token: &str,
+        playlist_id: &str,
+        uris: &[&str],
+        position: usize,
+    ) -> anyhow::Result<()> {
+        let add_tracks_body = AddTracksBody {
+            uris,
+            position: position as i32,
+        };
+        tracing::debug!("Add tracks body: {:#?}", add_tracks_body);
+        self.send(
+            self.client
+                .post(self.url(&format!("playlists/{playlist_id}/tracks"))?)
+                .json(&add_tracks_body)
+                .bearer_auth(access_token),
+        )
+        .await
+        .context("Failed to send playlist add")?
+        .error_for_status()
+        .context("Error status returned")?;
+        Ok(())
+    }
+
+    async fn replace_tracks(
+        &self,
+        access_token: &str,
+        playlist_id: &str,
+        uris: &[&str],
+    ) -> anyhow::Result<()> {
+        let replace_tracks_body = ReplaceTracksBody { uris };
+        tracing::debug!("Replace tracks body: {:#?}", replace_tracks_body);
+        self.send(
+            self.client
+                .put(self.url(&format!("playlists/{playlist_id}/tracks"))?)
+                .json(&replace_tracks_body)
+                .bearer_auth(access_token),
+        )
+        .await
+        .context("Failed to send playlist replace")?
+        .error_for_status()
+        .context("Error status returned")?;
+        Ok(())
+    }
+
+    async fn upload_cover(
+        &self,
+        access_token: &str,
+        playlist_id: &str,
+        jpeg: &[u8],
+    ) -> anyhow::Result<()> {
+        // Spotify wants the JPEG base64 encoded as body
+        self.send(
+            self.client
+                .put(self.url(&format!("playlists/{playlist_id}/images"))?)
+                .header(reqwest::header::CONTENT_TYPE, "image/jpeg")
+                .body(general_purpose::STANDARD.encode(jpeg))
+                .bearer_auth(access_token),
+        )
+        .await
+        .context("Failed to send cover upload")?
+        .error_for_status()
+        .context("Error status returned")?;
+        Ok(())
+    }
+}
+
+/// Name and flags of a playlist, sent when creating or updating it.
+#[derive(serde::Serialize, Debug)]
+pub struct PlaylistDetails<'a> {
+    pub name: &'a str,
+    pub description: &'a str,
+    pub public: bool,
+    pub collaborative: bool,
+}
+
+#[derive(serde::Deserialize, Debug)]
+pub struct CurrentUser {
+    pub id: String,
+}
+
+#[derive(serde::Deserialize, Debug)]
+pub struct TopTracksPage {
+    pub items: Vec<Track>,
+    /// Url of the next page, `None` on the last one.
+    pub next: Option<String>,
+}
+
+#[derive(serde::Deserialize, Debug)]
+pub struct Track {
+    pub uri: String,
+    pub name: String,
+    pub artists: Vec<Artist>,
+    pub album: Album,
+}
+
+#[derive(serde::Deserialize, Debug)]
+pub struct Artist {
+    pub name: String,
+}
+
+#[derive(serde::Deserialize, Debug)]
+pub struct Album {
+    pub name: String,
+    /// Widest first.
+    pub images: Vec<Image>,
+}
+
+#[derive(serde::Serialize, Debug)]
+struct AddTracksBody<'a> {
+    uris: &'a [&'a str],
+    position: i32,
+}
+
+#[derive(serde::Serialize, Debug)]
+struct ReplaceTracksBody<'a> {
+    uris: &'a [&'a str],
+}
+
+#[derive(serde::Deserialize, Debug)]
+struct CreatePlaylistResponse {
+    id: String,
+}
@@ -0,0 +1,213 @@
+use std::{collections::HashMap, sync::Arc};
+
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    Aes256Gcm, Nonce,
+};
+use anyhow::{anyhow, bail, Context};
+use base64::{engine::general_purpose, Engine};
+use secrecy::{ExposeSecret, SecretString};
+use sqlx::PgPool;
+
+use crate::TokenEncryptionConfig;
+
+/// Length of the random nonce stored in front of every ciphertext.
+const NONCE_LEN: usize = 12;
+
+/// Which token of a user is encrypted, bound to the ciphertext
+/// so a token can't be swapped into another column or user.
+#[derive(Debug, Clone, Copy)]
+pub enum TokenKind {
+    Refresh,
+    Access,
+}
+
+impl TokenKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            TokenKind::Refresh => "refresh_token",
+            TokenKind::Access => "access_token",
+        }
+    }
+}
+
+/// Encrypts the Spotify tokens of users with AES-256-GCM before they are stored.
+///
+/// Tokens are stored as base64 of the nonce followed by the ciphertext,
+/// `users.token_key_id` names the key they were encrypted with.
+#[derive(Clone)]
+pub struct TokenCipher {
+    current_key_id: String,
+    keys: Arc<HashMap<String, Aes256Gcm>>,
+}
+
+impl TokenCipher {
+    pub fn from_config(config: &TokenEncryptionConfig) -> anyhow::Result<Self> {
+        let keys = config
+            .keys
+            .iter()
+            .map(|(key_id, key)| {
+                let key = general_purpose::STANDARD
+                    .decode(key.expose_secret())
+                    .with_context(|| format!("Failed to base64-decode token key {key_id}"))?;
+                let cipher = Aes256Gcm::new_from_slice(&key)
+                    .map_err(|_| anyhow!("Token key {key_id} has to be 32 bytes long"))?;
+                Ok((key_id.clone(), cipher))
+            })
+            .collect::<anyhow::Result<HashMap<_, _>>>()?;
+        if !keys.contains_key(&config.current_key_id) {
+            bail!("No token key with the current id {}", config.current_key_id);
+        }
+        Ok(Self {
+            current_key_id: config.current_key_id.clone(),
+            keys: Arc::new(keys),
+        })
+    }
+
+    /// Id of the key [`TokenCipher::encrypt`] uses.
+    pub fn key_id(&self) -> &str {
+        &self.current_key_id
+    }
+
+    pub fn encrypt(
+        &self,
+        spotify_id: &str,
+        kind: TokenKind,
+        token: &str,
+    ) -> anyhow::Result<String> {
+        let cipher = &self.keys[&self.current_key_id];
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let aad = associated_data(spotify_id, kind);
+        let ciphertext = cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: token.as_bytes(),
+                    aad: aad.as_bytes(),
+                },
+            )
+            .map_err(|_| anyhow!("Failed to encrypt {} of {spotify_id}", kind.as_str()))?;
+        let mut stored = nonce.to_vec();
+        stored.extend(ciphertext);
+        Ok(general_purpose::STANDARD.encode(stored))
+    }
+
+    /// Decrypts a stored token, `key_id` is `None` for tokens stored in plaintext.
+    pub fn decrypt(
+        &self,
+        spotify_id: &str,
+        kind: TokenKind,
+        key_id: Option<&str>,
+        stored: &str,
+    ) -> anyhow::Result<SecretString> {
+        let Some(key_id) = key_id else {
+            return Ok(stored.to_owned().into());
+        };
+        let cipher = self
+            .keys
+            .get(key_id)
+            .with_context(|| format!("No token key with id {key_id}"))?;
+        let stored = general_purpose::STANDARD.decode(stored).with_context(|| {
+            format!("Failed to base64-decode {} of {spotify_id}", kind.as_str())
+        })?;
+        if stored.len() < NONCE_LEN {
+            bail!("Stored {} of {spotify_id} is too short", kind.as_str());
+        }
+        let (nonce, ciphertext) = stored.split_at(NONCE_LEN);
+        let aad = associated_data(spotify_id, kind);
+        let token = cipher
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: aad.as_bytes(),
+                },
+            )
+            .map_err(|_| anyhow!("Failed to decrypt {} of {spotify_id}", kind.as_str()))?;
+        let token = String::from_utf8(token)
+            .with_context(|| format!("Decrypted {} of {spotify_id} is no UTF8", kind.as_str()))?;
+        Ok(token.into())
+    }
+}
+
+fn associated_data(spotify_id: &str, kind: TokenKind) -> String {
+    format!("{spotify_id}:{}", kind.as_str())
+}
+
+/// Encrypts the tokens stored before encryption was introduced, returns how many users were updated.
+pub async fn encrypt_plaintext_tokens(
+    pg_pool: &PgPool,
+    cipher: &TokenCipher,
+) -> anyhow::Result<usize> {
+    let spotify_ids =
+        sqlx::query_scalar!("SELECT spotify_id FROM users WHERE token_key_id IS NULL")
+            .fetch_all(pg_pool)
+            .await
+            .context("Failed to get users with plaintext tokens")?;
+    reencrypt_users(pg_pool, cipher, &spotify_ids).await
+}
+
+/// Encrypts all tokens that aren't encrypted with the current key yet with it,
+/// returns how many users were updated.
+///
+/// Once done, keys other than the current one can be removed from the configuration.
+pub async fn reencrypt_tokens(pg_pool: &PgPool, cipher: &TokenCipher) -> anyhow::Result<usize> {
+    let spotify_ids = sqlx::query_scalar!(
+        "SELECT spotify_id FROM users WHERE token_key_id IS DISTINCT FROM $1",
+        cipher.key_id()
+    )
+    .fetch_all(pg_pool)
+    .await
+    .context("Failed to get users to re-encrypt")?;
+    reencrypt_users(pg_pool, cipher, &spotify_ids).await
+}
+
+async fn reencrypt_users(
+    pg_pool: &PgPool,
+    cipher: &TokenCipher,
+    spotify_ids: &[String],
+) -> anyhow::Result<usize> {
+    let mut reencrypted = 0;
+    for spotify_id in spotify_ids {
+        // Lock the row, so a concurrent refresh doesn't store tokens in between
+        let mut transaction = pg_pool
+            .begin()
+            .await
+            .context("Failed to begin transaction")?;
+        let Some(user) = sqlx::query!(
+            "SELECT refresh_token, access_token, token_key_id FROM users WHERE spotify_id = $1 FOR UPDATE",
+            spotify_id
+        )
+        .fetch_optional(&mut *transaction)
+        .await
+        .with_context(|| format!("Failed to lock tokens of user: {spotify_id}"))?
+        else {
+            continue;
+        };
+        if user.token_key_id.as_deref() == Some(cipher.key_id()) {
+            continue;
+        }
+
+        let key_id = user.token_key_id.as_deref();
+        let refresh_token =
+            cipher.decrypt(spotify_id, TokenKind::Refresh, key_id, &user.refresh_token)?;
+        let access_token =
+            cipher.decrypt(spotify_id, TokenKind::Access, key_id, &user.access_token)?;
+        sqlx::query!(
+            "UPDATE users SET refresh_token = $1, access_token = $2, token_key_id = $3 WHERE spotify_id = $4",
+            cipher.encrypt(spotify_id, TokenKind::Refresh, refresh_token.expose_secret())?,
+            cipher.encrypt(spotify_id, TokenKind::Access, access_token.expose_secret())?,
+            cipher.key_id(),
+            spotify_id
+        )
+        .execute(&mut *transaction)
+        .await
+        .with_context(|| format!("Failed to store re-encrypted tokens of user: {spotify_id}"))?;
+        transaction
+            .commit()
+            .await
+            .context("Failed to commit transaction")?;
+        reencrypted += 1;
+    }
+    Ok(reencrypted)
+}
@@ -0,0 +1,43 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Spaces out the requests to Spotify evenly,
+/// so that all users generated for at the same time together stay below a request rate.
+#[derive(Debug)]
+pub struct RateLimiter {
+    interval: Duration,
+    next_slot: Mutex<Instant>,
+}
+
+impl RateLimiter {
+    /// A limiter for `requests_per_second`, `0` doesn't limit at all.
+    pub fn new(requests_per_second: u32) -> Self {
+        let interval = match requests_per_second {
+            0 => Duration::ZERO,
+            rate => Duration::from_secs(1) / rate,
+        };
+        Self {
+            interval,
+            next_slot: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Waits for the next free slot to send a request in.
+    pub async fn acquire(&self) {
+        if self.interval.is_zero() {
+            return;
+        }
+        let wait = {
+            let mut next_slot = self.next_slot.lock().expect("Rate limiter lock poisoned");
+            let now = Instant::now();
+            let slot = (*next_slot).max(now);
+            *next_slot = slot + self.interval;
+            slot - now
+        };
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
@@ -0,0 +1,153 @@
+use std::{fmt, time::Duration};
+
+use rand::Rng;
+use reqwest::{header, RequestBuilder, Response, StatusCode};
+
+use crate::RateLimiter;
+
+/// How often and how long requests to Spotify are retried
+/// when Spotify answers with 429 Too Many Requests or a 5xx error, or can't be reached.
+#[derive(serde::Deserialize, Debug, Clone)]
+pub struct RetryConfig {
+    /// Attempts per request, including the first one.
+    pub max_attempts: u32,
+    /// Backoff before the first retry, doubled for every further retry.
+    pub initial_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+    /// Longest time a single request may spend waiting between its attempts.
+    pub max_wait_ms: u64,
+}
+
+impl RetryConfig {
+    /// The jittered exponential backoff before retry number `retry`, starting at 0.
+    fn backoff(&self, retry: u32) -> Duration {
+        let backoff = self
+            .initial_backoff_ms
+            .saturating_mul(2u64.saturating_pow(retry))
+            .min(self.max_backoff_ms);
+        // Wait at least half of the backoff so retries of parallel requests spread out
+        let jittered = backoff / 2 + rand::thread_rng().gen_range(0..=backoff / 2);
+        Duration::from_millis(jittered)
+    }
+}
+
+/// Spotify kept answering with a retryable status until the [`RetryConfig`] budget was used up.
+#[derive(Debug)]
+pub struct RetriesExhausted {
+    pub attempts: u32,
+    /// The status of the last attempt.
+    pub status: StatusCode,
+}
+
+impl fmt::Display for RetriesExhausted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Spotify answered {} after {} attempts, giving up",
+            self.status, self.attempts
+        )
+    }
+}
+
+impl std::error::Error for RetriesExhausted {}
+
+#[derive(Debug)]
+pub enum SendError {
+    RetriesExhausted(RetriesExhausted),
+    Request(reqwest::Error),
+}
+
+impl fmt::Display for SendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SendError::RetriesExhausted(err) => err.fmt(f),
+            SendError::Request(_) => write!(f, "Failed to send request"),
+        }
+    }
+}
+
+impl std::error::Error for SendError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SendError::RetriesExhausted(err) => Some(err),
+            SendError::Request(err) => Some(err),
+        }
+    }
+}
+
+/// Sends the request once `rate_limiter` allows it, retrying on 429 answers
+/// and on connection errors, where the request never reached Spotify.
+///
+/// 5xx answers are only retried for idempotent methods like GET and PUT,
+/// Spotify may have carried out a POST anyway and a retry would e.g. create a second playlist.
+/// Waits as long as the `Retry-After` header asks for, or otherwise backs off exponentially.
+/// Other statuses are returned as they are, for the caller to check.
+pub async fn send_with_retry(
+    config: &RetryConfig,
+    rate_limiter: &RateLimiter,
+    request: RequestBuilder,
+) -> Result<Response, SendError> {
+    let (client, request) = request.build_split();
+    let request = request.map_err(SendError::Request)?;
+    let idempotent = request.method().is_idempotent();
+    let max_wait = Duration::from_millis(config.max_wait_ms);
+    let mut waited = Duration::ZERO;
+    let mut attempt = 1;
+    loop {
+        rate_limiter.acquire().await;
+        let Some(this_request) = request.try_clone() else {
+            // Streaming bodies can't be sent twice
+            return client.execute(request).await.map_err(SendError::Request);
+        };
+        let (wait, error) = match client.execute(this_request).await {
+            Ok(response) if !is_retryable(response.status(), idempotent) => return Ok(response),
+            Ok(response) => {
+                let wait = retry_after(&response).unwrap_or_else(|| config.backoff(attempt - 1));
+                let exhausted = RetriesExhausted {
+                    attempts: attempt,
+                    status: response.status(),
+                };
+                (wait, SendError::RetriesExhausted(exhausted))
+            }
+            Err(err) if err.is_connect() => (config.backoff(attempt - 1), SendError::Request(err)),
+            Err(err) => return Err(SendError::Request(err)),
+        };
+
+        if attempt >= config.max_attempts || waited + wait > max_wait {
+            return Err(error);
+        }
+        match &error {
+            SendError::RetriesExhausted(err) => tracing::warn!(
+                "Spotify answered {} to {}, retrying in {:?}",
+                err.status,
+                request.url().path(),
+                wait
+            ),
+            SendError::Request(_) => tracing::warn!(
+                "Failed to connect to Spotify for {}, retrying in {:?}",
+                request.url().path(),
+                wait
+            ),
+        }
+        tokio::time::sleep(wait).await;
+        waited += wait;
+        attempt += 1;
+    }
+}
+
+fn is_retryable(status: StatusCode, idempotent: bool) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS || (idempotent && status.is_server_error())
+}
+
+/// The wait asked for by the `Retry-After` header, Spotify sends it in seconds.
+fn retry_after(response: &Response) -> Option<Duration> {
+    let seconds = response
+        .headers()
+        .get(header::RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse()
+        .ok()?;
+    Some(Duration::from_secs(seconds))
+}
@@ -0,0 +1,69 @@
+use std::fmt;
+
+use anyhow::Context;
+use oauth2::{
+    basic::{BasicErrorResponse, BasicErrorResponseType},
+    RequestTokenError,
+};
+use sqlx::PgPool;
+
+use crate::SendError;
+
+/// Stored in `users.deactivated_reason` when Spotify doesn't accept the refresh token anymore.
+pub const REVOKED_REASON: &str = "authorization_revoked";
+
+/// The user removed the app in their Spotify account, so they were deactivated until they reconnect.
+#[derive(Debug)]
+pub struct AuthorizationRevoked {
+    pub spotify_id: String,
+}
+
+impl fmt::Display for AuthorizationRevoked {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Spotify authorization of {} was revoked, deactivated the user",
+            self.spotify_id
+        )
+    }
+}
+
+impl std::error::Error for AuthorizationRevoked {}
+
+/// Whether Spotify rejected the refresh token with `invalid_grant`,
+/// which it keeps doing once the user removed the app.
+pub fn is_invalid_grant(err: &RequestTokenError<SendError, BasicErrorResponse>) -> bool {
+    matches!(
+        err,
+        RequestTokenError::ServerResponse(response)
+            if response.error() == &BasicErrorResponseType::InvalidGrant
+    )
+}
+
+/// Sets the user inactive after Spotify revoked the authorization
+/// and returns the error to fail with.
+///
+/// Ends the pause of a paused user, as reconnecting makes them active again.
+pub async fn deactivate_revoked(pg_pool: &PgPool, spotify_id: &str) -> anyhow::Error {
+    tracing::warn!("Spotify authorization of {spotify_id} was revoked, deactivating the user");
+    let result = sqlx::query!(
+        r#"WITH deactivated AS (
+                UPDATE users SET active = false, deactivated_reason = $1, deactivated_at = now() WHERE spotify_id = $2
+                    RETURNING spotify_id
+            )
+            UPDATE generation_pauses SET resumed_at = now()
+                WHERE spotify_id IN (SELECT spotify_id FROM deactivated) AND resumed_at IS NULL"#,
+        REVOKED_REASON,
+        spotify_id
+    )
+    .execute(pg_pool)
+    .await
+    .with_context(|| format!("Failed to deactivate user: {spotify_id}"));
+    match result {
+        Ok(_) => AuthorizationRevoked {
+            spotify_id: spotify_id.to_owned(),
+        }
+        .into(),
+        Err(err) => err,
+    }
+}
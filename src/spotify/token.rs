@@ -0,0 +1,176 @@
+use std::sync::Arc;
+
+use anyhow::Context;
+use oauth2::{basic::BasicClient, RefreshToken, TokenResponse};
+use secrecy::{ExposeSecret, SecretString};
+use sqlx::PgPool;
+use tracing::{debug, trace};
+
+use crate::{deactivate_revoked, is_invalid_grant, SpotifyApi, TokenCipher, TokenKind};
+
+/// Access tokens expiring sooner than this are refreshed, so they don't run out mid-request.
+const EXPIRY_MARGIN: chrono::Duration = chrono::Duration::seconds(60);
+
+/// Hands out the access tokens of users, stored in `users.access_token`,
+/// refreshing them only once they expired.
+///
+/// Refreshing locks the row of the user, so concurrent requests for the same user,
+/// also across instances, wait for a single refresh and then use its token.
+#[derive(Clone)]
+pub struct TokenManager {
+    pg_pool: PgPool,
+    oauth: BasicClient,
+    spotify_api: Arc<dyn SpotifyApi>,
+    cipher: TokenCipher,
+}
+
+struct StoredToken {
+    access_token: String,
+    expiry_timestamp: chrono::DateTime<chrono::Utc>,
+    token_key_id: Option<String>,
+}
+
+impl StoredToken {
+    fn is_valid(&self) -> bool {
+        self.expiry_timestamp - EXPIRY_MARGIN > chrono::Utc::now()
+    }
+}
+
+impl TokenManager {
+    pub fn new(
+        pg_pool: PgPool,
+        oauth: BasicClient,
+        spotify_api: Arc<dyn SpotifyApi>,
+        cipher: TokenCipher,
+    ) -> Self {
+        Self {
+            pg_pool,
+            oauth,
+            spotify_api,
+            cipher,
+        }
+    }
+
+    /// The cipher tokens have to be encrypted with before they are stored.
+    pub fn cipher(&self) -> &TokenCipher {
+        &self.cipher
+    }
+
+    /// A valid access token of the user.
+    ///
+    /// Fails with [`AuthorizationRevoked`](crate::AuthorizationRevoked)
+    /// and deactivates the user if Spotify doesn't accept the refresh token anymore.
+    pub async fn access_token(&self, spotify_id: &str) -> anyhow::Result<SecretString> {
+        let stored = sqlx::query_as!(
+            StoredToken,
+            "SELECT access_token, expiry_timestamp, token_key_id FROM users WHERE spotify_id = $1",
+            spotify_id
+        )
+        .fetch_one(&self.pg_pool)
+        .await
+        .with_context(|| format!("Failed to get access token of user: {spotify_id}"))?;
+        if stored.is_valid() {
+            trace!("Access token of {spotify_id} is still valid");
+            return self.decrypt_access_token(spotify_id, &stored);
+        }
+        self.refresh(spotify_id).await
+    }
+
+    async fn refresh(&self, spotify_id: &str) -> anyhow::Result<SecretString> {
+        let mut transaction = self
+            .pg_pool
+            .begin()
+            .await
+            .context("Failed to begin transaction")?;
+        let user = sqlx::query!(
+            "SELECT refresh_token, access_token, expiry_timestamp, token_key_id FROM users WHERE spotify_id = $1 FOR UPDATE",
+            spotify_id
+        )
+        .fetch_one(&mut *transaction)
+        .await
+        .with_context(|| format!("Failed to lock tokens of user: {spotify_id}"))?;
+
+        // Someone else refreshed while we waited for the lock
+        let stored = StoredToken {
+            access_token: user.access_token,
+            expiry_timestamp: user.expiry_timestamp,
+            token_key_id: user.token_key_id,
+        };
+        if stored.is_valid() {
+            debug!("Access token of {spotify_id} was refreshed concurrently");
+            return self.decrypt_access_token(spotify_id, &stored);
+        }
+
+        debug!("Refreshing access token of {spotify_id}");
+        let refresh_token = self.cipher.decrypt(
+            spotify_id,
+            TokenKind::Refresh,
+            stored.token_key_id.as_deref(),
+            &user.refresh_token,
+        )?;
+        let refresh_token = RefreshToken::new(refresh_token.expose_secret().clone());
+        let token_response = match self
+            .oauth
+            .exchange_refresh_token(&refresh_token)
+            .request_async(|request| self.spotify_api.oauth_http_client(request))
+            .await
+        {
+            Ok(token_response) => token_response,
+            Err(err) if is_invalid_grant(&err) => {
+                // Release the row lock first, deactivating updates the same row
+                transaction.rollback().await.ok();
+                return Err(deactivate_revoked(&self.pg_pool, spotify_id).await);
+            }
+            Err(err) => {
+                return Err(err).with_context(|| {
+                    format!("Failed to exchange_refresh_token for user: {spotify_id}")
+                });
+            }
+        };
+
+        let expires_in = token_response.expires_in().unwrap_or_default();
+        let expires_in = chrono::Duration::from_std(expires_in)
+            .with_context(|| format!("Failed to convert expires in {expires_in:?}"))?;
+        let expiry_timestamp = chrono::Utc::now() + expires_in;
+        let access_token = token_response.access_token().secret();
+        // Spotify may rotate the refresh token, it has to be stored together with the access token.
+        // Both are stored with the current key, so they share `token_key_id`.
+        let refresh_token = token_response
+            .refresh_token()
+            .unwrap_or(&refresh_token)
+            .secret();
+        sqlx::query!(
+            r#"UPDATE users SET access_token = $1, expiry_timestamp = $2, refresh_token = $3, token_key_id = $4
+                WHERE spotify_id = $5"#,
+            self.cipher
+                .encrypt(spotify_id, TokenKind::Access, access_token)?,
+            expiry_timestamp,
+            self.cipher
+                .encrypt(spotify_id, TokenKind::Refresh, refresh_token)?,
+            self.cipher.key_id(),
+            spotify_id
+        )
+        .execute(&mut *transaction)
+        .await
+        .with_context(|| format!("Failed to store tokens of user: {spotify_id}"))?;
+        transaction
+            .commit()
+            .await
+            .context("Failed to commit transaction")?;
+
+        Ok(access_token.to_owned().into())
+    }
+
+    fn decrypt_access_token(
+        &self,
+        spotify_id: &str,
+        stored: &StoredToken,
+    ) -> anyhow::Result<SecretString> {
+        self.cipher.decrypt(
+            spotify_id,
+            TokenKind::Access,
+            stored.token_key_id.as_deref(),
+            &stored.access_token,
+        )
+    }
+}
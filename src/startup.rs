@@ -1,10 +1,13 @@
-use std::{env, net::TcpListener};
+use std::{env, net::TcpListener, sync::Arc};
 
 use actix_files::Files;
 use actix_ip_filter::IPFilter;
-use actix_session::{storage::CookieSessionStore, SessionMiddleware};
+use actix_session::{
+    config::{PersistentSession, TtlExtensionPolicy},
+    SessionMiddleware,
+};
 use actix_web::{
-    cookie::Key,
+    cookie::{time::Duration, Key},
     dev::{Server, ServiceRequest},
     web, App, HttpResponse, HttpServer,
 };
@@ -18,8 +21,11 @@ use tracing_actix_web::TracingLogger;
 use url::form_urlencoded::Target;
 
 use crate::{
-    disconnect, generate, get_connect, index, logout, not_found, redirect, Configuration,
-    DatabaseConfig, SpotifyConfig,
+    encrypt_plaintext_tokens, export_data, generate, generate_status, get_connect, get_disconnect,
+    get_preview, get_settings, index, logout, logout_all, not_found, pause, post_disconnect,
+    post_settings, redirect, resume, Configuration, DatabaseConfig, JobQueue, JobWorker,
+    PgSessionStore, ReqwestSpotifyApi, Scheduler, SessionConfig, SpotifyApi, SpotifyConfig,
+    TokenCipher, TokenManager, VerifyCsrf,
 };
 
 pub struct Botm {
@@ -37,18 +43,13 @@ impl Botm {
     /// botm.run_until_stopped().await?;
     /// ```
     pub async fn build(configuration: Configuration) -> anyhow::Result<Self> {
-        if "local".to_owned() == env::var("ENV").unwrap_or_else(|_| "local".into()) {
-            dotenvy::dotenv()?;
-        }
-        let pg_pool = PgPool::connect_lazy(
-            &env::var("DATABASE_URL").context("Failed to load DATABASE_URL in prod")?,
-        )
-        .context("Failed to connect lazy to db")?;
+        let pg_pool = connect_database(configuration.database_url).await?;
 
-        sqlx::migrate!()
-            .run(&pg_pool)
-            .await
-            .context("Failed to run migration")?;
+        let cipher = TokenCipher::from_config(&configuration.token_encryption)?;
+        let encrypted = encrypt_plaintext_tokens(&pg_pool, &cipher).await?;
+        if encrypted != 0 {
+            info!("Encrypted the plaintext tokens of {encrypted} users");
+        }
 
         let address = format!(
             "{}:{}",
@@ -57,14 +58,46 @@ impl Botm {
         let listener = TcpListener::bind(address).expect("Failed to bind to address");
         let port = listener.local_addr().unwrap().port();
 
-        let oauth_client = oauth_client_from_config(configuration.spotify);
+        let spotify_api: Arc<dyn SpotifyApi> =
+            Arc::new(ReqwestSpotifyApi::from_config(&configuration.spotify)?);
+        let oauth_client = oauth_client_from_config(configuration.spotify)?;
+        let tokens = TokenManager::new(
+            pg_pool.clone(),
+            oauth_client.clone(),
+            spotify_api.clone(),
+            cipher,
+        );
+
+        if configuration.scheduler.enabled {
+            Scheduler::new(
+                &configuration.scheduler,
+                pg_pool.clone(),
+                tokens.clone(),
+                spotify_api.clone(),
+                configuration.generator.clone(),
+            )?
+            .spawn();
+        }
+
+        let job_queue = JobQueue::new(pg_pool.clone());
+        JobWorker::new(
+            job_queue.clone(),
+            tokens.clone(),
+            spotify_api.clone(),
+            configuration.generator,
+        )
+        .spawn();
 
         let server = run(
             listener,
             pg_pool,
             oauth_client,
+            spotify_api,
+            tokens,
+            job_queue,
             configuration.cron_ips,
             configuration.cookie_key,
+            configuration.session,
         )
         .expect("Failed to create server");
 
@@ -80,19 +113,52 @@ impl Botm {
     }
 }
 
+/// Connects to the database at `database_url`, or `DATABASE_URL` if none is configured,
+/// and runs the migrations.
+pub async fn connect_database(database_url: Option<SecretString>) -> anyhow::Result<PgPool> {
+    let database_url = match database_url {
+        Some(database_url) => database_url,
+        None => {
+            if "local" == env::var("ENV").unwrap_or_else(|_| "local".into()) {
+                dotenvy::dotenv()?;
+            }
+            env::var("DATABASE_URL")
+                .context("Failed to load DATABASE_URL in prod")?
+                .into()
+        }
+    };
+    let pg_pool = PgPool::connect_lazy(database_url.expose_secret())
+        .context("Failed to connect lazy to db")?;
+
+    sqlx::migrate!()
+        .run(&pg_pool)
+        .await
+        .context("Failed to run migration")?;
+    Ok(pg_pool)
+}
+
 pub struct ApplicationBaseUrl(pub String);
 
+#[allow(clippy::too_many_arguments)]
 pub fn run(
     listener: TcpListener,
     pg_pool: PgPool,
     oauth_client: BasicClient,
+    spotify_api: Arc<dyn SpotifyApi>,
+    tokens: TokenManager,
+    job_queue: JobQueue,
     _cron_ips: Vec<String>,
     cookie_key: SecretString,
+    session_config: SessionConfig,
 ) -> Result<Server, std::io::Error> {
+    let session_store = PgSessionStore::new(pg_pool.clone());
     let connection_pool = web::Data::new(pg_pool);
     let secret_key = Key::from(cookie_key.expose_secret().as_bytes());
 
     let oauth_client = web::Data::new(oauth_client);
+    let spotify_api: web::Data<dyn SpotifyApi> = web::Data::from(spotify_api);
+    let tokens = web::Data::new(tokens);
+    let job_queue = web::Data::new(job_queue);
 
     let message_store = CookieMessageStore::builder(secret_key.clone()).build();
     let message_framework = FlashMessagesFramework::builder(message_store).build();
@@ -100,10 +166,17 @@ pub fn run(
     let server = HttpServer::new(move || {
         App::new()
             .wrap(TracingLogger::default())
-            .wrap(SessionMiddleware::new(
-                CookieSessionStore::default(),
-                secret_key.clone(),
-            ))
+            // The cron job authenticates with basic auth instead of a session
+            .wrap(VerifyCsrf::default().exempt("/generate"))
+            .wrap(
+                SessionMiddleware::builder(session_store.clone(), secret_key.clone())
+                    .session_lifecycle(
+                        PersistentSession::default()
+                            .session_ttl(Duration::days(session_config.ttl_days))
+                            .session_ttl_extension_policy(TtlExtensionPolicy::OnEveryRequest),
+                    )
+                    .build(),
+            )
             // .wrap(
             //     IPFilter::new()
             //         .allow(cron_ips.iter().map(|ip| ip.as_str()).collect())
@@ -115,12 +188,24 @@ pub fn run(
             .route("/connect", web::get().to(get_connect))
             .route("/redirect", web::get().to(redirect))
             .route("/generate", web::post().to(generate))
-            .route("/logout", web::get().to(logout))
-            .route("/disconnect", web::get().to(disconnect))
+            .route("/generate/{job_id}", web::get().to(generate_status))
+            .route("/logout", web::post().to(logout))
+            .route("/logout/all", web::post().to(logout_all))
+            .route("/disconnect", web::get().to(get_disconnect))
+            .route("/disconnect", web::post().to(post_disconnect))
+            .route("/export", web::post().to(export_data))
+            .route("/pause", web::post().to(pause))
+            .route("/resume", web::post().to(resume))
+            .route("/preview", web::get().to(get_preview))
+            .route("/settings", web::get().to(get_settings))
+            .route("/settings", web::post().to(post_settings))
             .service(Files::new("/assets/css", "./assets/css"))
             .default_service(web::to(not_found))
             .app_data(connection_pool.clone())
             .app_data(oauth_client.clone())
+            .app_data(spotify_api.clone())
+            .app_data(tokens.clone())
+            .app_data(job_queue.clone())
     })
     .listen(listener)?
     .run();
@@ -133,16 +218,16 @@ pub fn get_connection_pool(database_settings: &DatabaseConfig) -> sqlx::Pool<sql
         .connect_lazy_with(database_settings.with_db())
 }
 
-pub fn oauth_client_from_config(spotify_config: SpotifyConfig) -> BasicClient {
+pub fn oauth_client_from_config(spotify_config: SpotifyConfig) -> anyhow::Result<BasicClient> {
     let client_id = ClientId::new(spotify_config.client_id.expose_secret().to_owned());
     let client_secret = ClientSecret::new(spotify_config.client_secret.expose_secret().to_owned());
+    let auth_url = AuthUrl::from_url(spotify_config.accounts_url("authorize")?);
+    let token_url = TokenUrl::from_url(spotify_config.accounts_url("api/token")?);
     let redirect_uri = RedirectUrl::new(spotify_config.redirect_uri).expect("Parse redirect uri");
-    let auth_url =
-        AuthUrl::new("https://accounts.spotify.com/authorize".to_owned()).expect("Parse auth url");
-    let token_url =
-        TokenUrl::new("https://accounts.spotify.com/api/token".to_owned()).expect("Parse auth url");
-    BasicClient::new(client_id, Some(client_secret), auth_url, Some(token_url))
-        .set_redirect_uri(redirect_uri)
+    Ok(
+        BasicClient::new(client_id, Some(client_secret), auth_url, Some(token_url))
+            .set_redirect_uri(redirect_uri),
+    )
 }
 
 fn _on_block_handler(_flt: &IPFilter, ip: &str, _req: &ServiceRequest) -> Option<HttpResponse> {
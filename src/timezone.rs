@@ -0,0 +1,96 @@
+use anyhow::Context;
+use chrono::{DateTime, Duration, NaiveDate, NaiveTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use sqlx::PgPool;
+
+/// The time zone of a user from its stored name, UTC if none or an unknown one is stored.
+pub fn parse_timezone(timezone: Option<&str>) -> Tz {
+    timezone.and_then(|tz| tz.parse().ok()).unwrap_or(Tz::UTC)
+}
+
+/// Loads the time zone of the user, see [`parse_timezone`].
+pub async fn user_timezone(pg_pool: &PgPool, spotify_id: &str) -> anyhow::Result<Tz> {
+    let timezone = sqlx::query_scalar!(
+        "SELECT timezone FROM users WHERE spotify_id = $1",
+        spotify_id
+    )
+    .fetch_one(pg_pool)
+    .await
+    .with_context(|| format!("Failed to get time zone of user: {spotify_id}"))?;
+    Ok(parse_timezone(timezone.as_deref()))
+}
+
+/// The instant `day` starts in `timezone`.
+pub fn start_of_day(day: NaiveDate, timezone: Tz) -> DateTime<Utc> {
+    let midnight = day.and_time(NaiveTime::MIN);
+    // Where clocks skip midnight, the day starts once they were turned forward
+    timezone
+        .from_local_datetime(&midnight)
+        .earliest()
+        .or_else(|| {
+            timezone
+                .from_local_datetime(&(midnight + Duration::hours(1)))
+                .earliest()
+        })
+        .expect("Clocks are turned forward by at most an hour")
+        .with_timezone(&Utc)
+}
+
+/// Formats `time` to the minute in the time zone of the user.
+pub fn format_local_time(time: DateTime<Utc>, timezone: Tz) -> String {
+    time.with_timezone(&timezone)
+        .format("%Y-%m-%d %H:%M")
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unknown_time_zones_fall_back_to_utc() {
+        assert_eq!(parse_timezone(Some("Europe/Berlin")), Tz::Europe__Berlin);
+        assert_eq!(parse_timezone(Some("Mars/Olympus_Mons")), Tz::UTC);
+        assert_eq!(parse_timezone(None), Tz::UTC);
+    }
+
+    #[test]
+    fn days_start_at_midnight_of_the_user() {
+        let day = NaiveDate::from_ymd_opt(2026, 11, 1).unwrap();
+
+        assert_eq!(
+            start_of_day(day, Tz::UTC),
+            Utc.with_ymd_and_hms(2026, 11, 1, 0, 0, 0).unwrap()
+        );
+        assert_eq!(
+            start_of_day(day, Tz::Pacific__Kiritimati),
+            Utc.with_ymd_and_hms(2026, 10, 31, 10, 0, 0).unwrap()
+        );
+        assert_eq!(
+            start_of_day(day, Tz::Pacific__Honolulu),
+            Utc.with_ymd_and_hms(2026, 11, 1, 10, 0, 0).unwrap()
+        );
+    }
+
+    #[test]
+    fn days_without_midnight_start_when_clocks_were_turned_forward() {
+        // Santiago turns its clocks from midnight to 1am
+        let day = NaiveDate::from_ymd_opt(2026, 9, 6).unwrap();
+
+        assert_eq!(
+            start_of_day(day, Tz::America__Santiago),
+            Utc.with_ymd_and_hms(2026, 9, 6, 4, 0, 0).unwrap()
+        );
+    }
+
+    #[test]
+    fn local_time_is_formatted_to_the_minute() {
+        let time = Utc.with_ymd_and_hms(2026, 10, 31, 23, 30, 59).unwrap();
+
+        assert_eq!(format_local_time(time, Tz::UTC), "2026-10-31 23:30");
+        assert_eq!(
+            format_local_time(time, Tz::Europe__Berlin),
+            "2026-11-01 00:30"
+        );
+    }
+}
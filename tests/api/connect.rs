@@ -0,0 +1,194 @@
+use actix_web::http::StatusCode;
+use url::Url;
+
+use crate::{
+    fake_spotify::{Endpoint, FakeUser},
+    helpers::{browser, connect_users, location, spawn_app},
+};
+
+#[tokio::test]
+async fn connect_stores_the_user_and_logs_in() {
+    let app = spawn_app().await;
+    app.spotify.add_user(FakeUser::new("alice", 10));
+    app.spotify.authorize_as(Some("alice"));
+
+    let response = app.connect().await;
+
+    assert_eq!(location(&response), "/");
+    let user =
+        sqlx::query!("SELECT active, timezone, scopes FROM users WHERE spotify_id = 'alice'")
+            .fetch_one(&app.pg_pool)
+            .await
+            .expect("User wasn't stored");
+    assert!(user.active);
+    assert_eq!(user.timezone.as_deref(), Some("Europe/Vienna"));
+    assert!(user.scopes.split(' ').any(|s| s == "user-top-read"));
+
+    let html = app.get_html("/").await;
+    assert!(html.contains("alice display name"));
+}
+
+#[tokio::test]
+async fn token_response_without_scope_stores_the_requested_scopes() {
+    let app = spawn_app().await;
+    app.spotify.omit_token_scope();
+    connect_users(&app, [FakeUser::new("alice", 10)]).await;
+
+    let scopes = sqlx::query_scalar!("SELECT scopes FROM users WHERE spotify_id = 'alice'")
+        .fetch_one(&app.pg_pool)
+        .await
+        .unwrap();
+    assert!(scopes.split(' ').any(|s| s == "user-top-read"), "{scopes}");
+
+    // Private playlists need another scope, after connecting again it is granted for good
+    let form = [
+        ("timezone", "Europe/Vienna"),
+        ("name_template", "BOTM"),
+        ("description_template", "BOTM"),
+        ("locale", "en_US"),
+        ("time_range", "short_term"),
+        ("track_count", "50"),
+        ("public", "false"),
+        ("cover_style", "none"),
+        ("notifications", "all"),
+    ];
+    let response = app.post_form("/settings", &form).await;
+    assert_eq!(location(&response), "/connect");
+    app.connect_as("alice").await;
+
+    let response = app.post_form("/settings", &form).await;
+    assert_eq!(location(&response), "/settings");
+}
+
+#[tokio::test]
+async fn denying_access_shows_an_error() {
+    let app = spawn_app().await;
+    app.spotify.authorize_as(None);
+
+    let response = app.connect().await;
+
+    assert_eq!(location(&response), "/");
+    let html = app.get_html("/").await;
+    assert!(html.contains("You need to agree in order to use this service."));
+    let users = sqlx::query_scalar!("SELECT COUNT(*) FROM users")
+        .fetch_one(&app.pg_pool)
+        .await
+        .unwrap();
+    assert_eq!(users, Some(0));
+}
+
+#[tokio::test]
+async fn failing_token_exchange_shows_an_error() {
+    let app = spawn_app().await;
+    app.spotify.add_user(FakeUser::new("alice", 10));
+    app.spotify.authorize_as(Some("alice"));
+    app.spotify
+        .fail(Endpoint::Token, StatusCode::INTERNAL_SERVER_ERROR, 1);
+
+    let response = app.connect().await;
+
+    assert_eq!(location(&response), "/");
+    // Exchanging the code isn't retried, it can only be used once
+    assert_eq!(app.spotify.requests(Endpoint::Token), 1);
+    let html = app.get_html("/").await;
+    assert!(html.contains("Could not get access token."));
+}
+
+#[tokio::test]
+async fn intercepted_code_is_rejected_in_another_session() {
+    let app = spawn_app().await;
+    app.spotify.add_user(FakeUser::new("alice", 10));
+    app.spotify.authorize_as(Some("alice"));
+    // The code Spotify sends back to the browser of alice
+    let authorize = app.get("/connect").await;
+    let callback = app.client.get(location(&authorize)).send().await.unwrap();
+    let callback = Url::parse(&location(&callback)).unwrap();
+    let (_, code) = callback
+        .query_pairs()
+        .find(|(key, _)| key == "code")
+        .unwrap();
+
+    // Someone else starts connecting in their own browser and swaps in the intercepted code
+    let attacker = browser("attacker");
+    let authorize = app.get_in(&attacker, "/connect").await;
+    let callback = attacker.get(location(&authorize)).send().await.unwrap();
+    let mut callback = Url::parse(&location(&callback)).unwrap();
+    let state = callback
+        .query_pairs()
+        .find(|(key, _)| key == "state")
+        .unwrap()
+        .1
+        .into_owned();
+    callback
+        .query_pairs_mut()
+        .clear()
+        .append_pair("code", &code)
+        .append_pair("state", &state);
+    let response = attacker.get(callback).send().await.unwrap();
+
+    assert_eq!(location(&response), "/");
+    let html = app.get_html_in(&attacker, "/").await;
+    assert!(html.contains("Could not get access token."));
+    let users = sqlx::query_scalar!("SELECT COUNT(*) FROM users")
+        .fetch_one(&app.pg_pool)
+        .await
+        .unwrap();
+    assert_eq!(users, Some(0));
+}
+
+#[tokio::test]
+async fn user_revoked_during_a_run_is_asked_to_reconnect() {
+    let app = spawn_app().await;
+    connect_users(&app, [FakeUser::new("alice", 10)]).await;
+    app.spotify.revoke("alice");
+    app.expire_access_tokens().await;
+    app.generate("").await;
+
+    let html = app.get_html("/").await;
+
+    assert!(html.contains("please reconnect"), "{html}");
+    assert!(html.contains("Connect Spotify"));
+}
+
+#[tokio::test]
+async fn index_deactivates_a_revoked_user() {
+    let app = spawn_app().await;
+    connect_users(&app, [FakeUser::new("alice", 10)]).await;
+    app.spotify.revoke("alice");
+    app.expire_access_tokens().await;
+
+    let html = app.get_html("/").await;
+
+    assert!(html.contains("please reconnect"), "{html}");
+    let user = sqlx::query!("SELECT active, deactivated_reason FROM users")
+        .fetch_one(&app.pg_pool)
+        .await
+        .unwrap();
+    assert!(!user.active);
+    assert_eq!(
+        user.deactivated_reason.as_deref(),
+        Some("authorization_revoked")
+    );
+}
+
+#[tokio::test]
+async fn reconnecting_reactivates_the_user() {
+    let app = spawn_app().await;
+    connect_users(&app, [FakeUser::new("alice", 10)]).await;
+    app.spotify.revoke("alice");
+    app.expire_access_tokens().await;
+    app.generate("").await;
+
+    app.connect_as("alice").await;
+
+    let user = sqlx::query!("SELECT active, deactivated_reason, deactivated_at FROM users")
+        .fetch_one(&app.pg_pool)
+        .await
+        .unwrap();
+    assert!(user.active);
+    assert!(user.deactivated_reason.is_none());
+    assert!(user.deactivated_at.is_none());
+    let html = app.get_html("/").await;
+    assert!(!html.contains("please reconnect"));
+    assert!(html.contains("alice display name"));
+}
@@ -0,0 +1,78 @@
+use crate::{
+    fake_spotify::FakeUser,
+    helpers::{connect_users, location, spawn_app},
+};
+
+#[tokio::test]
+async fn logout_needs_the_csrf_token() {
+    let app = spawn_app().await;
+    connect_users(&app, [FakeUser::new("alice", 10)]).await;
+
+    let forged = app
+        .client
+        .post(format!("{}/logout", app.address))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(forged.status().as_u16(), 403);
+    assert!(app.get_html("/").await.contains("alice display name"));
+
+    let response = app.post_form("/logout", &[]).await;
+    assert_eq!(location(&response), "/");
+    assert!(app.get_html("/").await.contains("Connect Spotify"));
+}
+
+#[tokio::test]
+async fn disconnect_with_a_wrong_csrf_token_keeps_the_user() {
+    let app = spawn_app().await;
+    connect_users(&app, [FakeUser::new("alice", 10)]).await;
+
+    let forged = app
+        .client
+        .post(format!("{}/disconnect", app.address))
+        .form(&[("csrf_token", "guessed")])
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(forged.status().as_u16(), 403);
+    let users = sqlx::query_scalar!("SELECT COUNT(*) FROM users")
+        .fetch_one(&app.pg_pool)
+        .await
+        .unwrap();
+    assert_eq!(users, Some(1));
+}
+
+#[tokio::test]
+async fn get_disconnect_only_asks_for_confirmation() {
+    let app = spawn_app().await;
+    connect_users(&app, [FakeUser::new("alice", 10)]).await;
+
+    let html = app.get_html("/disconnect").await;
+    assert!(html.contains("deletes everything BOTM stored about you"));
+    assert!(html.contains(r#"action="/disconnect" method="post""#));
+
+    let response = app.post_form("/disconnect", &[]).await;
+    assert_eq!(location(&response), "/");
+    let users = sqlx::query_scalar!("SELECT COUNT(*) FROM users")
+        .fetch_one(&app.pg_pool)
+        .await
+        .unwrap();
+    assert_eq!(users, Some(0));
+}
+
+#[tokio::test]
+async fn settings_need_the_csrf_token() {
+    let app = spawn_app().await;
+    connect_users(&app, [FakeUser::new("alice", 10)]).await;
+
+    let forged = app
+        .client
+        .post(format!("{}/settings", app.address))
+        .form(&[("timezone", "UTC"), ("name_template", "Forged")])
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(forged.status().as_u16(), 403);
+}
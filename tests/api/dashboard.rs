@@ -0,0 +1,153 @@
+use actix_web::http::StatusCode;
+
+use crate::{
+    fake_spotify::{Endpoint, FakeUser},
+    helpers::{connect_users, spawn_app, target_month},
+};
+
+#[tokio::test]
+async fn dashboard_lists_generated_playlists() {
+    let app = spawn_app().await;
+    connect_users(&app, [FakeUser::new("alice", 10)]).await;
+
+    app.generate("").await;
+
+    let playlist = &app.spotify.playlists()[0];
+    let html = app.get_html("/").await;
+    assert!(html.contains(&playlist.name), "{html}");
+    assert!(html.contains(&format!(
+        "https://open.spotify.com/playlist/{}",
+        playlist.id
+    )));
+    assert!(html.contains(&target_month().format("%B %Y").to_string()));
+    assert!(html.contains("<td>10</td>"));
+}
+
+#[tokio::test]
+async fn dashboard_shows_failed_months() {
+    let app = spawn_app().await;
+    connect_users(&app, [FakeUser::new("alice", 10)]).await;
+    app.spotify
+        .fail(Endpoint::TopTracks, StatusCode::FORBIDDEN, 1);
+
+    app.generate("").await;
+
+    let html = app.get_html("/").await;
+    assert!(html.contains("Failed 1x, retrying"), "{html}");
+}
+
+#[tokio::test]
+async fn dashboard_shows_whether_last_month_is_ready() {
+    let app = spawn_app().await;
+    connect_users(&app, [FakeUser::new("alice", 10)]).await;
+    let label = target_month().format("%B %Y").to_string();
+    assert!(app.get_html("/").await.contains(&format!(
+        "Your BOTM for {label} hasn&#x27;t been created yet."
+    )));
+
+    sqlx::query!(
+        r#"INSERT INTO botm_playlists (spotify_id, month, playlist_id, name, track_count)
+            VALUES ('alice', $1, 'playlist-id', 'BOTM', 10)"#,
+        target_month()
+    )
+    .execute(&app.pg_pool)
+    .await
+    .unwrap();
+
+    let html = app.get_html("/").await;
+    assert!(
+        html.contains(&format!("Your BOTM for {label} is ready.")),
+        "{html}"
+    );
+}
+
+#[tokio::test]
+async fn dashboard_shows_when_last_month_needs_attention() {
+    let app = spawn_app().await;
+    connect_users(&app, [FakeUser::new("alice", 10)]).await;
+    sqlx::query!(
+        r#"INSERT INTO generation_retries (spotify_id, month, attempts, last_error, next_attempt_at, needs_attention)
+            VALUES ('alice', $1, 3, 'error', now(), true)"#,
+        target_month()
+    )
+    .execute(&app.pg_pool)
+    .await
+    .unwrap();
+
+    let html = app.get_html("/").await;
+    let label = target_month().format("%B %Y").to_string();
+    assert!(html.contains(&format!(
+        "Creating your BOTM for {label} failed and isn&#x27;t tried again automatically."
+    )));
+    assert!(html.contains("Failed 3x, not retried anymore"));
+}
+
+#[tokio::test]
+async fn failed_quarterly_playlist_does_not_fail_last_month() {
+    let app = spawn_app().await;
+    connect_users(&app, [FakeUser::new("alice", 10)]).await;
+    sqlx::query!(
+        r#"INSERT INTO botm_playlists (spotify_id, month, playlist_id, name, track_count)
+            VALUES ('alice', $1, 'playlist-id', 'BOTM', 10)"#,
+        target_month()
+    )
+    .execute(&app.pg_pool)
+    .await
+    .unwrap();
+    sqlx::query!(
+        r#"INSERT INTO generation_retries (spotify_id, month, period, attempts, last_error, next_attempt_at, needs_attention)
+            VALUES ('alice', $1, 'quarter', 3, 'error', now(), true)"#,
+        target_month()
+    )
+    .execute(&app.pg_pool)
+    .await
+    .unwrap();
+
+    let html = app.get_html("/").await;
+    let label = target_month().format("%B %Y").to_string();
+    assert!(
+        html.contains(&format!("Your BOTM for {label} is ready.")),
+        "{html}"
+    );
+}
+
+#[tokio::test]
+async fn dashboard_only_tells_about_last_month_as_the_user_chose() {
+    let app = spawn_app().await;
+    connect_users(&app, [FakeUser::new("alice", 10)]).await;
+    let label = target_month().format("%B %Y").to_string();
+    sqlx::query!(
+        r#"INSERT INTO user_settings (spotify_id, name_template, description_template, locale, notifications)
+            VALUES ('alice', 'BOTM', '', 'en_US', 'failures')"#
+    )
+    .execute(&app.pg_pool)
+    .await
+    .unwrap();
+
+    let html = app.get_html("/").await;
+    assert!(!html.contains(&format!("Your BOTM for {label}")), "{html}");
+
+    sqlx::query!(
+        r#"INSERT INTO generation_retries (spotify_id, month, attempts, last_error, next_attempt_at, needs_attention)
+            VALUES ('alice', $1, 3, 'error', now(), true)"#,
+        target_month()
+    )
+    .execute(&app.pg_pool)
+    .await
+    .unwrap();
+    let html = app.get_html("/").await;
+    assert!(
+        html.contains(&format!("Creating your BOTM for {label} failed")),
+        "{html}"
+    );
+
+    sqlx::query!("UPDATE user_settings SET notifications = 'none'")
+        .execute(&app.pg_pool)
+        .await
+        .unwrap();
+    let html = app.get_html("/").await;
+    assert!(
+        !html.contains(&format!("Creating your BOTM for {label}")),
+        "{html}"
+    );
+}
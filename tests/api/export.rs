@@ -0,0 +1,127 @@
+use reqwest::header;
+use serde_json::Value;
+
+use crate::{
+    fake_spotify::FakeUser,
+    helpers::{connect_users, spawn_app, TestApp},
+};
+
+async fn export(app: &TestApp) -> Value {
+    let response = app.post_form("/export", &[]).await;
+    assert_eq!(response.status().as_u16(), 200);
+    let disposition = response.headers()[header::CONTENT_DISPOSITION]
+        .to_str()
+        .unwrap()
+        .to_owned();
+    assert!(disposition.starts_with("attachment"), "{disposition}");
+    // Streamed instead of built in memory with a known length
+    assert_eq!(response.content_length(), None);
+    response.json().await.unwrap()
+}
+
+#[tokio::test]
+async fn export_contains_the_data_of_the_user() {
+    let app = spawn_app().await;
+    connect_users(&app, [FakeUser::new("bob", 10), FakeUser::new("alice", 10)]).await;
+    app.generate("").await;
+
+    let export = export(&app).await;
+
+    assert_eq!(export["user"]["spotify_id"], "alice");
+    assert_eq!(export["user"]["timezone"], "Europe/Vienna");
+    assert_eq!(export["settings"]["track_count"], 50);
+    let playlists = export["playlists"].as_array().unwrap();
+    assert_eq!(playlists.len(), 1, "{export}");
+    let playlist = app
+        .spotify
+        .playlists()
+        .into_iter()
+        .find(|playlist| playlist.owner == "alice")
+        .unwrap();
+    assert_eq!(playlists[0]["playlist_id"], playlist.id.as_str());
+    assert_eq!(export["generation_runs"].as_array().unwrap().len(), 1);
+    assert_eq!(export["generation_runs"][0]["status"], "created");
+    assert_eq!(export["sessions"].as_array().unwrap().len(), 1);
+    assert_eq!(export["pauses"].as_array().unwrap().len(), 0);
+}
+
+#[tokio::test]
+async fn export_leaves_out_tokens() {
+    let app = spawn_app().await;
+    connect_users(&app, [FakeUser::new("alice", 10)]).await;
+    let stored = sqlx::query!("SELECT refresh_token, access_token FROM users")
+        .fetch_one(&app.pg_pool)
+        .await
+        .unwrap();
+
+    let export = export(&app).await.to_string();
+
+    assert!(!export.contains("token\""), "{export}");
+    assert!(!export.contains(&stored.refresh_token));
+    assert!(!export.contains(&stored.access_token));
+}
+
+#[tokio::test]
+async fn exports_are_recorded() {
+    let app = spawn_app().await;
+    connect_users(&app, [FakeUser::new("alice", 10)]).await;
+
+    export(&app).await;
+    let second = export(&app).await;
+
+    let recorded = sqlx::query!("SELECT spotify_id, user_agent FROM data_exports")
+        .fetch_all(&app.pg_pool)
+        .await
+        .unwrap();
+    assert_eq!(recorded.len(), 2);
+    assert_eq!(recorded[0].spotify_id, "alice");
+    assert_eq!(recorded[0].user_agent.as_deref(), Some("test-browser"));
+    // Each export lists the ones before it
+    assert_eq!(second["data_exports"].as_array().unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn disconnect_keeps_the_record_of_exports_and_deletes_jobs() {
+    let app = spawn_app().await;
+    connect_users(&app, [FakeUser::new("alice", 10)]).await;
+    app.generate("?spotify_id=alice").await;
+    export(&app).await;
+
+    app.post_form("/disconnect", &[]).await;
+
+    let recorded = sqlx::query_scalar!("SELECT spotify_id FROM data_exports")
+        .fetch_all(&app.pg_pool)
+        .await
+        .unwrap();
+    assert_eq!(recorded, ["alice"]);
+    let jobs = sqlx::query_scalar!("SELECT COUNT(*) FROM generation_jobs")
+        .fetch_one(&app.pg_pool)
+        .await
+        .unwrap();
+    assert_eq!(jobs, Some(0));
+}
+
+#[tokio::test]
+async fn export_bigger_than_a_chunk_is_complete() {
+    let app = spawn_app().await;
+    connect_users(&app, [FakeUser::new("alice", 10)]).await;
+    app.post_form("/pause", &[("resume_on", "")]).await;
+    sqlx::query!(
+        r#"INSERT INTO data_exports (spotify_id, user_agent)
+            SELECT 'alice', repeat('browser ', 20) || n FROM generate_series(1, 500) AS n"#
+    )
+    .execute(&app.pg_pool)
+    .await
+    .unwrap();
+
+    let export = export(&app).await;
+
+    let data_exports = export["data_exports"].as_array().unwrap();
+    assert_eq!(data_exports.len(), 500);
+    assert!(data_exports[499]["user_agent"]
+        .as_str()
+        .unwrap()
+        .ends_with("500"));
+    assert_eq!(export["pauses"].as_array().unwrap().len(), 1);
+    assert!(export["pauses"][0]["resumed_at"].is_null());
+}
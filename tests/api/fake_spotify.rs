@@ -0,0 +1,630 @@
+//! A fake of the Spotify accounts service and the parts of the Web API BOTM uses.
+//!
+//! Users and their top tracks are scripted with [`FakeSpotify::add_user`],
+//! failures are injected per endpoint with [`FakeSpotify::fail`]
+//! and created playlists can be inspected with [`FakeSpotify::playlists`].
+
+use std::{
+    collections::{HashMap, VecDeque},
+    net::TcpListener,
+    sync::Mutex,
+};
+
+use actix_web::{
+    http::{header, StatusCode},
+    web, App, HttpRequest, HttpResponse, HttpServer,
+};
+use base64::{engine::general_purpose, Engine};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+
+/// The endpoints of the fake, used to inject failures and count requests.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Endpoint {
+    Token,
+    Me,
+    UserProfile,
+    TopTracks,
+    CreatePlaylist,
+    UpdatePlaylist,
+    AddTracks,
+    ReplaceTracks,
+    UploadCover,
+}
+
+#[derive(Debug, Clone)]
+pub struct FakeUser {
+    pub id: String,
+    pub display_name: String,
+    /// Top tracks in order of rank, the same for every time range.
+    pub top_tracks: Vec<String>,
+}
+
+impl FakeUser {
+    /// A user with `track_count` top tracks named after the user.
+    pub fn new(id: &str, track_count: usize) -> Self {
+        Self {
+            id: id.to_owned(),
+            display_name: format!("{id} display name"),
+            top_tracks: tracks(id, track_count),
+        }
+    }
+}
+
+/// `count` distinct track uris with `prefix` in their id.
+pub fn tracks(prefix: &str, count: usize) -> Vec<String> {
+    (0..count)
+        .map(|i| format!("spotify:track:{prefix}{i}"))
+        .collect()
+}
+
+#[derive(Debug, Clone)]
+pub struct FakePlaylist {
+    pub id: String,
+    pub owner: String,
+    pub name: String,
+    pub description: String,
+    pub public: bool,
+    pub collaborative: bool,
+    pub tracks: Vec<String>,
+    /// The decoded JPEG of the last uploaded cover.
+    pub cover: Option<Vec<u8>>,
+}
+
+/// What an authorization code was issued for.
+struct CodeGrant {
+    user: String,
+    scope: String,
+    /// The PKCE challenge, the code is only exchanged with the matching verifier.
+    code_challenge: String,
+}
+
+#[derive(Default)]
+struct State {
+    users: HashMap<String, FakeUser>,
+    /// The user that agrees on the next visit of `/authorize`, `None` denies access.
+    authorizing_user: Option<String>,
+    /// Authorization codes to the grant they were issued for.
+    codes: HashMap<String, CodeGrant>,
+    access_tokens: HashMap<String, String>,
+    refresh_tokens: HashMap<String, (String, String)>,
+    /// Hand out a new refresh token on every refresh, invalidating the old one.
+    rotate_refresh_tokens: bool,
+    /// Leave the granted scopes out of token responses, as allowed if they are the requested ones.
+    omit_token_scope: bool,
+    playlists: Vec<FakePlaylist>,
+    failures: HashMap<Endpoint, VecDeque<StatusCode>>,
+    requests: HashMap<Endpoint, usize>,
+    top_tracks_requests: Vec<TopTracksParams>,
+    next_id: usize,
+}
+
+impl State {
+    fn next_id(&mut self) -> usize {
+        self.next_id += 1;
+        self.next_id
+    }
+
+    /// Counts the request and returns the injected failure for it, if any.
+    fn request(&mut self, endpoint: Endpoint) -> Option<HttpResponse> {
+        *self.requests.entry(endpoint).or_default() += 1;
+        let status = self.failures.get_mut(&endpoint)?.pop_front()?;
+        let mut response = HttpResponse::build(status);
+        if status == StatusCode::TOO_MANY_REQUESTS {
+            response.insert_header((header::RETRY_AFTER, "1"));
+        }
+        Some(response.json(json!({
+            "error": { "status": status.as_u16(), "message": "Injected failure" }
+        })))
+    }
+
+    /// The user of the bearer token of `request`, the error is the response to send instead.
+    fn user(&self, request: &HttpRequest) -> Result<String, Box<HttpResponse>> {
+        request
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .and_then(|token| self.access_tokens.get(token))
+            .cloned()
+            .ok_or_else(|| {
+                Box::new(HttpResponse::Unauthorized().json(json!({
+                    "error": { "status": 401, "message": "Invalid access token" }
+                })))
+            })
+    }
+
+    fn playlist_mut(
+        &mut self,
+        user: &str,
+        playlist_id: &str,
+    ) -> Result<&mut FakePlaylist, Box<HttpResponse>> {
+        match self.playlists.iter_mut().find(|p| p.id == playlist_id) {
+            Some(playlist) if playlist.owner == user => Ok(playlist),
+            Some(_) => Err(Box::new(HttpResponse::Forbidden().finish())),
+            None => Err(Box::new(HttpResponse::NotFound().finish())),
+        }
+    }
+}
+
+type SharedState = web::Data<Mutex<State>>;
+
+pub struct FakeSpotify {
+    /// Address of the server without trailing slash, e.g. `http://127.0.0.1:1234`.
+    pub address: String,
+    state: SharedState,
+}
+
+impl FakeSpotify {
+    /// Starts the fake on a random port in the background.
+    pub fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("Failed to bind fake Spotify");
+        let address = format!("http://127.0.0.1:{}", listener.local_addr().unwrap().port());
+        let state = SharedState::new(Mutex::new(State::default()));
+
+        let server_state = state.clone();
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(server_state.clone())
+                .route("/authorize", web::get().to(authorize))
+                .route("/api/token", web::post().to(token))
+                .route("/v1/me", web::get().to(me))
+                .route("/v1/me/top/tracks", web::get().to(top_tracks))
+                .route("/v1/users/{user_id}", web::get().to(user_profile))
+                .route(
+                    "/v1/users/{user_id}/playlists",
+                    web::post().to(create_playlist),
+                )
+                .route(
+                    "/v1/playlists/{playlist_id}",
+                    web::put().to(update_playlist),
+                )
+                .route(
+                    "/v1/playlists/{playlist_id}/tracks",
+                    web::post().to(add_tracks),
+                )
+                .route(
+                    "/v1/playlists/{playlist_id}/tracks",
+                    web::put().to(replace_tracks),
+                )
+                .route(
+                    "/v1/playlists/{playlist_id}/images",
+                    web::put().to(upload_cover),
+                )
+        })
+        .workers(1)
+        .listen(listener)
+        .expect("Failed to listen with fake Spotify")
+        .run();
+        tokio::spawn(server);
+
+        Self { address, state }
+    }
+
+    pub fn api_base_url(&self) -> String {
+        format!("{}/v1/", self.address)
+    }
+
+    pub fn accounts_base_url(&self) -> String {
+        format!("{}/", self.address)
+    }
+
+    pub fn add_user(&self, user: FakeUser) {
+        let mut state = self.state.lock().unwrap();
+        state.users.insert(user.id.clone(), user);
+    }
+
+    pub fn set_top_tracks(&self, user_id: &str, top_tracks: Vec<String>) {
+        let mut state = self.state.lock().unwrap();
+        state
+            .users
+            .get_mut(user_id)
+            .expect("Unknown user")
+            .top_tracks = top_tracks;
+    }
+
+    /// Makes `/authorize` log in as `user_id`, or deny access for `None`.
+    pub fn authorize_as(&self, user_id: Option<&str>) {
+        self.state.lock().unwrap().authorizing_user = user_id.map(str::to_owned);
+    }
+
+    /// Answers the next `times` requests to `endpoint` with `status`.
+    pub fn fail(&self, endpoint: Endpoint, status: StatusCode, times: usize) {
+        let mut state = self.state.lock().unwrap();
+        state
+            .failures
+            .entry(endpoint)
+            .or_default()
+            .extend(std::iter::repeat_n(status, times));
+    }
+
+    /// Makes every further refresh hand out a new refresh token and invalidate the old one.
+    pub fn rotate_refresh_tokens(&self) {
+        self.state.lock().unwrap().rotate_refresh_tokens = true;
+    }
+
+    /// Makes every further token response leave out the granted scopes.
+    pub fn omit_token_scope(&self) {
+        self.state.lock().unwrap().omit_token_scope = true;
+    }
+
+    /// Invalidates all refresh tokens of the user, like removing the app in the Spotify account.
+    pub fn revoke(&self, user_id: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.refresh_tokens.retain(|_, (user, _)| user != user_id);
+    }
+
+    pub fn playlists(&self) -> Vec<FakePlaylist> {
+        self.state.lock().unwrap().playlists.clone()
+    }
+
+    /// The query of every request for top tracks, including failed ones.
+    pub fn top_tracks_requests(&self) -> Vec<TopTracksParams> {
+        self.state.lock().unwrap().top_tracks_requests.clone()
+    }
+
+    /// Number of requests made to `endpoint`, including failed ones.
+    pub fn requests(&self, endpoint: Endpoint) -> usize {
+        let state = self.state.lock().unwrap();
+        state.requests.get(&endpoint).copied().unwrap_or_default()
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct AuthorizeParams {
+    redirect_uri: String,
+    state: String,
+    #[serde(default)]
+    scope: String,
+    code_challenge: String,
+    code_challenge_method: String,
+}
+
+/// Agrees right away as the scripted user and sends the browser back to the app.
+///
+/// Like Spotify for PKCE, only `S256` code challenges are accepted.
+async fn authorize(state: SharedState, params: web::Query<AuthorizeParams>) -> HttpResponse {
+    let mut state = state.lock().unwrap();
+    if params.code_challenge_method != "S256" {
+        return HttpResponse::BadRequest().body("code_challenge_method has to be S256");
+    }
+    let mut redirect = url::Url::parse(&params.redirect_uri).expect("Invalid redirect_uri");
+    let (key, value) = match state.authorizing_user.clone() {
+        Some(user) => {
+            let code = format!("code-{}", state.next_id());
+            state.codes.insert(
+                code.clone(),
+                CodeGrant {
+                    user,
+                    scope: params.scope.clone(),
+                    code_challenge: params.code_challenge.clone(),
+                },
+            );
+            ("code", code)
+        }
+        None => ("error", "access_denied".to_owned()),
+    };
+    redirect
+        .query_pairs_mut()
+        .append_pair(key, &value)
+        .append_pair("state", &params.state);
+    HttpResponse::Found()
+        .append_header((header::LOCATION, redirect.to_string()))
+        .finish()
+}
+
+#[derive(serde::Deserialize)]
+struct TokenForm {
+    grant_type: String,
+    code: Option<String>,
+    code_verifier: Option<String>,
+    refresh_token: Option<String>,
+}
+
+async fn token(state: SharedState, form: web::Form<TokenForm>) -> HttpResponse {
+    let mut state = state.lock().unwrap();
+    if let Some(response) = state.request(Endpoint::Token) {
+        return response;
+    }
+    let invalid_grant = || HttpResponse::BadRequest().json(json!({ "error": "invalid_grant" }));
+
+    let (user, scope, refresh_token) = match form.grant_type.as_str() {
+        "authorization_code" => {
+            let Some(CodeGrant {
+                user,
+                scope,
+                code_challenge,
+            }) = form.code.as_ref().and_then(|c| state.codes.remove(c))
+            else {
+                return invalid_grant();
+            };
+            let verified = form.code_verifier.as_ref().is_some_and(|verifier| {
+                general_purpose::URL_SAFE_NO_PAD.encode(Sha256::digest(verifier)) == code_challenge
+            });
+            if !verified {
+                return invalid_grant();
+            }
+            let refresh_token = format!("refresh-{user}-{}", state.next_id());
+            state
+                .refresh_tokens
+                .insert(refresh_token.clone(), (user.clone(), scope.clone()));
+            (user, scope, Some(refresh_token))
+        }
+        // Like Spotify, refreshing keeps the refresh token unless rotating is turned on
+        "refresh_token" => {
+            let Some((user, scope)) = form
+                .refresh_token
+                .as_ref()
+                .and_then(|t| state.refresh_tokens.get(t))
+                .cloned()
+            else {
+                return invalid_grant();
+            };
+            if !state.rotate_refresh_tokens {
+                (user, scope, None)
+            } else {
+                state
+                    .refresh_tokens
+                    .remove(form.refresh_token.as_ref().unwrap());
+                let refresh_token = format!("refresh-{user}-{}", state.next_id());
+                state
+                    .refresh_tokens
+                    .insert(refresh_token.clone(), (user.clone(), scope.clone()));
+                (user, scope, Some(refresh_token))
+            }
+        }
+        _ => return HttpResponse::BadRequest().json(json!({ "error": "unsupported_grant_type" })),
+    };
+
+    let access_token = format!("access-{user}-{}", state.next_id());
+    state.access_tokens.insert(access_token.clone(), user);
+    let mut body = json!({
+        "access_token": access_token,
+        "token_type": "Bearer",
+        "expires_in": 3600,
+    });
+    if !state.omit_token_scope {
+        body["scope"] = json!(scope);
+    }
+    if let Some(refresh_token) = refresh_token {
+        body["refresh_token"] = json!(refresh_token);
+    }
+    HttpResponse::Ok().json(body)
+}
+
+async fn me(state: SharedState, request: HttpRequest) -> HttpResponse {
+    let mut state = state.lock().unwrap();
+    if let Some(response) = state.request(Endpoint::Me) {
+        return response;
+    }
+    match state.user(&request) {
+        Ok(user) => HttpResponse::Ok().json(json!({ "id": user })),
+        Err(response) => *response,
+    }
+}
+
+async fn user_profile(
+    state: SharedState,
+    request: HttpRequest,
+    user_id: web::Path<String>,
+) -> HttpResponse {
+    let mut state = state.lock().unwrap();
+    if let Some(response) = state.request(Endpoint::UserProfile) {
+        return response;
+    }
+    if let Err(response) = state.user(&request) {
+        return *response;
+    }
+    match state.users.get(user_id.as_str()) {
+        Some(user) => HttpResponse::Ok().json(json!({
+            "display_name": user.display_name,
+            "images": [],
+        })),
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+#[derive(serde::Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct TopTracksParams {
+    pub time_range: String,
+    pub limit: usize,
+    pub offset: usize,
+}
+
+async fn top_tracks(
+    state: SharedState,
+    request: HttpRequest,
+    params: web::Query<TopTracksParams>,
+) -> HttpResponse {
+    let mut state = state.lock().unwrap();
+    state.top_tracks_requests.push(params.0.clone());
+    if let Some(response) = state.request(Endpoint::TopTracks) {
+        return response;
+    }
+    let user = match state.user(&request) {
+        Ok(user) => user,
+        Err(response) => return *response,
+    };
+    let tracks = &state.users[&user].top_tracks;
+    let end = (params.offset + params.limit).min(tracks.len());
+    let items: Vec<_> = tracks
+        .get(params.offset..end)
+        .unwrap_or_default()
+        .iter()
+        .map(|uri| {
+            let id = uri.trim_start_matches("spotify:track:");
+            json!({
+                "uri": uri,
+                "name": format!("Track {id}"),
+                "artists": [{ "name": format!("Artist {id}") }],
+                "album": {
+                    "name": format!("Album {id}"),
+                    "images": [
+                        { "url": format!("https://i.scdn.co/image/{id}-640") },
+                        { "url": format!("https://i.scdn.co/image/{id}-64") }
+                    ]
+                }
+            })
+        })
+        .collect();
+    let next = (end < tracks.len()).then(|| {
+        format!(
+            "{}?time_range={}&limit={}&offset={end}",
+            request.path(),
+            params.time_range,
+            params.limit
+        )
+    });
+    HttpResponse::Ok().json(json!({ "items": items, "next": next }))
+}
+
+#[derive(serde::Deserialize)]
+struct PlaylistDetails {
+    name: String,
+    description: String,
+    public: bool,
+    collaborative: bool,
+}
+
+async fn create_playlist(
+    state: SharedState,
+    request: HttpRequest,
+    user_id: web::Path<String>,
+    details: web::Json<PlaylistDetails>,
+) -> HttpResponse {
+    let mut state = state.lock().unwrap();
+    if let Some(response) = state.request(Endpoint::CreatePlaylist) {
+        return response;
+    }
+    match state.user(&request) {
+        Ok(user) if user == *user_id => {}
+        Ok(_) => return HttpResponse::Forbidden().finish(),
+        Err(response) => return *response,
+    }
+    let details = details.into_inner();
+    let id = format!("playlist{}", state.next_id());
+    state.playlists.push(FakePlaylist {
+        id: id.clone(),
+        owner: user_id.into_inner(),
+        name: details.name,
+        description: details.description,
+        public: details.public,
+        collaborative: details.collaborative,
+        tracks: Vec::new(),
+        cover: None,
+    });
+    HttpResponse::Created().json(json!({ "id": id }))
+}
+
+async fn update_playlist(
+    state: SharedState,
+    request: HttpRequest,
+    playlist_id: web::Path<String>,
+    details: web::Json<PlaylistDetails>,
+) -> HttpResponse {
+    let mut state = state.lock().unwrap();
+    if let Some(response) = state.request(Endpoint::UpdatePlaylist) {
+        return response;
+    }
+    let result = state
+        .user(&request)
+        .and_then(|user| state.playlist_mut(&user, &playlist_id));
+    match result {
+        Ok(playlist) => {
+            let details = details.into_inner();
+            playlist.name = details.name;
+            playlist.description = details.description;
+            playlist.public = details.public;
+            playlist.collaborative = details.collaborative;
+            HttpResponse::Ok().finish()
+        }
+        Err(response) => *response,
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct TracksBody {
+    uris: Vec<String>,
+    position: Option<usize>,
+}
+
+async fn add_tracks(
+    state: SharedState,
+    request: HttpRequest,
+    playlist_id: web::Path<String>,
+    body: web::Json<TracksBody>,
+) -> HttpResponse {
+    let mut state = state.lock().unwrap();
+    if let Some(response) = state.request(Endpoint::AddTracks) {
+        return response;
+    }
+    let result = state
+        .user(&request)
+        .and_then(|user| state.playlist_mut(&user, &playlist_id));
+    match result {
+        Ok(playlist) => {
+            let body = body.into_inner();
+            let position = body.position.unwrap_or(playlist.tracks.len());
+            if body.uris.len() > 100 || position > playlist.tracks.len() {
+                return HttpResponse::BadRequest().finish();
+            }
+            playlist.tracks.splice(position..position, body.uris);
+            HttpResponse::Created().json(json!({ "snapshot_id": "snapshot" }))
+        }
+        Err(response) => *response,
+    }
+}
+
+async fn replace_tracks(
+    state: SharedState,
+    request: HttpRequest,
+    playlist_id: web::Path<String>,
+    body: web::Json<TracksBody>,
+) -> HttpResponse {
+    let mut state = state.lock().unwrap();
+    if let Some(response) = state.request(Endpoint::ReplaceTracks) {
+        return response;
+    }
+    let result = state
+        .user(&request)
+        .and_then(|user| state.playlist_mut(&user, &playlist_id));
+    match result {
+        Ok(playlist) => {
+            let body = body.into_inner();
+            if body.uris.len() > 100 {
+                return HttpResponse::BadRequest().finish();
+            }
+            playlist.tracks = body.uris;
+            HttpResponse::Ok().json(json!({ "snapshot_id": "snapshot" }))
+        }
+        Err(response) => *response,
+    }
+}
+
+async fn upload_cover(
+    state: SharedState,
+    request: HttpRequest,
+    playlist_id: web::Path<String>,
+    body: String,
+) -> HttpResponse {
+    let mut state = state.lock().unwrap();
+    if let Some(response) = state.request(Endpoint::UploadCover) {
+        return response;
+    }
+    let Ok(jpeg) = general_purpose::STANDARD.decode(body) else {
+        return HttpResponse::BadRequest().finish();
+    };
+    if !jpeg.starts_with(&[0xFF, 0xD8]) {
+        return HttpResponse::BadRequest().finish();
+    }
+    let result = state
+        .user(&request)
+        .and_then(|user| state.playlist_mut(&user, &playlist_id));
+    match result {
+        Ok(playlist) => {
+            playlist.cover = Some(jpeg);
+            HttpResponse::Accepted().finish()
+        }
+        Err(response) => *response,
+    }
+}
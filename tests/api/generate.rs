@@ -0,0 +1,769 @@
+use std::time::Duration;
+
+use actix_web::http::StatusCode;
+use chrono::Datelike;
+use serde_json::Value;
+
+use crate::{
+    fake_spotify::{tracks, Endpoint, FakeUser, TopTracksParams},
+    helpers::{
+        assert_counts, connect_users, location, spawn_app, target_month, TestApp, MAX_ATTEMPTS,
+    },
+};
+
+#[tokio::test]
+async fn generate_requires_basic_auth() {
+    let app = spawn_app().await;
+
+    let response = app
+        .client
+        .post(format!("{}/generate", app.address))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 401);
+
+    let response = app
+        .client
+        .post(format!("{}/generate", app.address))
+        .basic_auth("generate-user", Some("wrong-password"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 401);
+}
+
+#[tokio::test]
+async fn generate_creates_a_playlist_of_the_top_tracks() {
+    let app = spawn_app().await;
+    let alice = FakeUser::new("alice", 60);
+    connect_users(&app, [alice.clone()]).await;
+
+    let status = app.generate("").await;
+
+    assert_counts(&status, [1, 0, 0, 0]);
+    let playlists = app.spotify.playlists();
+    assert_eq!(playlists.len(), 1);
+    let playlist = &playlists[0];
+    assert_eq!(playlist.owner, "alice");
+    assert_eq!(
+        playlist.name,
+        target_month().format("%Y-%m (%b) BOTM").to_string()
+    );
+    assert_eq!(playlist.tracks, alice.top_tracks[..50]);
+    assert!(playlist.public);
+    assert!(playlist.cover.is_some());
+
+    let stored = sqlx::query!(
+        "SELECT playlist_id, month, track_count FROM botm_playlists WHERE spotify_id = 'alice'"
+    )
+    .fetch_one(&app.pg_pool)
+    .await
+    .unwrap();
+    assert_eq!(stored.playlist_id, playlist.id);
+    assert_eq!(stored.month, target_month());
+    assert_eq!(stored.track_count, 50);
+}
+
+#[tokio::test]
+async fn generating_again_skips_the_existing_playlist() {
+    let app = spawn_app().await;
+    connect_users(&app, [FakeUser::new("alice", 60)]).await;
+
+    app.generate("").await;
+    let status = app.generate("").await;
+
+    assert_counts(&status, [0, 0, 1, 0]);
+    assert_eq!(app.spotify.playlists().len(), 1);
+    assert_eq!(app.spotify.requests(Endpoint::CreatePlaylist), 1);
+}
+
+#[tokio::test]
+async fn force_regenerates_the_playlist_in_place() {
+    let app = spawn_app().await;
+    connect_users(&app, [FakeUser::new("alice", 60)]).await;
+    app.generate("").await;
+    let new_tracks = tracks("new", 60);
+    app.spotify.set_top_tracks("alice", new_tracks.clone());
+
+    let status = app.generate("?force=true").await;
+
+    assert_counts(&status, [0, 1, 0, 0]);
+    let playlists = app.spotify.playlists();
+    assert_eq!(playlists.len(), 1);
+    assert_eq!(playlists[0].tracks, new_tracks[..50]);
+}
+
+#[tokio::test]
+async fn generate_only_for_the_given_user() {
+    let app = spawn_app().await;
+    connect_users(&app, [FakeUser::new("alice", 10), FakeUser::new("bob", 10)]).await;
+
+    let status = app.generate("?spotify_id=bob").await;
+
+    assert_counts(&status, [1, 0, 0, 0]);
+    let playlists = app.spotify.playlists();
+    assert_eq!(playlists.len(), 1);
+    assert_eq!(playlists[0].owner, "bob");
+}
+
+#[tokio::test]
+async fn generate_uses_the_settings_of_the_user() {
+    let app = spawn_app().await;
+    let alice = FakeUser::new("alice", 120);
+    connect_users(&app, [alice.clone()]).await;
+
+    let response = app
+        .post_form(
+            "/settings",
+            &[
+                ("timezone", "Europe/Vienna"),
+                ("name_template", "BOTM {year}/{month}"),
+                ("description_template", "{track_count} bangers"),
+                ("locale", "en_US"),
+                ("time_range", "medium_term"),
+                ("track_count", "100"),
+                ("public", "false"),
+                ("cover_style", "none"),
+                ("notifications", "all"),
+            ],
+        )
+        .await;
+    // Private playlists need another scope, so the user has to connect again
+    assert_eq!(location(&response), "/connect");
+    app.connect_as("alice").await;
+
+    app.generate("").await;
+
+    let playlists = app.spotify.playlists();
+    assert_eq!(playlists.len(), 1);
+    let playlist = &playlists[0];
+    assert_eq!(
+        playlist.name,
+        target_month().format("BOTM %Y/%m").to_string()
+    );
+    assert_eq!(playlist.description, "100 bangers");
+    assert_eq!(playlist.tracks, alice.top_tracks[..100]);
+    assert!(!playlist.public);
+    assert!(playlist.cover.is_none());
+    assert_eq!(app.spotify.requests(Endpoint::TopTracks), 2);
+}
+
+#[tokio::test]
+async fn failing_user_does_not_stop_the_others() {
+    let app = spawn_app().await;
+    connect_users(&app, [FakeUser::new("alice", 10), FakeUser::new("bob", 10)]).await;
+    app.spotify
+        .fail(Endpoint::TopTracks, StatusCode::FORBIDDEN, 1);
+
+    let status = app.generate("").await;
+
+    assert_counts(&status, [1, 0, 0, 1]);
+    assert_eq!(app.spotify.playlists().len(), 1);
+    let runs = sqlx::query!("SELECT status, error FROM user_botm_runs ORDER BY status")
+        .fetch_all(&app.pg_pool)
+        .await
+        .unwrap();
+    assert_eq!(runs.len(), 2);
+    assert_eq!(runs[0].status, "created");
+    assert_eq!(runs[1].status, "failed");
+    assert!(runs[1].error.is_some());
+}
+
+#[tokio::test]
+async fn expired_access_token_fails_the_user() {
+    let app = spawn_app().await;
+    connect_users(&app, [FakeUser::new("alice", 10)]).await;
+    app.spotify
+        .fail(Endpoint::CreatePlaylist, StatusCode::UNAUTHORIZED, 1);
+
+    app.generate("").await;
+
+    assert!(app.spotify.playlists().is_empty());
+    let run = sqlx::query!("SELECT total_users, succeeded, failed FROM botm_runs")
+        .fetch_one(&app.pg_pool)
+        .await
+        .unwrap();
+    assert_eq!(run.total_users, 1);
+    assert_eq!(run.succeeded, 0);
+    assert_eq!(run.failed, 1);
+}
+
+#[tokio::test]
+async fn revoked_authorization_fails_the_user() {
+    let app = spawn_app().await;
+    connect_users(&app, [FakeUser::new("alice", 10)]).await;
+    app.spotify.revoke("alice");
+    app.expire_access_tokens().await;
+
+    let status = app.generate("").await;
+
+    assert_counts(&status, [0, 0, 0, 1]);
+    assert!(app.spotify.playlists().is_empty());
+    assert_eq!(app.spotify.requests(Endpoint::TopTracks), 0);
+    let user = sqlx::query!(
+        "SELECT active, deactivated_reason, deactivated_at FROM users WHERE spotify_id = 'alice'"
+    )
+    .fetch_one(&app.pg_pool)
+    .await
+    .unwrap();
+    assert!(!user.active);
+    assert_eq!(
+        user.deactivated_reason.as_deref(),
+        Some("authorization_revoked")
+    );
+    assert!(user.deactivated_at.is_some());
+    let retries = sqlx::query_scalar!("SELECT COUNT(*) FROM generation_retries")
+        .fetch_one(&app.pg_pool)
+        .await
+        .unwrap();
+    assert_eq!(retries, Some(0));
+}
+
+#[tokio::test]
+async fn rate_limited_requests_are_retried() {
+    let app = spawn_app().await;
+    connect_users(&app, [FakeUser::new("alice", 10)]).await;
+    app.spotify
+        .fail(Endpoint::TopTracks, StatusCode::TOO_MANY_REQUESTS, 1);
+
+    let status = app.generate("").await;
+
+    assert_counts(&status, [1, 0, 0, 0]);
+    assert_eq!(app.spotify.requests(Endpoint::TopTracks), 2);
+    assert_eq!(app.spotify.playlists()[0].tracks.len(), 10);
+}
+
+#[tokio::test]
+async fn server_errors_are_retried() {
+    let app = spawn_app().await;
+    connect_users(&app, [FakeUser::new("alice", 10)]).await;
+    app.spotify
+        .fail(Endpoint::TopTracks, StatusCode::BAD_GATEWAY, 1);
+    app.spotify
+        .fail(Endpoint::ReplaceTracks, StatusCode::SERVICE_UNAVAILABLE, 2);
+
+    app.generate("").await;
+    let status = app.generate("?force=true").await;
+
+    assert_counts(&status, [0, 1, 0, 0]);
+    assert_eq!(app.spotify.requests(Endpoint::TopTracks), 3);
+    assert_eq!(app.spotify.requests(Endpoint::ReplaceTracks), 3);
+    assert_eq!(app.spotify.playlists().len(), 1);
+}
+
+#[tokio::test]
+async fn server_errors_of_posts_are_not_retried() {
+    // Spotify may have carried out the request anyway, a retry could e.g. duplicate the playlist
+    let app = spawn_app().await;
+    connect_users(&app, [FakeUser::new("alice", 10)]).await;
+    let token_requests = app.spotify.requests(Endpoint::Token);
+
+    app.expire_access_tokens().await;
+    app.spotify
+        .fail(Endpoint::Token, StatusCode::BAD_GATEWAY, 1);
+    assert_counts(&app.generate("").await, [0, 0, 0, 1]);
+    assert_eq!(app.spotify.requests(Endpoint::Token), token_requests + 1);
+
+    app.spotify
+        .fail(Endpoint::CreatePlaylist, StatusCode::SERVICE_UNAVAILABLE, 1);
+    assert_counts(&app.generate("").await, [0, 0, 0, 1]);
+    assert_eq!(app.spotify.requests(Endpoint::CreatePlaylist), 1);
+    assert!(app.spotify.playlists().is_empty());
+
+    app.spotify
+        .fail(Endpoint::AddTracks, StatusCode::INTERNAL_SERVER_ERROR, 1);
+    assert_counts(&app.generate("").await, [0, 0, 0, 1]);
+    assert_eq!(app.spotify.requests(Endpoint::AddTracks), 1);
+    let playlists = app.spotify.playlists();
+    assert_eq!(playlists.len(), 1);
+    assert!(playlists[0].tracks.is_empty());
+}
+
+#[tokio::test]
+async fn rate_limited_posts_are_retried() {
+    let app = spawn_app().await;
+    connect_users(&app, [FakeUser::new("alice", 10)]).await;
+    app.spotify
+        .fail(Endpoint::CreatePlaylist, StatusCode::TOO_MANY_REQUESTS, 1);
+
+    let status = app.generate("").await;
+
+    assert_counts(&status, [1, 0, 0, 0]);
+    assert_eq!(app.spotify.requests(Endpoint::CreatePlaylist), 2);
+    assert_eq!(app.spotify.playlists().len(), 1);
+}
+
+#[tokio::test]
+async fn generate_gives_up_when_the_retries_are_used_up() {
+    let app = spawn_app().await;
+    connect_users(&app, [FakeUser::new("alice", 10)]).await;
+    app.spotify.fail(
+        Endpoint::TopTracks,
+        StatusCode::INTERNAL_SERVER_ERROR,
+        MAX_ATTEMPTS,
+    );
+
+    let status = app.generate("").await;
+
+    assert_counts(&status, [0, 0, 0, 1]);
+    assert_eq!(app.spotify.requests(Endpoint::TopTracks), MAX_ATTEMPTS);
+    let error = sqlx::query_scalar!("SELECT error FROM user_botm_runs")
+        .fetch_one(&app.pg_pool)
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(error.contains("giving up"), "{error}");
+}
+
+#[tokio::test]
+async fn generate_queues_a_job() {
+    let app = spawn_app().await;
+    connect_users(&app, [FakeUser::new("alice", 10)]).await;
+
+    let response = app.post_generate("?spotify_id=alice&force=true").await;
+
+    assert_eq!(response.status().as_u16(), 202);
+    let job = sqlx::query!("SELECT id, spotify_id, force, due_only FROM generation_jobs")
+        .fetch_one(&app.pg_pool)
+        .await
+        .unwrap();
+    assert_eq!(location(&response), format!("/generate/{}", job.id));
+    let body: Value = response.json().await.unwrap();
+    assert_eq!(body["job_id"], job.id);
+    assert_eq!(job.spotify_id.as_deref(), Some("alice"));
+    assert!(job.force);
+    assert!(!job.due_only);
+}
+
+#[tokio::test]
+async fn generate_status_shows_the_users_and_timing() {
+    let app = spawn_app().await;
+    connect_users(&app, [FakeUser::new("alice", 10), FakeUser::new("bob", 10)]).await;
+    app.spotify
+        .fail(Endpoint::TopTracks, StatusCode::FORBIDDEN, 1);
+
+    let status = app.generate("").await;
+
+    assert_eq!(status["state"], "finished");
+    assert!(status["error"].is_null());
+    assert!(status["started_at"].is_string());
+    assert!(status["finished_at"].is_string());
+    assert!(status["duration_ms"].as_i64().unwrap() >= 0);
+    assert_eq!(status["progress"]["total"], 2);
+    assert_eq!(status["progress"]["done"], 2);
+    assert_eq!(status["progress"]["finished"], true);
+    let users = status["users"].as_array().unwrap();
+    assert_eq!(users.len(), 2);
+    let failed: Vec<_> = users.iter().filter(|u| u["status"] == "failed").collect();
+    assert_eq!(failed.len(), 1);
+    assert!(failed[0]["error"].is_string());
+    let created: Vec<_> = users.iter().filter(|u| u["status"] == "created").collect();
+    assert_eq!(created.len(), 1);
+    assert_eq!(created[0]["track_count"], 10);
+    assert_eq!(created[0]["period"], "month");
+}
+
+#[tokio::test]
+async fn generate_without_users_finishes_without_a_run() {
+    let app = spawn_app().await;
+
+    let status = app.generate("").await;
+
+    assert_eq!(status["state"], "finished");
+    assert!(status["progress"].is_null());
+    assert_eq!(status["users"].as_array().unwrap().len(), 0);
+}
+
+#[tokio::test]
+async fn status_of_an_unknown_job_is_not_found() {
+    let app = spawn_app().await;
+
+    let response = app.get_generate_status("/generate/42").await;
+
+    assert_eq!(response.status().as_u16(), 404);
+}
+
+#[tokio::test]
+async fn generate_status_requires_basic_auth() {
+    let app = spawn_app().await;
+    let response = app.post_generate("").await;
+
+    let response = app
+        .client
+        .get(format!("{}{}", app.address, location(&response)))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status().as_u16(), 401);
+}
+
+#[tokio::test]
+async fn generate_for_many_users_concurrently() {
+    let app = spawn_app().await;
+    let users: Vec<_> = (0..10)
+        .map(|i| FakeUser::new(&format!("user{i}"), 20))
+        .collect();
+    connect_users(&app, users).await;
+
+    let status = app.generate("").await;
+
+    assert_counts(&status, [10, 0, 0, 0]);
+    let mut owners: Vec<_> = app
+        .spotify
+        .playlists()
+        .into_iter()
+        .map(|playlist| playlist.owner)
+        .collect();
+    owners.sort();
+    owners.dedup();
+    assert_eq!(owners.len(), 10);
+}
+
+#[test]
+fn target_month_is_the_last_month_that_ended_in_the_users_time_zone() {
+    let now = chrono::DateTime::parse_from_rfc3339("2026-10-31T12:00:00Z").unwrap();
+    let october = chrono::NaiveDate::from_ymd_opt(2026, 10, 1).unwrap();
+    let september = chrono::NaiveDate::from_ymd_opt(2026, 9, 1).unwrap();
+
+    // Already November 1st, 02:00 on Kiritimati (UTC+14).
+    let kiritimati = now.with_timezone(&chrono_tz::Pacific::Kiritimati);
+    assert!(botm_web::month_ended(october, &kiritimati));
+    assert_eq!(botm_web::target_month(&kiritimati), october);
+
+    // Still October 31st, 02:00 in Honolulu (UTC-10).
+    let honolulu = now.with_timezone(&chrono_tz::Pacific::Honolulu);
+    assert!(!botm_web::month_ended(october, &honolulu));
+    assert!(botm_web::month_ended(september, &honolulu));
+    assert_eq!(botm_web::target_month(&honolulu), september);
+}
+
+/// The top tracks requests for `time_range` as `(limit, offset)`.
+fn pages(requests: &[TopTracksParams], time_range: &str) -> Vec<(usize, usize)> {
+    requests
+        .iter()
+        .filter(|r| r.time_range == time_range)
+        .map(|r| (r.limit, r.offset))
+        .collect()
+}
+
+#[tokio::test]
+async fn generate_pages_through_more_than_50_top_tracks() {
+    let app = spawn_app().await;
+    let alice = FakeUser::new("alice", 120);
+    connect_users(&app, [alice.clone()]).await;
+    let response = app
+        .post_form(
+            "/settings",
+            &[
+                ("timezone", "Europe/Vienna"),
+                ("name_template", "{year}-{month} ({month_short}) BOTM"),
+                ("description_template", "{track_count} bangers"),
+                ("locale", "en_US"),
+                ("time_range", "short_term"),
+                ("track_count", "100"),
+                ("public", "true"),
+                ("cover_style", "none"),
+                ("notifications", "all"),
+            ],
+        )
+        .await;
+    assert_eq!(location(&response), "/settings");
+
+    app.generate("").await;
+
+    let playlists = app.spotify.playlists();
+    assert_eq!(playlists.len(), 1);
+    assert_eq!(playlists[0].tracks, alice.top_tracks[..100]);
+    assert_eq!(
+        pages(&app.spotify.top_tracks_requests(), "short_term"),
+        [(50, 0), (50, 50)]
+    );
+}
+
+#[tokio::test]
+async fn generate_rejects_a_month_that_has_not_ended() {
+    let app = spawn_app().await;
+
+    let current = chrono::Utc::now().date_naive().with_day(1).unwrap();
+    for month in [current.to_string(), "2025-12-15".to_owned()] {
+        let response = app.post_generate(&format!("?month={month}")).await;
+        assert_eq!(response.status().as_u16(), 400, "{month}");
+    }
+    let jobs = sqlx::query_scalar!("SELECT count(*) FROM generation_jobs")
+        .fetch_one(&app.pg_pool)
+        .await
+        .unwrap();
+    assert_eq!(jobs, Some(0));
+}
+
+#[tokio::test]
+async fn generate_creates_quarterly_and_yearly_playlists_at_the_end_of_the_year() {
+    let app = spawn_app().await;
+    let alice = FakeUser::new("alice", 60);
+    connect_users(&app, [alice.clone()]).await;
+    let response = app
+        .post_form(
+            "/settings",
+            &[
+                ("timezone", "Europe/Vienna"),
+                ("name_template", "{year}-{month} ({month_short}) BOTM"),
+                ("description_template", "{track_count} bangers"),
+                ("locale", "en_US"),
+                ("time_range", "short_term"),
+                ("track_count", "60"),
+                ("public", "true"),
+                ("cover_style", "none"),
+                ("notifications", "all"),
+                ("quarterly_playlist", "true"),
+                ("yearly_playlist", "true"),
+            ],
+        )
+        .await;
+    assert_eq!(location(&response), "/settings");
+
+    let status = app.generate("?month=2025-12-01").await;
+
+    assert_counts(&status, [3, 0, 0, 0]);
+    assert_eq!(status["month"], "2025-12-01");
+    let stored = sqlx::query!(
+        "SELECT period, month, playlist_id FROM botm_playlists WHERE spotify_id = 'alice' ORDER BY month DESC"
+    )
+    .fetch_all(&app.pg_pool)
+    .await
+    .unwrap();
+    let stored: Vec<_> = stored
+        .iter()
+        .map(|p| (p.period.as_str(), p.month.to_string()))
+        .collect();
+    assert_eq!(
+        stored,
+        [
+            ("month", "2025-12-01".to_owned()),
+            ("quarter", "2025-10-01".to_owned()),
+            ("year", "2025-01-01".to_owned()),
+        ]
+    );
+
+    let playlists = app.spotify.playlists();
+    assert_eq!(playlists.len(), 3);
+    assert!(playlists
+        .iter()
+        .all(|p| p.owner == "alice" && p.tracks == alice.top_tracks[..60]));
+    let mut names: Vec<_> = playlists.iter().map(|p| p.name.as_str()).collect();
+    names.sort();
+    assert_eq!(names, ["2025 BOTM", "2025 Q4 BOTM", "2025-12 (Dec) BOTM"]);
+
+    let requests = app.spotify.top_tracks_requests();
+    assert_eq!(pages(&requests, "short_term"), [(50, 0), (10, 50)]);
+    assert_eq!(pages(&requests, "medium_term"), [(50, 0), (10, 50)]);
+    assert_eq!(pages(&requests, "long_term"), [(50, 0), (10, 50)]);
+}
+
+#[tokio::test]
+async fn generate_creates_no_quarterly_playlist_in_the_middle_of_a_quarter() {
+    let app = spawn_app().await;
+    connect_users(&app, [FakeUser::new("alice", 10)]).await;
+    let response = app
+        .post_form(
+            "/settings",
+            &[
+                ("timezone", "Europe/Vienna"),
+                ("name_template", "{year}-{month} ({month_short}) BOTM"),
+                ("description_template", "{track_count} bangers"),
+                ("locale", "en_US"),
+                ("time_range", "short_term"),
+                ("track_count", "10"),
+                ("public", "true"),
+                ("cover_style", "none"),
+                ("notifications", "all"),
+                ("quarterly_playlist", "true"),
+                ("yearly_playlist", "true"),
+            ],
+        )
+        .await;
+    assert_eq!(location(&response), "/settings");
+
+    let status = app.generate("?month=2025-11-01").await;
+
+    assert_counts(&status, [1, 0, 0, 0]);
+    let periods = sqlx::query_scalar!("SELECT period FROM botm_playlists")
+        .fetch_all(&app.pg_pool)
+        .await
+        .unwrap();
+    assert_eq!(periods, ["month"]);
+    assert_eq!(
+        app.spotify
+            .top_tracks_requests()
+            .iter()
+            .map(|r| r.time_range.as_str())
+            .collect::<Vec<_>>(),
+        ["short_term"]
+    );
+}
+
+/// Waits for the job to be finished or failed and returns its status.
+async fn wait_for_job(app: &TestApp, job_id: i32) -> Value {
+    for _ in 0..500 {
+        let status: Value = app
+            .get_generate_status(&format!("/generate/{job_id}"))
+            .await
+            .json()
+            .await
+            .unwrap();
+        if status["state"] == "finished" || status["state"] == "failed" {
+            return status;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    panic!("Job {job_id} wasn't done in time");
+}
+
+#[tokio::test]
+async fn job_of_a_crashed_worker_is_requeued() {
+    let app = spawn_app().await;
+    connect_users(&app, [FakeUser::new("alice", 10)]).await;
+    let job_id = sqlx::query_scalar!(
+        r#"INSERT INTO generation_jobs (state, started_at, heartbeat_at)
+            VALUES ('running', now() - interval '10 minutes', now() - interval '10 minutes')
+            RETURNING id"#
+    )
+    .fetch_one(&app.pg_pool)
+    .await
+    .unwrap();
+
+    let status = wait_for_job(&app, job_id).await;
+
+    assert_eq!(status["state"], "finished");
+    assert_counts(&status, [1, 0, 0, 0]);
+    assert_eq!(app.spotify.playlists().len(), 1);
+}
+
+#[tokio::test]
+async fn running_job_with_a_heartbeat_is_left_alone() {
+    let app = spawn_app().await;
+    connect_users(&app, [FakeUser::new("alice", 10)]).await;
+    let job_id = sqlx::query_scalar!(
+        r#"INSERT INTO generation_jobs (state, started_at, heartbeat_at)
+            VALUES ('running', now() - interval '10 minutes', now())
+            RETURNING id"#
+    )
+    .fetch_one(&app.pg_pool)
+    .await
+    .unwrap();
+
+    // Gives the worker a chance to look at the running job
+    app.generate("?spotify_id=nobody").await;
+
+    let state = sqlx::query_scalar!("SELECT state FROM generation_jobs WHERE id = $1", job_id)
+        .fetch_one(&app.pg_pool)
+        .await
+        .unwrap();
+    assert_eq!(state, "running");
+    assert!(app.spotify.playlists().is_empty());
+}
+
+#[tokio::test]
+async fn user_locked_by_a_crashed_instance_is_taken_over() {
+    let app = spawn_app().await;
+    connect_users(&app, [FakeUser::new("alice", 10)]).await;
+    sqlx::query!(
+        "INSERT INTO generation_locks (spotify_id, locked_at) VALUES ('alice', now() - interval '10 minutes')"
+    )
+    .execute(&app.pg_pool)
+    .await
+    .unwrap();
+
+    let status = app.generate("").await;
+
+    assert_counts(&status, [1, 0, 0, 0]);
+    let locks = sqlx::query_scalar!("SELECT count(*) FROM generation_locks")
+        .fetch_one(&app.pg_pool)
+        .await
+        .unwrap();
+    assert_eq!(locks, Some(0));
+}
+
+#[tokio::test]
+async fn job_waits_for_another_instance_generating_for_the_user() {
+    let app = spawn_app().await;
+    connect_users(&app, [FakeUser::new("alice", 10)]).await;
+    // Another instance, e.g. its scheduler, is generating for alice right now
+    sqlx::query!("INSERT INTO generation_locks (spotify_id) VALUES ('alice')")
+        .execute(&app.pg_pool)
+        .await
+        .unwrap();
+
+    let response = app.post_generate("").await;
+    let job_id: i32 = location(&response)
+        .trim_start_matches("/generate/")
+        .parse()
+        .unwrap();
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    let status: Value = app
+        .get_generate_status(&format!("/generate/{job_id}"))
+        .await
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(status["state"], "running");
+    assert_eq!(app.spotify.requests(Endpoint::CreatePlaylist), 0);
+
+    // The other instance is done with the playlist
+    sqlx::query!(
+        r#"INSERT INTO botm_playlists (spotify_id, month, playlist_id, name, track_count)
+            VALUES ('alice', $1, 'playlist-id', 'BOTM', 10)"#,
+        target_month()
+    )
+    .execute(&app.pg_pool)
+    .await
+    .unwrap();
+    sqlx::query!("DELETE FROM generation_locks")
+        .execute(&app.pg_pool)
+        .await
+        .unwrap();
+
+    let status = wait_for_job(&app, job_id).await;
+    assert_counts(&status, [0, 0, 1, 0]);
+    assert_eq!(app.spotify.requests(Endpoint::CreatePlaylist), 0);
+}
+
+#[tokio::test]
+async fn run_taking_too_long_leaves_the_lock_of_the_run_that_took_over() {
+    let app = spawn_app().await;
+    connect_users(&app, [FakeUser::new("alice", 10)]).await;
+    // Keeps the run busy for a second while Spotify asks it to wait
+    app.spotify
+        .fail(Endpoint::TopTracks, StatusCode::TOO_MANY_REQUESTS, 1);
+
+    let response = app.post_generate("").await;
+    let job_id: i32 = location(&response)
+        .trim_start_matches("/generate/")
+        .parse()
+        .unwrap();
+    // Another instance takes the lock over, as if the run had crashed
+    let mut taken_over = 0;
+    for _ in 0..50 {
+        taken_over = sqlx::query!(
+            "UPDATE generation_locks SET locked_at = now() + interval '1 minute' WHERE spotify_id = 'alice'"
+        )
+        .execute(&app.pg_pool)
+        .await
+        .unwrap()
+        .rows_affected();
+        if taken_over == 1 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert_eq!(taken_over, 1);
+
+    let status = wait_for_job(&app, job_id).await;
+    assert_counts(&status, [1, 0, 0, 0]);
+    let locks = sqlx::query_scalar!("SELECT count(*) FROM generation_locks")
+        .fetch_one(&app.pg_pool)
+        .await
+        .unwrap();
+    assert_eq!(locks, Some(1));
+}
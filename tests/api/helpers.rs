@@ -0,0 +1,400 @@
+use std::{
+    collections::HashMap,
+    env,
+    net::TcpListener,
+    sync::{Arc, Once},
+    time::Duration,
+};
+
+use base64::{engine::general_purpose, Engine};
+use botm_web::{
+    oauth_client_from_config, AppConfig, Botm, Configuration, GenerationRetryConfig,
+    GeneratorConfig, ReqwestSpotifyApi, RetryConfig, Scheduler, SchedulerConfig, SessionConfig,
+    SpotifyConfig, TokenCipher, TokenEncryptionConfig, TokenManager,
+};
+use chrono::NaiveDate;
+use reqwest::{header, redirect, Response};
+use secrecy::SecretString;
+use serde_json::Value;
+use sqlx::{Connection, Executor, PgConnection, PgPool};
+use url::Url;
+use uuid::Uuid;
+
+use crate::fake_spotify::{FakeSpotify, FakeUser};
+
+pub const GENERATE_USERNAME: &str = "generate-user";
+pub const GENERATE_PASSWORD: &str = "generate-password";
+/// Attempts per request to Spotify before the app gives up.
+pub const MAX_ATTEMPTS: usize = 3;
+/// Id of the key the app encrypts tokens with.
+pub const TOKEN_KEY_ID: &str = "test-1";
+/// Attempts per user and month before a user needs attention.
+pub const MAX_GENERATION_ATTEMPTS: i32 = 3;
+
+static SET_GENERATE_CREDENTIALS: Once = Once::new();
+
+pub struct TestApp {
+    /// Address of the app without trailing slash, e.g. `http://127.0.0.1:1234`.
+    pub address: String,
+    pub pg_pool: PgPool,
+    pub spotify: FakeSpotify,
+    /// Client of a single browser, keeping cookies but not following redirects.
+    pub client: reqwest::Client,
+    spotify_config: SpotifyConfig,
+    token_encryption: TokenEncryptionConfig,
+    scheduler_config: SchedulerConfig,
+    generator_config: GeneratorConfig,
+}
+
+impl TestApp {
+    /// Goes through `/connect`, the fake Spotify authorization and `/redirect`,
+    /// returning the final response of `/redirect`.
+    ///
+    /// Who authorizes is set with [`FakeSpotify::authorize_as`].
+    pub async fn connect(&self) -> Response {
+        self.connect_in(&self.client).await
+    }
+
+    /// Like [`TestApp::connect`], but in another browser, e.g. one made with [`browser`].
+    pub async fn connect_in(&self, browser: &reqwest::Client) -> Response {
+        let response = self
+            .get_in(browser, "/connect?timezone=Europe/Vienna")
+            .await;
+        assert_eq!(response.status().as_u16(), 302);
+        let authorize = self.follow(browser, response).await;
+        assert_eq!(authorize.status().as_u16(), 302);
+        self.follow(browser, authorize).await
+    }
+
+    /// Connects as `user_id`, who has to be added to the fake Spotify first.
+    pub async fn connect_as(&self, user_id: &str) {
+        self.spotify.authorize_as(Some(user_id));
+        let response = self.connect().await;
+        assert_eq!(location(&response), "/");
+    }
+
+    pub async fn get(&self, path: &str) -> Response {
+        self.get_in(&self.client, path).await
+    }
+
+    pub async fn get_in(&self, browser: &reqwest::Client, path: &str) -> Response {
+        browser
+            .get(format!("{}{path}", self.address))
+            .send()
+            .await
+            .expect("Failed to execute request")
+    }
+
+    pub async fn get_html(&self, path: &str) -> String {
+        self.get(path).await.text().await.unwrap()
+    }
+
+    pub async fn get_html_in(&self, browser: &reqwest::Client, path: &str) -> String {
+        self.get_in(browser, path).await.text().await.unwrap()
+    }
+
+    /// Posts the form like the pages of the app do, with the CSRF token of the session.
+    pub async fn post_form(&self, path: &str, form: &[(&str, &str)]) -> Response {
+        self.post_form_in(&self.client, path, form).await
+    }
+
+    pub async fn post_form_in(
+        &self,
+        browser: &reqwest::Client,
+        path: &str,
+        form: &[(&str, &str)],
+    ) -> Response {
+        let csrf_token = self.csrf_token_in(browser).await;
+        let mut form = form.to_vec();
+        form.push(("csrf_token", &csrf_token));
+        browser
+            .post(format!("{}{path}", self.address))
+            .form(&form)
+            .send()
+            .await
+            .expect("Failed to execute request")
+    }
+
+    /// The CSRF token of the session of a logged in browser, as the settings page has it.
+    pub async fn csrf_token_in(&self, browser: &reqwest::Client) -> String {
+        let html = self.get_html_in(browser, "/settings").await;
+        let (_, rest) = html
+            .split_once(r#"name="csrf_token" value=""#)
+            .expect("No CSRF token on the settings page");
+        rest.split('"').next().unwrap().to_owned()
+    }
+
+    /// Triggers a BOTM run with the basic auth of the cron job, `query` is e.g. `?force=true`.
+    pub async fn post_generate(&self, query: &str) -> Response {
+        self.client
+            .post(format!("{}/generate{query}", self.address))
+            .basic_auth(GENERATE_USERNAME, Some(GENERATE_PASSWORD))
+            .send()
+            .await
+            .expect("Failed to execute request")
+    }
+
+    /// Queues a BOTM run and waits for the job to be done, returning its final status.
+    pub async fn generate(&self, query: &str) -> Value {
+        let response = self.post_generate(query).await;
+        assert_eq!(response.status().as_u16(), 202);
+        let status_path = location(&response);
+        for _ in 0..500 {
+            let status = self.get_generate_status(&status_path).await;
+            assert_eq!(status.status().as_u16(), 200);
+            let status: Value = status.json().await.unwrap();
+            if status["state"] == "finished" || status["state"] == "failed" {
+                return status;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("Job at {status_path} wasn't done in time");
+    }
+
+    /// Requests the status of a generation job, `path` is e.g. `/generate/1`.
+    pub async fn get_generate_status(&self, path: &str) -> Response {
+        self.client
+            .get(format!("{}{path}", self.address))
+            .basic_auth(GENERATE_USERNAME, Some(GENERATE_PASSWORD))
+            .send()
+            .await
+            .expect("Failed to execute request")
+    }
+
+    /// Lets the stored access tokens of all users expire, as they have by the next monthly run.
+    pub async fn expire_access_tokens(&self) {
+        sqlx::query!("UPDATE users SET expiry_timestamp = now() - interval '1 hour'")
+            .execute(&self.pg_pool)
+            .await
+            .expect("Failed to expire access tokens");
+    }
+
+    /// The token cipher of the app.
+    pub fn token_cipher(&self) -> TokenCipher {
+        TokenCipher::from_config(&self.token_encryption).unwrap()
+    }
+
+    /// A token cipher with the keys of the app and a new current key `key_id`,
+    /// as after rotating the key.
+    pub fn rotated_token_cipher(&self, key_id: &str) -> TokenCipher {
+        let mut token_encryption = self.token_encryption.clone();
+        token_encryption
+            .keys
+            .insert(key_id.to_owned(), random_token_key());
+        token_encryption.current_key_id = key_id.to_owned();
+        TokenCipher::from_config(&token_encryption).unwrap()
+    }
+
+    /// A token manager of its own, next to the one of the app, encrypting with `cipher`.
+    pub fn token_manager_with(&self, cipher: TokenCipher) -> TokenManager {
+        let spotify_api = ReqwestSpotifyApi::from_config(&self.spotify_config).unwrap();
+        TokenManager::new(
+            self.pg_pool.clone(),
+            oauth_client_from_config(self.spotify_config.clone()).unwrap(),
+            Arc::new(spotify_api),
+            cipher,
+        )
+    }
+
+    /// A token manager of its own, next to the one of the app.
+    pub fn token_manager(&self) -> TokenManager {
+        self.token_manager_with(self.token_cipher())
+    }
+
+    /// A scheduler of its own against the database of the app, as on another instance.
+    pub fn scheduler(&self) -> Scheduler {
+        let spotify_api = ReqwestSpotifyApi::from_config(&self.spotify_config).unwrap();
+        Scheduler::new(
+            &self.scheduler_config,
+            self.pg_pool.clone(),
+            self.token_manager(),
+            Arc::new(spotify_api),
+            self.generator_config.clone(),
+        )
+        .unwrap()
+    }
+
+    /// Requests the location of a redirect.
+    async fn follow(&self, browser: &reqwest::Client, response: Response) -> Response {
+        let location = location(&response);
+        let url = if location.starts_with('/') {
+            format!("{}{location}", self.address)
+        } else {
+            location
+        };
+        browser
+            .get(url)
+            .send()
+            .await
+            .expect("Failed to execute request")
+    }
+}
+
+/// First day of the month the BOTM of a user in Vienna is generated for right now,
+/// the last one that has ended there.
+pub fn target_month() -> NaiveDate {
+    botm_web::target_month(&chrono::Utc::now().with_timezone(&chrono_tz::Europe::Vienna))
+}
+
+/// The month the test users are in right now.
+pub fn current_month() -> NaiveDate {
+    botm_web::current_month(&chrono::Utc::now().with_timezone(&chrono_tz::Europe::Vienna))
+}
+
+/// Asserts the created, updated, skipped and failed counts of a finished job.
+pub fn assert_counts(status: &Value, [created, updated, skipped, failed]: [u32; 4]) {
+    let progress = &status["progress"];
+    assert_eq!(
+        [
+            &progress["created"],
+            &progress["updated"],
+            &progress["skipped"],
+            &progress["failed"]
+        ],
+        [created, updated, skipped, failed],
+        "{status}"
+    );
+}
+
+/// Adds the users to the fake Spotify and connects each of them.
+pub async fn connect_users(app: &TestApp, users: impl IntoIterator<Item = FakeUser>) {
+    for user in users {
+        let id = user.id.clone();
+        app.spotify.add_user(user);
+        app.connect_as(&id).await;
+    }
+}
+
+/// A fresh base64 encoded key for [`TokenCipher`].
+pub fn random_token_key() -> SecretString {
+    let key: [u8; 32] = rand::random();
+    general_purpose::STANDARD.encode(key).into()
+}
+
+/// Client of a browser of its own, keeping cookies but not following redirects.
+pub fn browser(user_agent: &str) -> reqwest::Client {
+    reqwest::Client::builder()
+        .redirect(redirect::Policy::none())
+        .cookie_store(true)
+        .user_agent(user_agent)
+        .build()
+        .unwrap()
+}
+
+pub fn location(response: &Response) -> String {
+    response
+        .headers()
+        .get(header::LOCATION)
+        .expect("Response is no redirect")
+        .to_str()
+        .unwrap()
+        .to_owned()
+}
+
+/// Starts the app with a fresh database against a fresh fake Spotify.
+pub async fn spawn_app() -> TestApp {
+    SET_GENERATE_CREDENTIALS.call_once(|| {
+        env::set_var("GENERATE_USERNAME", GENERATE_USERNAME);
+        env::set_var("GENERATE_PASSWORD", GENERATE_PASSWORD);
+    });
+
+    let spotify = FakeSpotify::start();
+    let database_url = configure_database().await;
+
+    // The redirect uri has to be known before the app binds its port
+    let port = {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("Failed to bind random port");
+        listener.local_addr().unwrap().port()
+    };
+    let configuration = Configuration {
+        application: AppConfig {
+            port,
+            host: "127.0.0.1".to_owned(),
+        },
+        database_url: Some(database_url.clone().into()),
+        spotify: SpotifyConfig {
+            client_id: "client-id".to_owned().into(),
+            client_secret: "client-secret".to_owned().into(),
+            redirect_uri: format!("http://127.0.0.1:{port}/redirect"),
+            api_base_url: spotify.api_base_url(),
+            accounts_base_url: spotify.accounts_base_url(),
+            retry: RetryConfig {
+                max_attempts: MAX_ATTEMPTS as u32,
+                initial_backoff_ms: 10,
+                max_backoff_ms: 100,
+                max_wait_ms: 5000,
+            },
+            max_requests_per_second: 0,
+        },
+        cron_ips: Vec::new(),
+        cookie_key: "test-cookie-key-which-needs-to-be-at-least-64-bytes-long-for-the-session"
+            .to_owned()
+            .into(),
+        session: SessionConfig { ttl_days: 30 },
+        token_encryption: TokenEncryptionConfig {
+            current_key_id: TOKEN_KEY_ID.to_owned(),
+            keys: HashMap::from([(TOKEN_KEY_ID.to_owned(), random_token_key())]),
+        },
+        scheduler: SchedulerConfig {
+            enabled: false,
+            cron: "0 5 * * * *".to_owned(),
+            timezone: chrono_tz::Tz::UTC,
+        },
+        generator: GeneratorConfig {
+            concurrency: 4,
+            poll_interval_ms: 1000,
+            stale_after_secs: 60,
+            retry: GenerationRetryConfig {
+                max_attempts: MAX_GENERATION_ATTEMPTS,
+                initial_backoff_mins: 60,
+                max_backoff_mins: 1440,
+            },
+        },
+    };
+
+    let spotify_config = configuration.spotify.clone();
+    let token_encryption = configuration.token_encryption.clone();
+    let scheduler_config = configuration.scheduler.clone();
+    let generator_config = configuration.generator.clone();
+    let botm = Botm::build(configuration)
+        .await
+        .expect("Failed to build app");
+    let address = format!("http://127.0.0.1:{}", botm.port());
+    tokio::spawn(botm.run_until_stopped());
+
+    let pg_pool = PgPool::connect(&database_url)
+        .await
+        .expect("Failed to connect to test database");
+    let client = browser("test-browser");
+
+    TestApp {
+        address,
+        pg_pool,
+        spotify,
+        client,
+        spotify_config,
+        token_encryption,
+        scheduler_config,
+        generator_config,
+    }
+}
+
+/// Creates an empty database next to the one in `DATABASE_URL` and returns its url,
+/// the app runs the migrations itself.
+async fn configure_database() -> String {
+    dotenvy::dotenv().ok();
+    let mut url = Url::parse(&env::var("DATABASE_URL").expect("DATABASE_URL must be set"))
+        .expect("Failed to parse DATABASE_URL");
+    let database_name = format!("botm_test_{}", Uuid::new_v4().simple());
+
+    let mut connection = PgConnection::connect(url.as_str())
+        .await
+        .expect("Failed to connect to Postgres");
+    connection
+        .execute(format!(r#"CREATE DATABASE "{database_name}";"#).as_str())
+        .await
+        .expect("Failed to create test database");
+
+    url.set_path(&database_name);
+    url.to_string()
+}
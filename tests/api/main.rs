@@ -0,0 +1,14 @@
+mod connect;
+mod csrf;
+mod dashboard;
+mod export;
+mod fake_spotify;
+mod generate;
+mod helpers;
+mod pause;
+mod preview;
+mod retries;
+mod scheduler;
+mod sessions;
+mod settings;
+mod tokens;
@@ -0,0 +1,174 @@
+use chrono::{Months, Utc};
+use chrono_tz::Tz;
+
+use crate::{
+    fake_spotify::FakeUser,
+    helpers::{assert_counts, connect_users, location, spawn_app, target_month, TestApp},
+};
+
+async fn is_active(app: &TestApp, spotify_id: &str) -> bool {
+    sqlx::query_scalar!("SELECT active FROM users WHERE spotify_id = $1", spotify_id)
+        .fetch_one(&app.pg_pool)
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn paused_users_are_skipped() {
+    let app = spawn_app().await;
+    connect_users(&app, [FakeUser::new("bob", 10), FakeUser::new("alice", 10)]).await;
+
+    let response = app.post_form("/pause", &[("resume_on", "")]).await;
+
+    assert_eq!(location(&response), "/");
+    assert!(app
+        .get_html("/")
+        .await
+        .contains("Your BOTM is paused, no playlists are created until you resume it."));
+    app.generate("").await;
+    let playlists = app.spotify.playlists();
+    assert_eq!(playlists.len(), 1);
+    assert_eq!(playlists[0].owner, "bob");
+}
+
+#[tokio::test]
+async fn resumed_users_are_generated_for_again() {
+    let app = spawn_app().await;
+    connect_users(&app, [FakeUser::new("alice", 10)]).await;
+    app.post_form("/pause", &[("resume_on", "")]).await;
+
+    let response = app.post_form("/resume", &[]).await;
+
+    assert_eq!(location(&response), "/");
+    assert!(is_active(&app, "alice").await);
+    let status = app.generate("").await;
+    assert_counts(&status, [1, 0, 0, 0]);
+}
+
+#[tokio::test]
+async fn paused_users_are_resumed_on_their_date() {
+    let app = spawn_app().await;
+    connect_users(&app, [FakeUser::new("alice", 10)]).await;
+    let resume_on = chrono::Utc::now().date_naive() + chrono::Duration::days(40);
+    app.post_form("/pause", &[("resume_on", &resume_on.to_string())])
+        .await;
+    assert!(app
+        .get_html("/")
+        .await
+        .contains(&format!("Your BOTM is paused until {resume_on}.")));
+    assert!(app.generate("").await["progress"].is_null());
+
+    sqlx::query!("UPDATE users SET resume_on = CURRENT_DATE - 1")
+        .execute(&app.pg_pool)
+        .await
+        .unwrap();
+    let status = app.generate("").await;
+
+    assert_counts(&status, [1, 0, 0, 0]);
+    assert!(is_active(&app, "alice").await);
+}
+
+/// Moves the start of the pause of the user back to the start of the month BOTM would generate now.
+async fn paused_since_target_month(app: &TestApp, spotify_id: &str) {
+    sqlx::query!(
+        "UPDATE generation_pauses SET paused_at = $1 WHERE spotify_id = $2",
+        target_month().and_time(chrono::NaiveTime::MIN).and_utc(),
+        spotify_id
+    )
+    .execute(&app.pg_pool)
+    .await
+    .unwrap();
+}
+
+#[tokio::test]
+async fn month_that_ended_while_paused_is_not_created_after_resuming() {
+    let app = spawn_app().await;
+    connect_users(&app, [FakeUser::new("alice", 10)]).await;
+    app.post_form("/pause", &[("resume_on", "")]).await;
+    paused_since_target_month(&app, "alice").await;
+
+    app.post_form("/resume", &[]).await;
+    let status = app.generate("?due_only=true").await;
+
+    assert!(is_active(&app, "alice").await);
+    assert!(status["progress"].is_null());
+    assert!(app.spotify.playlists().is_empty());
+}
+
+#[tokio::test]
+async fn month_that_ended_on_the_resume_date_is_created() {
+    let app = spawn_app().await;
+    connect_users(&app, [FakeUser::new("alice", 10)]).await;
+    app.post_form("/pause", &[("resume_on", "")]).await;
+    paused_since_target_month(&app, "alice").await;
+    // The run resuming alice is late, the month still ended the midnight she resumed on
+    sqlx::query!(
+        "UPDATE users SET resume_on = $1",
+        target_month() + Months::new(1)
+    )
+    .execute(&app.pg_pool)
+    .await
+    .unwrap();
+
+    let status = app.generate("?due_only=true").await;
+
+    assert_counts(&status, [1, 0, 0, 0]);
+    assert!(is_active(&app, "alice").await);
+}
+
+#[tokio::test]
+async fn reconnecting_keeps_the_pause() {
+    let app = spawn_app().await;
+    connect_users(&app, [FakeUser::new("alice", 10)]).await;
+    app.post_form("/pause", &[("resume_on", "")]).await;
+
+    app.connect_as("alice").await;
+
+    assert!(!is_active(&app, "alice").await);
+    assert!(app.get_html("/").await.contains("Your BOTM is paused"));
+}
+
+#[tokio::test]
+async fn resume_date_has_to_be_in_the_future() {
+    let app = spawn_app().await;
+    connect_users(&app, [FakeUser::new("alice", 10)]).await;
+
+    let response = app
+        .post_form("/pause", &[("resume_on", "2000-01-01")])
+        .await;
+
+    assert_eq!(location(&response), "/");
+    assert!(app
+        .get_html("/")
+        .await
+        .contains("The resume date must be a day in the future."));
+    assert!(is_active(&app, "alice").await);
+}
+
+#[tokio::test]
+async fn resume_date_is_checked_in_the_time_zone_of_the_user() {
+    let app = spawn_app().await;
+    connect_users(&app, [FakeUser::new("alice", 10)]).await;
+    // Today for the first time zone to reach it, tomorrow or later for the last one
+    let today = Utc::now().with_timezone(&Tz::Etc__GMTMinus14).date_naive();
+
+    sqlx::query!("UPDATE users SET timezone = 'Etc/GMT-14'")
+        .execute(&app.pg_pool)
+        .await
+        .unwrap();
+    app.post_form("/pause", &[("resume_on", &today.to_string())])
+        .await;
+    assert!(app
+        .get_html("/")
+        .await
+        .contains("The resume date must be a day in the future."));
+    assert!(is_active(&app, "alice").await);
+
+    sqlx::query!("UPDATE users SET timezone = 'Etc/GMT+12'")
+        .execute(&app.pg_pool)
+        .await
+        .unwrap();
+    app.post_form("/pause", &[("resume_on", &today.to_string())])
+        .await;
+    assert!(!is_active(&app, "alice").await);
+}
@@ -0,0 +1,83 @@
+use crate::{
+    fake_spotify::{Endpoint, FakeUser},
+    helpers::{connect_users, current_month, location, spawn_app, target_month},
+};
+
+#[tokio::test]
+async fn preview_shows_the_top_tracks_without_creating_a_playlist() {
+    let app = spawn_app().await;
+    connect_users(&app, [FakeUser::new("alice", 3)]).await;
+
+    let html = app.get_html("/preview").await;
+
+    for i in 0..3 {
+        assert!(html.contains(&format!("Track alice{i}")), "{html}");
+        assert!(html.contains(&format!("Artist alice{i}")));
+        // The smallest cover
+        assert!(html.contains(&format!("https://i.scdn.co/image/alice{i}-64\"")));
+    }
+    assert!(html.find("Track alice0") < html.find("Track alice1"));
+    assert!(html.contains(&current_month().format("%B %Y").to_string()));
+    assert!(app.spotify.playlists().is_empty());
+    assert_eq!(app.spotify.requests(Endpoint::CreatePlaylist), 0);
+}
+
+#[tokio::test]
+async fn preview_uses_the_settings_of_the_user() {
+    let app = spawn_app().await;
+    connect_users(&app, [FakeUser::new("alice", 10)]).await;
+    let response = app
+        .post_form(
+            "/settings",
+            &[
+                ("timezone", "Europe/Vienna"),
+                ("name_template", "BOTM {year}/{month}"),
+                ("description_template", ""),
+                ("locale", "en_US"),
+                ("time_range", "short_term"),
+                ("track_count", "2"),
+                ("public", "true"),
+                ("cover_style", "gradient"),
+                ("notifications", "all"),
+            ],
+        )
+        .await;
+    assert_eq!(location(&response), "/settings");
+
+    let html = app.get_html("/preview").await;
+
+    assert!(html.contains(&current_month().format("BOTM %Y/%m").to_string()));
+    assert!(html.contains("Track alice1"));
+    assert!(!html.contains("Track alice2"));
+}
+
+#[tokio::test]
+async fn preview_does_not_create_the_current_month() {
+    let app = spawn_app().await;
+    connect_users(&app, [FakeUser::new("alice", 3)]).await;
+    // Last month is done already, that isn't what the preview is about
+    app.generate("").await;
+
+    let html = app.get_html("/preview").await;
+
+    assert!(html.contains(&current_month().format("%B %Y").to_string()));
+    assert!(!html.contains(&target_month().format("%B %Y").to_string()));
+    assert!(!html.contains("<form"));
+    let response = app.post_form("/preview", &[]).await;
+    assert_eq!(response.text().await.unwrap(), "404 Page not found");
+    let jobs = sqlx::query_scalar!("SELECT count(*) FROM generation_jobs")
+        .fetch_one(&app.pg_pool)
+        .await
+        .unwrap();
+    assert_eq!(jobs, Some(1));
+    assert_eq!(app.spotify.playlists().len(), 1);
+}
+
+#[tokio::test]
+async fn preview_requires_login() {
+    let app = spawn_app().await;
+
+    let response = app.get("/preview").await;
+
+    assert_eq!(location(&response), "/");
+}
@@ -0,0 +1,185 @@
+use actix_web::http::StatusCode;
+
+use crate::{
+    fake_spotify::{Endpoint, FakeUser},
+    helpers::{
+        assert_counts, connect_users, location, spawn_app, target_month, TestApp,
+        MAX_GENERATION_ATTEMPTS,
+    },
+};
+
+/// Makes the pending retries of all users due right away.
+async fn make_retries_due(app: &TestApp) {
+    sqlx::query!("UPDATE generation_retries SET next_attempt_at = now() - interval '1 minute'")
+        .execute(&app.pg_pool)
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn failed_user_is_scheduled_for_a_retry() {
+    let app = spawn_app().await;
+    connect_users(&app, [FakeUser::new("alice", 10)]).await;
+    app.spotify
+        .fail(Endpoint::TopTracks, StatusCode::FORBIDDEN, 1);
+
+    app.generate("").await;
+
+    let retry = sqlx::query!(
+        r#"SELECT month, period, attempts, last_error, needs_attention, next_attempt_at > now() AS "later!"
+            FROM generation_retries WHERE spotify_id = 'alice'"#
+    )
+    .fetch_one(&app.pg_pool)
+    .await
+    .expect("No retry was scheduled");
+    assert_eq!(retry.month, target_month());
+    assert_eq!(retry.period, "month");
+    assert_eq!(retry.attempts, 1);
+    assert!(!retry.last_error.is_empty());
+    assert!(!retry.needs_attention);
+    assert!(retry.later);
+}
+
+#[tokio::test]
+async fn retry_fills_the_playlist_created_before_the_failure() {
+    let app = spawn_app().await;
+    connect_users(&app, [FakeUser::new("alice", 10)]).await;
+    app.spotify
+        .fail(Endpoint::AddTracks, StatusCode::INTERNAL_SERVER_ERROR, 1);
+
+    assert_counts(&app.generate("?due_only=true").await, [0, 0, 0, 1]);
+    let pending = sqlx::query_scalar!("SELECT pending FROM botm_playlists")
+        .fetch_one(&app.pg_pool)
+        .await
+        .unwrap();
+    assert!(pending);
+    make_retries_due(&app).await;
+    assert_counts(&app.generate("?due_only=true").await, [1, 0, 0, 0]);
+
+    let playlists = app.spotify.playlists();
+    assert_eq!(playlists.len(), 1);
+    assert_eq!(playlists[0].tracks.len(), 10);
+    assert_eq!(app.spotify.requests(Endpoint::CreatePlaylist), 1);
+    let stored = sqlx::query!("SELECT playlist_id, track_count, pending FROM botm_playlists")
+        .fetch_one(&app.pg_pool)
+        .await
+        .unwrap();
+    assert_eq!(stored.playlist_id, playlists[0].id);
+    assert_eq!(stored.track_count, 10);
+    assert!(!stored.pending);
+}
+
+#[tokio::test]
+async fn failed_quarterly_playlist_is_retried_as_such() {
+    let app = spawn_app().await;
+    connect_users(&app, [FakeUser::new("alice", 10)]).await;
+    let response = app
+        .post_form(
+            "/settings",
+            &[
+                ("timezone", "Europe/Vienna"),
+                ("name_template", "{year}-{month} ({month_short}) BOTM"),
+                ("description_template", "{track_count} bangers"),
+                ("locale", "en_US"),
+                ("time_range", "short_term"),
+                ("track_count", "10"),
+                ("public", "true"),
+                ("cover_style", "none"),
+                ("notifications", "all"),
+                ("quarterly_playlist", "true"),
+            ],
+        )
+        .await;
+    assert_eq!(location(&response), "/settings");
+    app.spotify
+        .fail(Endpoint::CreatePlaylist, StatusCode::FORBIDDEN, 1);
+
+    // The monthly BOTM already exists, only the quarterly playlist is created and fails
+    sqlx::query!(
+        r#"INSERT INTO botm_playlists (spotify_id, month, playlist_id, name, track_count)
+            VALUES ('alice', '2025-12-01', 'playlist-id', 'BOTM', 10)"#
+    )
+    .execute(&app.pg_pool)
+    .await
+    .unwrap();
+    let status = app.generate("?month=2025-12-01").await;
+
+    assert_counts(&status, [0, 0, 0, 1]);
+    let retry = sqlx::query!("SELECT month, period, last_error FROM generation_retries")
+        .fetch_one(&app.pg_pool)
+        .await
+        .unwrap();
+    assert_eq!(retry.month.to_string(), "2025-12-01");
+    assert_eq!(retry.period, "quarter");
+    assert!(
+        retry
+            .last_error
+            .starts_with("Failed to generate the quarter playlist"),
+        "{}",
+        retry.last_error
+    );
+}
+
+#[tokio::test]
+async fn retry_waits_for_its_backoff() {
+    let app = spawn_app().await;
+    connect_users(&app, [FakeUser::new("alice", 10)]).await;
+    app.spotify
+        .fail(Endpoint::TopTracks, StatusCode::FORBIDDEN, 1);
+    app.generate("").await;
+
+    let status = app.generate("?due_only=true").await;
+
+    assert!(status["progress"].is_null(), "{status}");
+    assert!(app.spotify.playlists().is_empty());
+}
+
+#[tokio::test]
+async fn due_retry_generates_the_playlist() {
+    let app = spawn_app().await;
+    connect_users(&app, [FakeUser::new("alice", 10)]).await;
+    app.spotify
+        .fail(Endpoint::TopTracks, StatusCode::FORBIDDEN, 1);
+    app.generate("").await;
+    make_retries_due(&app).await;
+
+    let status = app.generate("?due_only=true").await;
+
+    assert_counts(&status, [1, 0, 0, 0]);
+    assert_eq!(app.spotify.playlists().len(), 1);
+    let retries = sqlx::query_scalar!("SELECT COUNT(*) FROM generation_retries")
+        .fetch_one(&app.pg_pool)
+        .await
+        .unwrap();
+    assert_eq!(retries, Some(0));
+}
+
+#[tokio::test]
+async fn user_needs_attention_after_the_last_attempt() {
+    let app = spawn_app().await;
+    connect_users(&app, [FakeUser::new("alice", 10)]).await;
+    app.spotify.fail(
+        Endpoint::TopTracks,
+        StatusCode::FORBIDDEN,
+        MAX_GENERATION_ATTEMPTS as usize,
+    );
+
+    app.generate("").await;
+    for _ in 1..MAX_GENERATION_ATTEMPTS {
+        make_retries_due(&app).await;
+        let status = app.generate("?due_only=true").await;
+        assert_counts(&status, [0, 0, 0, 1]);
+    }
+
+    let retry = sqlx::query!("SELECT attempts, needs_attention FROM generation_retries")
+        .fetch_one(&app.pg_pool)
+        .await
+        .unwrap();
+    assert_eq!(retry.attempts, MAX_GENERATION_ATTEMPTS);
+    assert!(retry.needs_attention);
+
+    make_retries_due(&app).await;
+    let status = app.generate("?due_only=true").await;
+    assert!(status["progress"].is_null(), "{status}");
+    assert!(app.spotify.playlists().is_empty());
+}
@@ -0,0 +1,138 @@
+use botm_web::{SchedulerConfig, SCHEDULER_LOCK_KEY};
+use chrono::TimeZone;
+use config::{Config, File, FileFormat};
+
+use crate::{
+    fake_spotify::{Endpoint, FakeUser},
+    helpers::{connect_users, spawn_app, target_month},
+};
+
+fn scheduler_config(yaml: &str) -> Result<SchedulerConfig, config::ConfigError> {
+    Config::builder()
+        .add_source(File::from_str(yaml, FileFormat::Yaml))
+        .build()?
+        .try_deserialize()
+}
+
+#[test]
+fn scheduler_config_is_parsed() {
+    let config = scheduler_config(
+        r#"
+enabled: true
+cron: "0 5 * * * *"
+timezone: "Pacific/Honolulu"
+"#,
+    )
+    .unwrap();
+    assert!(config.enabled);
+    assert_eq!(config.timezone, chrono_tz::Pacific::Honolulu);
+
+    // Evaluated in the configured time zone: five past every hour there.
+    let now = config
+        .timezone
+        .with_ymd_and_hms(2026, 10, 31, 23, 30, 0)
+        .unwrap();
+    let next = config.schedule().unwrap().after(&now).next().unwrap();
+    assert_eq!(
+        next,
+        config
+            .timezone
+            .with_ymd_and_hms(2026, 11, 1, 0, 5, 0)
+            .unwrap()
+    );
+}
+
+#[test]
+fn base_config_has_a_valid_scheduler() {
+    let config: SchedulerConfig = Config::builder()
+        .add_source(File::with_name("config/base"))
+        .build()
+        .unwrap()
+        .get("scheduler")
+        .unwrap();
+    assert!(config.schedule().is_ok());
+}
+
+#[test]
+fn invalid_scheduler_config_is_rejected() {
+    let unknown_timezone = scheduler_config(
+        r#"
+enabled: true
+cron: "0 5 * * * *"
+timezone: "Europe/Atlantis"
+"#,
+    );
+    assert!(unknown_timezone.is_err());
+
+    // Without the seconds field the cron crate expects.
+    let config = scheduler_config(
+        r#"
+enabled: true
+cron: "5 * * * *"
+timezone: "UTC"
+"#,
+    )
+    .unwrap();
+    assert!(config.schedule().is_err());
+}
+
+#[tokio::test]
+async fn tick_generates_for_due_users() {
+    let app = spawn_app().await;
+    connect_users(&app, [FakeUser::new("alice", 10), FakeUser::new("bob", 10)]).await;
+
+    app.scheduler().tick().await.unwrap();
+
+    let playlists = sqlx::query!("SELECT spotify_id, month FROM botm_playlists")
+        .fetch_all(&app.pg_pool)
+        .await
+        .unwrap();
+    assert_eq!(playlists.len(), 2);
+    assert!(playlists.iter().all(|p| p.month == target_month()));
+}
+
+#[tokio::test]
+async fn concurrent_ticks_generate_only_once() {
+    let app = spawn_app().await;
+    connect_users(
+        &app,
+        (0..5).map(|i| FakeUser::new(&format!("user-{i}"), 10)),
+    )
+    .await;
+    let (first, second) = (app.scheduler(), app.scheduler());
+
+    let (a, b) = tokio::join!(first.tick(), second.tick());
+    a.unwrap();
+    b.unwrap();
+
+    let runs = sqlx::query_scalar!("SELECT count(*) FROM botm_runs")
+        .fetch_one(&app.pg_pool)
+        .await
+        .unwrap();
+    assert_eq!(runs, Some(1));
+    assert_eq!(app.spotify.playlists().len(), 5);
+    assert_eq!(app.spotify.requests(Endpoint::CreatePlaylist), 5);
+}
+
+#[tokio::test]
+async fn tick_skips_while_another_instance_holds_the_lock() {
+    let app = spawn_app().await;
+    connect_users(&app, [FakeUser::new("alice", 10)]).await;
+
+    // Advisory locks belong to the connection, like the one of another instance.
+    let mut other_instance = app.pg_pool.acquire().await.unwrap();
+    sqlx::query_scalar!("SELECT pg_advisory_lock($1)", SCHEDULER_LOCK_KEY)
+        .fetch_one(&mut *other_instance)
+        .await
+        .unwrap();
+
+    app.scheduler().tick().await.unwrap();
+    assert!(app.spotify.playlists().is_empty());
+
+    sqlx::query_scalar!("SELECT pg_advisory_unlock($1)", SCHEDULER_LOCK_KEY)
+        .fetch_one(&mut *other_instance)
+        .await
+        .unwrap();
+    app.scheduler().tick().await.unwrap();
+    assert_eq!(app.spotify.playlists().len(), 1);
+}
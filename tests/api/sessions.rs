@@ -0,0 +1,126 @@
+use std::collections::HashMap;
+
+use actix_session::storage::SessionStore;
+use actix_web::cookie::time::Duration;
+use botm_web::{delete_user_sessions, PgSessionStore};
+
+use crate::{
+    fake_spotify::FakeUser,
+    helpers::{browser, connect_users, location, spawn_app},
+};
+
+#[tokio::test]
+async fn sessions_are_stored_in_the_database() {
+    let app = spawn_app().await;
+    connect_users(&app, [FakeUser::new("alice", 10)]).await;
+
+    let sessions = sqlx::query!("SELECT spotify_id, user_agent FROM sessions")
+        .fetch_all(&app.pg_pool)
+        .await
+        .unwrap();
+
+    // The one of connecting was replaced on login
+    assert_eq!(sessions.len(), 1);
+    assert_eq!(sessions[0].spotify_id.as_deref(), Some("alice"));
+    assert_eq!(sessions[0].user_agent.as_deref(), Some("test-browser"));
+}
+
+#[tokio::test]
+async fn disconnect_logs_out_other_browsers() {
+    let app = spawn_app().await;
+    connect_users(&app, [FakeUser::new("alice", 10)]).await;
+    let phone = browser("phone");
+    app.connect_in(&phone).await;
+    assert!(app
+        .get_html_in(&phone, "/")
+        .await
+        .contains("alice display name"));
+
+    app.post_form("/disconnect", &[]).await;
+
+    let html = app.get_html_in(&phone, "/").await;
+    assert!(!html.contains("alice display name"));
+    assert!(html.contains("Connect Spotify"));
+}
+
+#[tokio::test]
+async fn logout_of_all_devices_ends_every_session() {
+    let app = spawn_app().await;
+    connect_users(&app, [FakeUser::new("alice", 10)]).await;
+    let phone = browser("phone");
+    app.connect_in(&phone).await;
+    let bob = browser("bob");
+    app.spotify.add_user(FakeUser::new("bob", 10));
+    app.spotify.authorize_as(Some("bob"));
+    app.connect_in(&bob).await;
+
+    let response = app.post_form_in(&phone, "/logout/all", &[]).await;
+
+    assert_eq!(location(&response), "/");
+    assert!(app.get_html("/").await.contains("Connect Spotify"));
+    assert!(app
+        .get_html_in(&phone, "/")
+        .await
+        .contains("Connect Spotify"));
+    // Sessions of other users stay
+    assert!(app
+        .get_html_in(&bob, "/")
+        .await
+        .contains("bob display name"));
+    let users = sqlx::query_scalar!("SELECT COUNT(*) FROM users")
+        .fetch_one(&app.pg_pool)
+        .await
+        .unwrap();
+    assert_eq!(users, Some(2));
+}
+
+#[tokio::test]
+async fn settings_list_the_sessions_of_the_user() {
+    let app = spawn_app().await;
+    connect_users(&app, [FakeUser::new("alice", 10)]).await;
+    app.connect_in(&browser("phone")).await;
+    app.spotify.add_user(FakeUser::new("bob", 10));
+    app.spotify.authorize_as(Some("bob"));
+    app.connect_in(&browser("bobs-browser")).await;
+
+    let html = app.get_html("/settings").await;
+
+    assert!(html.contains("test-browser"), "{html}");
+    assert!(html.contains("phone"));
+    assert!(!html.contains("bobs-browser"));
+}
+
+#[tokio::test]
+async fn expired_session_is_logged_out() {
+    let app = spawn_app().await;
+    connect_users(&app, [FakeUser::new("alice", 10)]).await;
+
+    sqlx::query!("UPDATE sessions SET expires_at = now() - interval '1 minute'")
+        .execute(&app.pg_pool)
+        .await
+        .unwrap();
+
+    assert!(app.get_html("/").await.contains("Connect Spotify"));
+}
+
+#[tokio::test]
+async fn request_in_flight_during_logout_does_not_log_in_again() {
+    let app = spawn_app().await;
+    connect_users(&app, [FakeUser::new("alice", 10)]).await;
+    let store = PgSessionStore::new(app.pg_pool.clone());
+    let ttl = Duration::days(30);
+    let state = HashMap::from([("login".to_owned(), r#""alice""#.to_owned())]);
+    let session_key = store.save(state.clone(), &ttl).await.unwrap();
+
+    // Logged out of all devices after the request loaded its session, before it saved it
+    delete_user_sessions(&app.pg_pool, "alice").await.unwrap();
+    let session_key = store.update(session_key, state, &ttl).await.unwrap();
+
+    let state = store.load(&session_key).await.unwrap().unwrap();
+    assert!(state.is_empty());
+    let logins = sqlx::query_scalar!("SELECT count(*) FROM sessions WHERE spotify_id = 'alice'")
+        .fetch_one(&app.pg_pool)
+        .await
+        .unwrap();
+    assert_eq!(logins, Some(0));
+}
@@ -0,0 +1,146 @@
+use crate::{
+    fake_spotify::FakeUser,
+    helpers::{connect_users, location, spawn_app, TestApp},
+};
+
+/// Posts the settings form with the defaults, except for `changes`.
+async fn post_settings(app: &TestApp, changes: &[(&str, &str)]) -> reqwest::Response {
+    let mut form = vec![
+        ("timezone", "Europe/Vienna"),
+        ("name_template", "{year}-{month} ({month_short}) BOTM"),
+        ("description_template", ""),
+        ("locale", "en_US"),
+        ("time_range", "short_term"),
+        ("track_count", "50"),
+        ("public", "true"),
+        ("cover_style", "gradient"),
+        ("notifications", "all"),
+    ];
+    for (key, value) in changes {
+        match form.iter_mut().find(|(k, _)| k == key) {
+            Some(field) => field.1 = value,
+            None => form.push((key, value)),
+        }
+    }
+    app.post_form("/settings", &form).await
+}
+
+#[tokio::test]
+async fn settings_require_login() {
+    let app = spawn_app().await;
+
+    let response = app.get("/settings").await;
+
+    assert_eq!(location(&response), "/");
+}
+
+#[tokio::test]
+async fn settings_show_the_defaults() {
+    let app = spawn_app().await;
+    connect_users(&app, [FakeUser::new("alice", 10)]).await;
+
+    let html = app.get_html("/settings").await;
+
+    assert!(
+        html.contains(r#"value="{year}-{month} ({month_short}) BOTM""#),
+        "{html}"
+    );
+    assert!(html.contains(r#"<option value="short_term" selected>"#));
+    assert!(html.contains(r#"<option value="Europe/Vienna" selected>"#));
+}
+
+#[tokio::test]
+async fn saved_settings_are_shown() {
+    let app = spawn_app().await;
+    connect_users(&app, [FakeUser::new("alice", 10)]).await;
+
+    let response = post_settings(
+        &app,
+        &[
+            ("name_template", "BOTM {month_name}"),
+            ("time_range", "long_term"),
+            ("track_count", "20"),
+            ("yearly_playlist", "true"),
+            ("notifications", "failures"),
+        ],
+    )
+    .await;
+
+    assert_eq!(location(&response), "/settings");
+    let html = app.get_html("/settings").await;
+    assert!(html.contains("Settings saved."), "{html}");
+    assert!(html.contains(r#"value="BOTM {month_name}""#));
+    assert!(html.contains(r#"<option value="long_term" selected>"#));
+    assert!(html.contains(r#"value="20""#));
+    assert!(html.contains(r#"<option value="failures" selected>"#));
+    let settings = sqlx::query!(
+        "SELECT time_range, track_count, yearly_playlist, notifications FROM user_settings WHERE spotify_id = 'alice'"
+    )
+    .fetch_one(&app.pg_pool)
+    .await
+    .unwrap();
+    assert_eq!(settings.time_range, "long_term");
+    assert_eq!(settings.track_count, 20);
+    assert!(settings.yearly_playlist);
+    assert_eq!(settings.notifications, "failures");
+}
+
+#[tokio::test]
+async fn invalid_settings_are_rejected_with_a_message() {
+    let app = spawn_app().await;
+    connect_users(&app, [FakeUser::new("alice", 10)]).await;
+
+    let cases = [
+        (
+            ("track_count", "0"),
+            "The number of tracks must be between 1 and 100.",
+        ),
+        (
+            ("name_template", "{nope}"),
+            "Invalid playlist name: unknown placeholder {nope}.",
+        ),
+        (("time_range", "forever"), "Unknown time range"),
+        (("notifications", "loud"), "Unknown notifications"),
+        (("timezone", "Mars/Olympus"), "Unknown time zone"),
+        (
+            ("collaborative", "true"),
+            "Collaborative playlists can't be public.",
+        ),
+    ];
+    for (change, message) in cases {
+        let response = post_settings(&app, &[change]).await;
+
+        assert_eq!(location(&response), "/settings");
+        let html = app.get_html("/settings").await;
+        assert!(html.contains(message), "{change:?}: {html}");
+    }
+    let saved = sqlx::query_scalar!("SELECT COUNT(*) FROM user_settings")
+        .fetch_one(&app.pg_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved, Some(0));
+}
+
+#[tokio::test]
+async fn time_zone_is_not_saved_without_the_settings() {
+    let app = spawn_app().await;
+    connect_users(&app, [FakeUser::new("alice", 10)]).await;
+    // Makes saving the settings fail after the time zone was updated
+    sqlx::query!("ALTER TABLE user_settings ADD CONSTRAINT few_tracks CHECK (track_count < 20)")
+        .execute(&app.pg_pool)
+        .await
+        .unwrap();
+
+    let response = post_settings(
+        &app,
+        &[("timezone", "America/New_York"), ("track_count", "20")],
+    )
+    .await;
+
+    assert_eq!(location(&response), "/settings");
+    let timezone = sqlx::query_scalar!("SELECT timezone FROM users WHERE spotify_id = 'alice'")
+        .fetch_one(&app.pg_pool)
+        .await
+        .unwrap();
+    assert_eq!(timezone.as_deref(), Some("Europe/Vienna"));
+}
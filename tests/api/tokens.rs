@@ -0,0 +1,194 @@
+use botm_web::{encrypt_plaintext_tokens, reencrypt_tokens, TokenKind};
+use secrecy::ExposeSecret;
+
+use crate::{
+    fake_spotify::{Endpoint, FakeUser},
+    helpers::{connect_users, spawn_app, TOKEN_KEY_ID},
+};
+
+#[tokio::test]
+async fn generate_reuses_a_valid_access_token() {
+    let app = spawn_app().await;
+    connect_users(&app, [FakeUser::new("alice", 10)]).await;
+
+    app.generate("").await;
+    app.generate("?force=true").await;
+
+    // Only the token exchange of connecting
+    assert_eq!(app.spotify.requests(Endpoint::Token), 1);
+    assert_eq!(app.spotify.playlists().len(), 1);
+}
+
+#[tokio::test]
+async fn expired_access_token_is_refreshed_and_stored() {
+    let app = spawn_app().await;
+    connect_users(&app, [FakeUser::new("alice", 10)]).await;
+    app.expire_access_tokens().await;
+
+    app.generate("").await;
+    app.generate("?force=true").await;
+
+    assert_eq!(app.spotify.requests(Endpoint::Token), 2);
+    let valid = sqlx::query_scalar!(r#"SELECT expiry_timestamp > now() AS "valid!" FROM users"#)
+        .fetch_one(&app.pg_pool)
+        .await
+        .unwrap();
+    assert!(valid);
+}
+
+#[tokio::test]
+async fn rotated_refresh_token_is_stored() {
+    let app = spawn_app().await;
+    connect_users(&app, [FakeUser::new("alice", 10)]).await;
+    app.spotify.rotate_refresh_tokens();
+    let old_refresh_token = sqlx::query_scalar!("SELECT refresh_token FROM users")
+        .fetch_one(&app.pg_pool)
+        .await
+        .unwrap();
+
+    app.expire_access_tokens().await;
+    app.generate("").await;
+    app.expire_access_tokens().await;
+    let status = app.generate("?force=true").await;
+
+    assert_eq!(status["progress"]["updated"], 1, "{status}");
+    let refresh_token = sqlx::query_scalar!("SELECT refresh_token FROM users")
+        .fetch_one(&app.pg_pool)
+        .await
+        .unwrap();
+    assert_ne!(refresh_token, old_refresh_token);
+}
+
+#[tokio::test]
+async fn concurrent_requests_refresh_only_once() {
+    let app = spawn_app().await;
+    connect_users(&app, [FakeUser::new("alice", 10)]).await;
+    app.expire_access_tokens().await;
+    let tokens = [app.token_manager(), app.token_manager()];
+
+    let access_tokens = futures_util::future::join_all(
+        (0..6).map(|i| tokens[i % tokens.len()].access_token("alice")),
+    )
+    .await;
+
+    let access_tokens: Vec<_> = access_tokens
+        .into_iter()
+        .map(|token| token.unwrap().expose_secret().clone())
+        .collect();
+    assert!(access_tokens.iter().all(|token| token == &access_tokens[0]));
+    assert_eq!(app.spotify.requests(Endpoint::Token), 2);
+}
+
+#[tokio::test]
+async fn tokens_are_stored_encrypted() {
+    let app = spawn_app().await;
+    connect_users(&app, [FakeUser::new("alice", 10)]).await;
+
+    let user = sqlx::query!("SELECT refresh_token, access_token, token_key_id FROM users")
+        .fetch_one(&app.pg_pool)
+        .await
+        .unwrap();
+    assert_eq!(user.token_key_id.as_deref(), Some(TOKEN_KEY_ID));
+    assert!(!user.refresh_token.contains("alice"));
+    let refresh_token = app
+        .token_cipher()
+        .decrypt(
+            "alice",
+            TokenKind::Refresh,
+            Some(TOKEN_KEY_ID),
+            &user.refresh_token,
+        )
+        .unwrap();
+    assert!(refresh_token.expose_secret().starts_with("refresh-alice-"));
+    // Bound to the column, the refresh token doesn't decrypt as access token
+    assert!(app
+        .token_cipher()
+        .decrypt(
+            "alice",
+            TokenKind::Access,
+            Some(TOKEN_KEY_ID),
+            &user.refresh_token
+        )
+        .is_err());
+}
+
+#[tokio::test]
+async fn plaintext_tokens_are_encrypted() {
+    let app = spawn_app().await;
+    connect_users(&app, [FakeUser::new("alice", 10)]).await;
+    let access_token = app.token_manager().access_token("alice").await.unwrap();
+    let user = sqlx::query!("SELECT refresh_token FROM users")
+        .fetch_one(&app.pg_pool)
+        .await
+        .unwrap();
+    let refresh_token = app
+        .token_cipher()
+        .decrypt(
+            "alice",
+            TokenKind::Refresh,
+            Some(TOKEN_KEY_ID),
+            &user.refresh_token,
+        )
+        .unwrap();
+    // As stored before encryption
+    sqlx::query!(
+        "UPDATE users SET refresh_token = $1, access_token = $2, token_key_id = NULL",
+        refresh_token.expose_secret(),
+        access_token.expose_secret()
+    )
+    .execute(&app.pg_pool)
+    .await
+    .unwrap();
+    // Plaintext tokens still work until they are encrypted
+    let plaintext_access_token = app.token_manager().access_token("alice").await.unwrap();
+    assert_eq!(
+        plaintext_access_token.expose_secret(),
+        access_token.expose_secret()
+    );
+
+    let encrypted = encrypt_plaintext_tokens(&app.pg_pool, &app.token_cipher())
+        .await
+        .unwrap();
+
+    assert_eq!(encrypted, 1);
+    let user = sqlx::query!("SELECT access_token, token_key_id FROM users")
+        .fetch_one(&app.pg_pool)
+        .await
+        .unwrap();
+    assert_eq!(user.token_key_id.as_deref(), Some(TOKEN_KEY_ID));
+    assert_ne!(&user.access_token, access_token.expose_secret());
+    app.expire_access_tokens().await;
+    let status = app.generate("").await;
+    assert_eq!(status["progress"]["created"], 1, "{status}");
+}
+
+#[tokio::test]
+async fn tokens_are_reencrypted_with_a_new_key() {
+    let app = spawn_app().await;
+    connect_users(&app, [FakeUser::new("alice", 10), FakeUser::new("bob", 10)]).await;
+    let access_token = app.token_manager().access_token("alice").await.unwrap();
+    let rotated = app.rotated_token_cipher("test-2");
+
+    let reencrypted = reencrypt_tokens(&app.pg_pool, &rotated).await.unwrap();
+
+    assert_eq!(reencrypted, 2);
+    let key_ids = sqlx::query_scalar!("SELECT token_key_id FROM users")
+        .fetch_all(&app.pg_pool)
+        .await
+        .unwrap();
+    assert!(key_ids
+        .iter()
+        .all(|key_id| key_id.as_deref() == Some("test-2")));
+    // The previous key isn't needed anymore
+    assert!(app.token_manager().access_token("alice").await.is_err());
+    let reencrypted_access_token = app
+        .token_manager_with(rotated.clone())
+        .access_token("alice")
+        .await
+        .unwrap();
+    assert_eq!(
+        reencrypted_access_token.expose_secret(),
+        access_token.expose_secret()
+    );
+    assert_eq!(reencrypt_tokens(&app.pg_pool, &rotated).await.unwrap(), 0);
+}